@@ -2,7 +2,6 @@
 #![recursion_limit = "1024"]
 #![allow(unused_doc_comment)] // temp until error_chain updated
 
-extern crate digest;
 #[macro_use]
 extern crate error_chain;
 extern crate futures;
@@ -14,7 +13,6 @@ extern crate libloading;
 extern crate proto;
 #[macro_use]
 extern crate rental;
-extern crate sha3;
 extern crate sodiumoxide;
 extern crate tokio_core;
 extern crate tokio_io;
@@ -34,6 +32,7 @@ mod render_loop;
 
 use std::io::{self, Write};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::process;
 use std::sync::mpsc;
 use std::thread;
@@ -45,7 +44,7 @@ use g::gfx::Device;
 use g::gfx_text;
 use g::gfx_window_glutin;
 use g::glutin::{self, GlContext};
-use proto::{Bincoded, Bytes, handshake};
+use proto::{Bytes, Endpoints, Envelope, handshake};
 
 use common::OurFuture;
 use errors::*;
@@ -56,9 +55,12 @@ const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
 const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
 
 fn main() {
-    let addr = ([127, 0, 0, 1], 2001).into();
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+    let endpoints = Endpoints::from_command_line(&root, "client")
+        .and_then(|endpoints| Ok((endpoints.connect_addr()?, endpoints.max_message)))
+        .chain_err(|| "couldn't configure endpoints");
 
-    if let Err(e) = client(addr) {
+    if let Err(e) = endpoints.and_then(|(addr, max)| client(addr, max)) {
         let stderr = io::stderr();
         let oops = "couldn't write to stderr";
         let mut log = stderr.lock();
@@ -74,7 +76,7 @@ fn main() {
     }
 }
 
-fn client(server_addr: SocketAddr) -> Result<()> {
+fn client(server_addr: SocketAddr, max_message: usize) -> Result<()> {
 
     let controller = Controller::new();
     let control_tx = controller.control_tx.clone();
@@ -89,8 +91,8 @@ fn client(server_addr: SocketAddr) -> Result<()> {
                 let update_tx = update_tx.clone();
                 let inbox = inbox.clone();
                 let hello = handshake::Hello::Newbie;
-                box common::write_bincoded(sock, &hello)
-                    .and_then(|(sock, _)| receive::fetch_driver(sock))
+                box common::write_envelope(sock, &hello)
+                    .and_then(move |(sock, _)| receive::fetch_driver(sock, max_message))
                     .and_then(move |(sock, info, path)| {
                         println!("driver {}", info.digest.short_hex());
 
@@ -99,7 +101,7 @@ fn client(server_addr: SocketAddr) -> Result<()> {
 
                         // inform the draw thread about our new driver
                         update_tx.send((path, box comms))
-                            .map(|()| (sock, net::ClientSide { inbox, rx }))
+                            .map(|()| (sock, net::ClientSide { inbox, rx, max_message }))
                             .map_err(|_| ErrorKind::BrokenComms.into())
                     })
             })
@@ -251,8 +253,7 @@ impl Engine<g::Res> for Hot {
 
         // xxx handle disconnected pipe
         if let Ok(bytes) = self.controller.control_rx.try_recv() {
-            let coded = unsafe { Bincoded::from_bytes(bytes) };
-            match coded.deserialize() {
+            match Envelope::<handshake::UpControl>::from_bytes(bytes).and_then(|coded| coded.deserialize()) {
                 Ok(msg) => self.obey(msg)?,
                 Err(e) => println!("control: de: {:?}", e),
             }
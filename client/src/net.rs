@@ -79,18 +79,20 @@ pub type MessageBuffer = Arc<Mutex<VecDeque<Bytes>>>;
 pub struct ClientSide {
     pub inbox: MessageBuffer,
     pub rx: UnboundedReceiver<Bytes>,
+    /// Longest message we'll accept from the server.
+    pub max_message: usize,
 }
 
 impl ClientSide {
     pub fn handle(self, sock: TcpStream) -> OurFuture<()> {
         let (r, w) = sock.split();
-        let ClientSide { inbox, rx } = self;
+        let ClientSide { inbox, rx, max_message } = self;
 
         fn swap<A, B>((a, b): (A, B)) -> (B, A) {
             (b, a)
         }
 
-        let read = stream::unfold(r, |r| Some(common::read_with_length(r).map(swap)))
+        let read = stream::unfold(r, |r| Some(common::read_with_length(r, max_message).map(swap)))
             .for_each(
                 move |bytes| {
                     let mut inbox = inbox.lock().expect("put message in inbox");
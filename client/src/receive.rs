@@ -1,20 +1,18 @@
 use std::fs::{self, File};
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::str;
 
-use digest::{Input, VariableOutput};
 use futures::{Future, Stream, future};
 use futures_cpupool::CpuPool;
 use hyper::{self, Client, Uri};
-use sha3::Shake128;
 use sodiumoxide::crypto::sign::{self, PublicKey};
 use tokio_core::reactor::{Core, Handle};
 use tokio_io::AsyncRead;
 
 use common::{self, OurFuture};
 use errors::*;
-use proto::{Digest, DriverInfo, digest, handshake};
+use proto::{Digest, DriverInfo, delta, handshake};
 
 /// Generated by `cd issuer; cargo run -- keygen`.
 pub static PUBLIC_KEY: PublicKey = PublicKey(*include_bytes!("../../issuer/cred/public"));
@@ -23,9 +21,10 @@ pub static PUBLIC_KEY: PublicKey = PublicKey(*include_bytes!("../../issuer/cred/
 /// Downloads the newest driver (if needed), returning its path.
 pub fn fetch_driver<R: AsyncRead + 'static>(
     reader: R,
+    max_message: usize,
     ) -> OurFuture<(R, Box<DriverInfo>, PathBuf)> {
 
-    box common::read_bincoded::<_, handshake::Welcome<Box<DriverInfo>>>(reader).and_then(
+    box common::read_envelope::<_, handshake::Welcome<Box<DriverInfo>>>(reader, max_message).and_then(
         move |(reader, welcome)| -> OurFuture<_> {
 
             match welcome {
@@ -36,10 +35,23 @@ pub fn fetch_driver<R: AsyncRead + 'static>(
                 handshake::Welcome::Download(uri, info) => {
                     // verify that the signature is ok
                     let sig = sign::Signature(info.sig.0);
-                    let verified = sign::verify_detached(&sig, &info.digest.0, &PUBLIC_KEY);
+                    let verified =
+                        info.verify_with(|msg| sign::verify_detached(&sig, msg, &PUBLIC_KEY));
                     if !verified {
                         return box future::err("sig check failed".into());
                     }
+                    // a driver linked against some other g would crash on load
+                    if let Some(g_digest) = try_box!(linked_g_digest()) {
+                        if info.g_digest != g_digest {
+                            let msg = format!(
+                                "driver {} needs g {}, but we have g {}",
+                                info.digest.short_hex(),
+                                info.g_digest.short_hex(),
+                                g_digest.short_hex()
+                            );
+                            return box future::err(msg.into());
+                        }
+                    }
 
                     let uri: Uri = try_box!(uri.parse().map_err(hyper::Error::Uri));
                     box download_in_bg(uri, info)
@@ -50,6 +62,19 @@ pub fn fetch_driver<R: AsyncRead + 'static>(
     )
 }
 
+/// Digest of the g we were linked against, if the builder told us.
+fn linked_g_digest() -> Result<Option<Digest>> {
+    match option_env!("EXUDE_G_DIGEST") {
+        Some(hex) => {
+            match hex.parse() {
+                Ok(digest) => Ok(Some(digest)),
+                Err(()) => bail!("client was built with a bad EXUDE_G_DIGEST ({:?})", hex),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
 pub fn download_in_bg(uri: Uri, info: Box<DriverInfo>) -> OurFuture<(Box<DriverInfo>, PathBuf)> {
     box CpuPool::new(1)
         .spawn_fn(
@@ -61,40 +86,76 @@ pub fn download_in_bg(uri: Uri, info: Box<DriverInfo>) -> OurFuture<(Box<DriverI
         )
 }
 
+/// Asks for a patch against the newest driver we already have, if any,
+/// falling back to downloading the whole thing.
 fn download(
     uri: Uri,
     info: Box<DriverInfo>,
     handle: &Handle,
     ) -> OurFuture<(Box<DriverInfo>, PathBuf)> {
 
+    let base = match newest_cached(&info.digest) {
+        Some(base) => base,
+        None => return download_from(uri, info, handle, None),
+    };
+    let delta_uri = handshake::delta_url(&uri.to_string(), &base.0);
+    let delta_uri: Uri = try_box!(delta_uri.parse().map_err(hyper::Error::Uri));
+
+    let retry = info.clone();
+    let handle = handle.clone();
+    box download_from(delta_uri, info, &handle, Some(base)).or_else(move |e| {
+        println!("delta download failed ({}); fetching the whole driver", e);
+        download_from(uri, retry, &handle, None)
+    })
+}
+
+fn download_from(
+    uri: Uri,
+    info: Box<DriverInfo>,
+    handle: &Handle,
+    base: Option<(Digest, PathBuf)>,
+    ) -> OurFuture<(Box<DriverInfo>, PathBuf)> {
+
     let client = Client::new(handle);
     box client
         .get(uri)
         .then(|res| res.chain_err(|| "requesting driver download"))
-        .and_then(|resp| -> OurFuture<_> {
+        .and_then(move |resp| -> OurFuture<_> {
             // xxx put this in tmp folder first
             let mut path = repo_path().to_owned();
-            let hex = info.digest.hex_bytes();
-            path.push(unsafe { str::from_utf8_unchecked(&hex) });
+            path.push(info.digest.file_name());
+
+            let patched_from = resp.headers()
+                .get_raw(handshake::DELTA_BASE_HEADER)
+                .and_then(|raw| raw.one())
+                .map(|hex| str::from_utf8(hex).ok().and_then(|hex| hex.parse::<Digest>().ok()));
+            if let Some(patched_from) = patched_from {
+                let base = match base {
+                    Some(ref base) if Some(&base.0) == patched_from.as_ref() => base.clone(),
+                    _ => return box future::err("server patched against the wrong driver".into()),
+                };
+                return box resp.body()
+                    .then(|res| res.chain_err(|| "reading driver patch"))
+                    .fold(Vec::new(), |mut patch, chunk| -> Result<_> {
+                        patch.extend_from_slice(&chunk);
+                        Ok(patch)
+                    })
+                    .and_then(move |patch| -> Result<_> {
+                        apply_patch(&patch, &base.1, &info.digest, &path)?;
+                        Ok((info, path))
+                    });
+            }
 
             let file = try_box!(File::create(&path));
-            let hasher = Shake128::default();
+            let body = resp.body().then(|res| res.chain_err(|| "reading driver download"));
 
             // hash download while writing it to fs
             // idea: if we could extract the Bytes from Chunk::Shared,
             //       we could send chunks to yet another thread, for hashing
-            box resp.body()
-                .then(|res| res.chain_err(|| "reading driver download"))
-                .fold((file, hasher), |(mut f, mut h), chunk| -> Result<_> {
-                    f.write_all(&chunk)?;
-                    h.process(&chunk);
-                    Ok((f, h))
-                })
-                .and_then(move |(mut file, hasher)| -> Result<_> {
+            box common::write_hashed(body, file, info.digest.algorithm())
+                .and_then(move |(mut file, digest, _)| -> Result<_> {
                     // verify that the binary hashes to the digest
-                    let mut result = [0u8; digest::LEN];
-                    hasher.variable_result(&mut result).expect("hashing");
-                    if Digest(result) != info.digest {
+                    if digest != info.digest {
                         if let Err(e) = fs::remove_file(path) {
                             println!("remove failed download: {}", e);
                         }
@@ -107,6 +168,52 @@ fn download(
         })
 }
 
+/// Rebuilds the driver from `base` and writes it to `path`, if it checks out.
+fn apply_patch(patch: &[u8], base: &Path, digest: &Digest, path: &Path) -> Result<()> {
+    let patch = delta::Patch::decode(patch).chain_err(|| "couldn't decode driver patch")?;
+    let mut base_bytes = Vec::new();
+    File::open(base)
+        .and_then(|mut f| f.read_to_end(&mut base_bytes))
+        .chain_err(|| format!("couldn't read {}", base.display()))?;
+    let driver = patch.apply(&base_bytes).chain_err(|| "couldn't apply driver patch")?;
+    // the patch vouches for nothing; only the signed digest counts
+    ensure!(digest.matches(&driver), "hash check failed");
+
+    File::create(path)
+        .and_then(|mut f| f.write_all(&driver))
+        .chain_err(|| format!("couldn't write {}", path.display()))
+}
+
+/// The most recently downloaded driver other than `target`, as a patch base.
+fn newest_cached(target: &Digest) -> Option<(Digest, PathBuf)> {
+    let entries = match fs::read_dir(repo_path()) {
+        Ok(entries) => entries,
+        Err(_) => return None,
+    };
+    let mut newest = None;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let digest: Digest = match entry.file_name().to_str().and_then(|hex| hex.parse().ok()) {
+            Some(digest) => digest,
+            None => continue,
+        };
+        let mtime = match entry.metadata().and_then(|meta| meta.modified()) {
+            Ok(mtime) => mtime,
+            Err(_) => continue,
+        };
+        if &digest == target {
+            continue;
+        }
+        let newer = match newest {
+            Some((_, _, ref best)) => mtime > *best,
+            None => true,
+        };
+        if newer {
+            newest = Some((digest, entry.path(), mtime));
+        }
+    }
+    newest.map(|(digest, path, _)| (digest, path))
+}
+
 fn repo_path() -> &'static Path {
     use std::sync::{ONCE_INIT, Once};
 
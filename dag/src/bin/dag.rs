@@ -0,0 +1,111 @@
+extern crate dag;
+
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+use dag::{Dag, GcOptions, Result, ResultExt};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        usage();
+    }
+
+    match dispatch(Path::new(&args[0]), &args[1], &args[2..]) {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(ref e) => {
+            let stderr = &mut io::stderr();
+            let errmsg = "Error writing to stderr";
+
+            writeln!(stderr, "error: {}", e).expect(errmsg);
+
+            for e in e.iter().skip(1) {
+                writeln!(stderr, "caused by: {}", e).expect(errmsg);
+            }
+
+            process::exit(1);
+        }
+    }
+}
+
+/// Returns whether the dag is healthy.
+fn dispatch(dir: &Path, cmd: &str, args: &[String]) -> Result<bool> {
+    let dag = Dag::new(dir).chain_err(|| format!("couldn't open {}", dir.display()))?;
+    match cmd {
+        "fsck" => fsck(&dag, args),
+        "gc" => gc(&dag, args),
+        cmd => {
+            let _ = writeln!(io::stderr(), "Unknown command: {}", cmd);
+            usage()
+        }
+    }
+}
+
+fn fsck(dag: &Dag, args: &[String]) -> Result<bool> {
+    let mut repair = false;
+    for arg in args {
+        match &**arg {
+            "--repair" => repair = true,
+            _ => usage(),
+        }
+    }
+
+    let report = dag.fsck(repair)?;
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    for path in &report.quarantined {
+        println!("quarantined {}", path.display());
+    }
+    println!("{} intact objects, {} problems", report.objects, report.problems.len());
+    Ok(report.is_clean())
+}
+
+fn gc(dag: &Dag, args: &[String]) -> Result<bool> {
+    let mut options = GcOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &**arg {
+            "--dry-run" | "-n" => options.dry_run = true,
+            "--grace" => {
+                let secs = args.next().ok_or("--grace needs a number of seconds")?;
+                let secs = secs.parse().chain_err(|| format!("{:?} is not a number of seconds", secs))?;
+                options.grace = Duration::from_secs(secs);
+            }
+            _ => usage(),
+        }
+    }
+
+    let report = dag.gc(&options)?;
+    let verb = if options.dry_run { "would remove" } else { "removed" };
+    for digest in &report.removed {
+        println!("{} {}", verb, digest);
+    }
+    println!(
+        "{} live, {} too young; {} {} objects and {} temp files, {} bytes",
+        report.live,
+        report.young,
+        verb,
+        report.removed.len(),
+        report.temps,
+        report.reclaimed
+    );
+    Ok(true)
+}
+
+fn usage() -> ! {
+    println!(
+        "Command patterns:
+    DIR fsck [--repair]
+    DIR gc [--dry-run] [--grace SECONDS]
+
+fsck exits 1 if it finds problems. --repair moves broken objects into DIR/quarantine.
+gc keeps unreachable objects younger than --grace (default an hour).
+"
+    );
+    process::exit(1)
+}
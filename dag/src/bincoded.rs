@@ -1,12 +1,11 @@
-use std::fs::{self, File};
 use std::io;
-use std::io::prelude::*;
 use std::marker::PhantomData;
-use std::path::Path;
 
 use bytes::{BufMut, Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 
+use frame;
+
 pub use bincode::{self, Error, ErrorKind, Result};
 
 /// Holds the result of `bincode::serialize`.
@@ -16,20 +15,14 @@ pub struct Bincoded<T> {
     _phantom: PhantomData<T>,
 }
 
-pub static BINCODED_MAX: u64 = 0xffff;
+/// The largest value `new` will serialize; the same as the default frame limit.
+pub static BINCODED_MAX: u64 = frame::DEFAULT_MAX as u64;
 
 impl<T> Bincoded<T> {
-    /// Returns the number of serialized bytes stored. Does not include length header.
+    /// Returns the number of serialized bytes stored. Does not include the frame header.
     pub fn len(&self) -> usize {
         self.bytes.len()
     }
-
-    /// Not intended for general use; this is for low-level use.
-    /// Precondition: `vec` must have been encoded with the same T.
-    pub unsafe fn from_bytes(bytes: Bytes) -> Self {
-        assert!(!bytes.is_empty());
-        Bincoded { bytes, _phantom: PhantomData }
-    }
 }
 
 impl<T: Serialize> Bincoded<T> {
@@ -45,13 +38,6 @@ impl<T: Serialize> Bincoded<T> {
         let bytes = bytes.into_inner().freeze();
         Ok(Bincoded { bytes, _phantom: PhantomData })
     }
-
-    /// Does not write a header or anything fancy.
-    pub fn write_to_path(&self, path: &Path) -> io::Result<()> {
-        let mut f = File::create(path)?;
-        f.write_all(&self.bytes)?;
-        f.sync_all()
-    }
 }
 
 pub fn serialized_size<T: Serialize>(value: &T) -> Result<usize> {
@@ -104,35 +90,6 @@ where
     pub fn deserialize(&self) -> Result<T> {
         deserialize_exact(self)
     }
-
-    /// Deserialize from the given file.
-    /// Precondition: file at `path` must have been encoded with the same T.
-    pub unsafe fn from_path(path: &Path) -> io::Result<Self> {
-        let len = fs::metadata(path)?.len() as usize; // unchecked cast
-        if len == 0 {
-            debug_assert!(
-                false,
-                "zero-length {} {}",
-                ::std::intrinsics::type_name::<T>(),
-                path.display(),
-            );
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "zero-length deserialization"));
-        }
-        let mut bytes = BytesMut::with_capacity(len);
-        bytes.set_len(len); // unsafe
-        let mut f = File::open(path)?;
-        f.read_exact(&mut bytes)?;
-        // ensure we hit eof
-        loop {
-            match f.read(&mut [0]) {
-                Ok(0) => break,
-                Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "not eof")),
-                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                Err(e) => return Err(e),
-            }
-        }
-        Ok(Bincoded { bytes: bytes.freeze(), _phantom: PhantomData })
-    }
 }
 
 impl<T> AsRef<[u8]> for Bincoded<T> {
@@ -149,14 +106,11 @@ impl<T> Into<Bytes> for Bincoded<T> {
 
 #[cfg(test)]
 mod test {
-    extern crate tempfile;
-
     use std::io;
     use std::marker::PhantomData;
 
     use bincode::ErrorKind;
     use bytes::Bytes;
-    use self::tempfile::NamedTempFile;
 
     use super::Bincoded;
 
@@ -198,22 +152,4 @@ mod test {
     fn zero() {
         Bincoded::new(&()).unwrap();
     }
-
-    #[test]
-    #[should_panic]
-    fn zero_file() {
-        let tmp = NamedTempFile::new().unwrap(); // do not use NamedTempFile in real code!
-        unsafe { Bincoded::<()>::from_path(tmp.path()).unwrap() };
-    }
-
-    #[test]
-    fn file() {
-        let orig = (format!("route"), 66);
-        let tmp = NamedTempFile::new().unwrap(); // do not use NamedTempFile in real code!
-        let coded = Bincoded::new(&orig).unwrap();
-        coded.write_to_path(tmp.path()).unwrap();
-        let read = unsafe { Bincoded::from_path(tmp.path()).unwrap() };
-        let decoded = read.deserialize().unwrap();
-        assert_eq!(orig, decoded);
-    }
 }
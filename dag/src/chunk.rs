@@ -0,0 +1,162 @@
+//! Splits large blobs at content-defined boundaries, so that blobs which
+//! differ by a few edits share most of their chunks.
+//!
+//! Boundaries come from a gear hash (a rolling hash over roughly the last 64
+//! bytes), so an insertion only disturbs the chunks around it.
+
+use bincode;
+
+use bincoded;
+use digest::Digest;
+use errors::*;
+use node;
+
+/// Marks an object stored as a `Manifest` of chunks rather than whole.
+pub static MAGIC: &[u8] = b"\0dag\x01chunks";
+
+pub const MIN_CHUNK: usize = 16 * 1024;
+pub const MAX_CHUNK: usize = 256 * 1024;
+/// Cuts happen where the hash has this many low zero bits, so chunks average
+/// `MIN_CHUNK` plus 64 KiB.
+const MASK_BITS: u32 = 16;
+
+/// How a chunked blob is stored under its own digest.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Manifest {
+    /// Of the whole blob.
+    pub len: u64,
+    /// In order. Each is an ordinary object.
+    pub chunks: Vec<Digest>,
+}
+
+impl Manifest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bincode::serialize_into(&mut bytes, self, bincode::Infinite).expect("encode manifest");
+        bytes
+    }
+
+    /// `None` if `bytes` aren't a manifest at all.
+    pub fn decode(bytes: &[u8]) -> Result<Option<Manifest>> {
+        if !bytes.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        let manifest = bincoded::deserialize_exact(&bytes[MAGIC.len()..]).chain_err(|| "malformed manifest")?;
+        Ok(Some(manifest))
+    }
+}
+
+/// Cuts `bytes` into chunks of `MIN_CHUNK` to `MAX_CHUNK` bytes (bar the last).
+/// No chunk starts with a reserved header, so each can be saved as a blob.
+pub fn split(bytes: &[u8]) -> Vec<&[u8]> {
+    let gear = gear_table();
+    let mut chunks = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let len = cut(&gear, rest);
+        chunks.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    chunks
+}
+
+/// The length of the next chunk.
+fn cut(gear: &[u64; 256], bytes: &[u8]) -> usize {
+    if bytes.len() <= MIN_CHUNK {
+        return bytes.len();
+    }
+    let mask = (1u64 << MASK_BITS) - 1;
+    let limit = if bytes.len() < MAX_CHUNK { bytes.len() } else { MAX_CHUNK };
+    let mut hash = 0u64;
+    for (i, &byte) in bytes[..limit].iter().enumerate().skip(MIN_CHUNK - 64) {
+        hash = (hash << 1).wrapping_add(gear[byte as usize]);
+        if i + 1 >= MIN_CHUNK && hash & mask == 0 && !node::is_reserved(&bytes[i + 1..]) {
+            return i + 1;
+        }
+    }
+    // forced to cut; go a little further if the next chunk would look reserved
+    let mut len = limit;
+    while len < bytes.len() && node::is_reserved(&bytes[len..]) {
+        len += 1;
+    }
+    len
+}
+
+/// Fixed pseudo-random values (splitmix64), one per byte value.
+fn gear_table() -> [u64; 256] {
+    let mut state = 0u64;
+    let mut table = [0u64; 256];
+    for entry in table.iter_mut() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        *entry = z ^ (z >> 31);
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use digest::Digest;
+    use node;
+    use super::{MAX_CHUNK, MIN_CHUNK, Manifest, split};
+
+    fn noise(len: usize, mut seed: u32) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                // xorshift
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sizes() {
+        let bytes = noise(3 * 1024 * 1024, 1);
+        let chunks = split(&bytes);
+        assert!(chunks.len() > 8);
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), bytes.len());
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= MIN_CHUNK && chunk.len() <= MAX_CHUNK, "{}", chunk.len());
+        }
+        assert_eq!(split(&bytes[..100]), vec![&bytes[..100]]);
+        assert!(split(&[]).is_empty());
+    }
+
+    #[test]
+    fn edits_share_chunks() {
+        let before = noise(2 * 1024 * 1024, 7);
+        let mut after = before.clone();
+        let middle: Vec<u8> = after.split_off(1024 * 1024);
+        after.extend_from_slice(b"a small insertion");
+        after.extend_from_slice(&middle);
+
+        let digests = |bytes: &[u8]| -> HashSet<Digest> { split(bytes).into_iter().map(Digest::from_bytes).collect() };
+        let (before, after) = (digests(&before), digests(&after));
+        let changed = after.difference(&before).count();
+        assert!(changed <= 4, "{} of {} chunks changed", changed, after.len());
+    }
+
+    #[test]
+    fn never_reserved() {
+        // plant a header right where a forced cut would land
+        let mut bytes = vec![0u8; MAX_CHUNK * 2];
+        bytes[MAX_CHUNK..MAX_CHUNK + node::RESERVED.len()].copy_from_slice(node::RESERVED);
+        for chunk in split(&bytes) {
+            assert!(!node::is_reserved(chunk));
+        }
+    }
+
+    #[test]
+    fn manifest_roundtrip() {
+        let manifest = Manifest { len: 5, chunks: vec![Digest::sample(), Digest::zero()] };
+        assert_eq!(Manifest::decode(&manifest.encode()).unwrap(), Some(manifest));
+        assert_eq!(Manifest::decode(b"blob").unwrap(), None);
+    }
+}
@@ -0,0 +1,229 @@
+//! Binary patches from one version of a blob to the next, so that upgrades
+//! only ship what changed.
+//!
+//! The base is indexed in fixed-size blocks; the target is scanned with a
+//! rolling hash, and every block found in the base grows into the longest
+//! matching run, which becomes a copy. Everything else is inserted verbatim.
+
+use std::collections::HashMap;
+
+use bincode;
+
+use bincoded;
+use digest::Digest;
+use errors::*;
+
+/// Starts every encoded `Patch`.
+pub static MAGIC: &[u8] = b"\0dag\x01delta";
+
+const BLOCK: usize = 32;
+const BASE: u32 = 0x0100_0193;
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Op {
+    /// Bytes from the base.
+    Copy { offset: u64, len: u64 },
+    /// New bytes.
+    Insert(Vec<u8>),
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Patch {
+    pub base: Digest,
+    pub target: Digest,
+    /// Of the target.
+    pub len: u64,
+    pub ops: Vec<Op>,
+}
+
+impl Patch {
+    pub fn new(base: &[u8], target: &[u8]) -> Self {
+        Patch {
+            base: Digest::from_bytes(base),
+            target: Digest::from_bytes(target),
+            len: target.len() as u64,
+            ops: diff(base, target),
+        }
+    }
+
+    /// Rebuilds the target, checking both the base and the result.
+    pub fn apply(&self, base: &[u8]) -> Result<Vec<u8>> {
+        ensure!(self.base.matches(base), "patch is for base {}", self.base);
+        let mut out = Vec::with_capacity(self.len as usize);
+        for op in &self.ops {
+            match *op {
+                Op::Copy { offset, len } => {
+                    let end = offset.checked_add(len).ok_or("patch copies out of bounds")?;
+                    ensure!(end <= base.len() as u64, "patch copies out of bounds");
+                    out.extend_from_slice(&base[offset as usize..end as usize]);
+                }
+                Op::Insert(ref bytes) => out.extend_from_slice(bytes),
+            }
+            ensure!(out.len() as u64 <= self.len, "patch overruns its target");
+        }
+        if !self.target.matches(&out) {
+            bail!(ErrorKind::Corrupt(self.target.clone()));
+        }
+        Ok(out)
+    }
+
+    /// How many new bytes this ships, which is most of its encoded size.
+    pub fn inserted(&self) -> usize {
+        self.ops
+            .iter()
+            .map(|op| match *op {
+                Op::Insert(ref bytes) => bytes.len(),
+                Op::Copy { .. } => 0,
+            })
+            .sum()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bincode::serialize_into(&mut bytes, self, bincode::Infinite).expect("encode patch");
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        ensure!(is_patch(bytes), "not a patch");
+        bincoded::deserialize_exact(&bytes[MAGIC.len()..]).chain_err(|| "malformed patch")
+    }
+}
+
+pub fn is_patch(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn diff(base: &[u8], target: &[u8]) -> Vec<Op> {
+    let mut ops = Vec::new();
+    if base.len() < BLOCK || target.len() < BLOCK {
+        push_insert(&mut ops, target);
+        return ops;
+    }
+
+    // the first occurrence of each aligned block
+    let mut index = HashMap::with_capacity(base.len() / BLOCK);
+    for (i, block) in base.chunks(BLOCK).enumerate() {
+        if block.len() == BLOCK {
+            index.entry(hash(block)).or_insert(i * BLOCK);
+        }
+    }
+
+    // `BASE` to the power of `BLOCK - 1`, for rolling the oldest byte out
+    let outgoing = (1..BLOCK).fold(1u32, |p, _| p.wrapping_mul(BASE));
+    let mut pending = 0; // start of bytes not yet copied or inserted
+    let mut i = 0;
+    let mut h = hash(&target[..BLOCK]);
+    while i + BLOCK <= target.len() {
+        let found = match index.get(&h) {
+            Some(&off) if base[off..off + BLOCK] == target[i..i + BLOCK] => Some(off),
+            _ => None,
+        };
+        if let Some(mut off) = found {
+            let mut start = i;
+            // grow backwards into what would've been inserted
+            while start > pending && off > 0 && base[off - 1] == target[start - 1] {
+                start -= 1;
+                off -= 1;
+            }
+            let mut len = i + BLOCK - start;
+            while start + len < target.len() && off + len < base.len() && base[off + len] == target[start + len] {
+                len += 1;
+            }
+            push_insert(&mut ops, &target[pending..start]);
+            push_copy(&mut ops, off, len);
+            i = start + len;
+            pending = i;
+            if i + BLOCK <= target.len() {
+                h = hash(&target[i..i + BLOCK]);
+            }
+            continue;
+        }
+        if i + BLOCK < target.len() {
+            h = h.wrapping_sub((target[i] as u32).wrapping_mul(outgoing))
+                .wrapping_mul(BASE)
+                .wrapping_add(target[i + BLOCK] as u32);
+        }
+        i += 1;
+    }
+    push_insert(&mut ops, &target[pending..]);
+    ops
+}
+
+fn hash(block: &[u8]) -> u32 {
+    block.iter().fold(0u32, |h, &b| h.wrapping_mul(BASE).wrapping_add(b as u32))
+}
+
+fn push_insert(ops: &mut Vec<Op>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        ops.push(Op::Insert(bytes.to_vec()));
+    }
+}
+
+fn push_copy(ops: &mut Vec<Op>, offset: usize, len: usize) {
+    if let Some(&mut Op::Copy { offset: prev, len: ref mut prev_len }) = ops.last_mut() {
+        if prev + *prev_len == offset as u64 {
+            *prev_len += len as u64;
+            return;
+        }
+    }
+    ops.push(Op::Copy { offset: offset as u64, len: len as u64 });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Op, Patch};
+
+    fn noise(len: usize, mut seed: u32) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn identical() {
+        let base = noise(10_000, 3);
+        let patch = Patch::new(&base, &base);
+        assert_eq!(patch.ops, vec![Op::Copy { offset: 0, len: 10_000 }]);
+        assert_eq!(patch.apply(&base).unwrap(), base);
+    }
+
+    #[test]
+    fn small_edits() {
+        let base = noise(200_000, 5);
+        let mut target = base[..50_000].to_vec();
+        target.extend_from_slice(b"inserted here");
+        target.extend_from_slice(&base[50_000..]);
+        target.drain(120_000..120_100);
+        target[150_000] ^= 0x80;
+
+        let patch = Patch::new(&base, &target);
+        assert!(patch.inserted() < 200, "{} bytes inserted", patch.inserted());
+        let encoded = patch.encode();
+        assert!(encoded.len() < 1000, "{} byte patch", encoded.len());
+        let decoded = Patch::decode(&encoded).unwrap();
+        assert_eq!(decoded.apply(&base).unwrap(), target);
+    }
+
+    #[test]
+    fn unrelated_and_tiny() {
+        let base = noise(5_000, 1);
+        let target = noise(7_000, 2);
+        assert_eq!(Patch::new(&base, &target).apply(&base).unwrap(), target);
+        assert_eq!(Patch::new(b"", b"tiny").apply(b"").unwrap(), b"tiny");
+        assert_eq!(Patch::new(&base, b"").apply(&base).unwrap(), b"");
+    }
+
+    #[test]
+    fn wrong_base() {
+        let base = noise(5_000, 1);
+        let patch = Patch::new(&base, &noise(5_000, 9));
+        assert!(patch.apply(&base[1..]).is_err());
+        assert!(Patch::decode(b"not a patch").is_err());
+    }
+}
@@ -1,53 +1,233 @@
+//! Content digests, tagged with the algorithm that made them.
+//!
+//! Textual and bincoded forms follow multihash: the algorithm's code, the
+//! digest length, then the digest itself. Digests from before tagging were
+//! all SHAKE128, so bare 64-digit hex still parses as one, and `untagged`
+//! reads the old bincoded form.
+
 use std::fmt::{self, Debug, Display};
 use std::io;
 use std::io::prelude::*;
 use std::str::{self, FromStr};
 
-use digest_crate::{Input, VariableOutput};
-use sha3::Shake128;
+use digest_crate::{FixedOutput, Input, VariableOutput};
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+use sha3::{Sha3_256, Shake128};
 
 pub static HEX_CHARS: &[u8] = b"0123456789abcdef";
+/// Every algorithm we support produces this many bytes.
 pub const LEN: usize = 32;
+/// Length of the textual form, tag included.
+pub const HEX_LEN: usize = (LEN + 2) * 2;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Algorithm {
+    /// SHAKE128 with 256 bits of output. The default.
+    Shake128,
+    Sha3_256,
+}
+
+impl Algorithm {
+    /// The multihash code.
+    pub fn code(self) -> u8 {
+        match self {
+            Algorithm::Sha3_256 => 0x16,
+            Algorithm::Shake128 => 0x18,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x16 => Some(Algorithm::Sha3_256),
+            0x18 => Some(Algorithm::Shake128),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Shake128 => "shake-128",
+            Algorithm::Sha3_256 => "sha3-256",
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        let state = match self {
+            Algorithm::Shake128 => State::Shake128(Shake128::default()),
+            Algorithm::Sha3_256 => State::Sha3_256(Sha3_256::default()),
+        };
+        Hasher { state }
+    }
+
+    pub fn digest(self, bytes: &[u8]) -> Digest {
+        let mut hasher = self.hasher();
+        hasher.input(bytes);
+        hasher.finish()
+    }
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        Algorithm::Shake128
+    }
+}
+
+/// Hashes bytes fed in piecemeal.
+pub struct Hasher {
+    state: State,
+}
+
+enum State {
+    Shake128(Shake128),
+    Sha3_256(Sha3_256),
+}
+
+impl Hasher {
+    pub fn input(&mut self, bytes: &[u8]) {
+        match self.state {
+            State::Shake128(ref mut h) => h.process(bytes),
+            State::Sha3_256(ref mut h) => h.process(bytes),
+        }
+    }
+
+    pub fn finish(self) -> Digest {
+        let mut bytes = [0u8; LEN];
+        let algo = match self.state {
+            State::Shake128(h) => {
+                h.variable_result(&mut bytes).expect("hashing");
+                Algorithm::Shake128
+            }
+            State::Sha3_256(h) => {
+                bytes.copy_from_slice(&h.fixed_result());
+                Algorithm::Sha3_256
+            }
+        };
+        Digest { algo, bytes }
+    }
+}
+
+/// Hashes everything written through it on the way to `W`.
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Hasher,
+    len: u64,
+}
 
-/// Stores a 256-bit hash digest.
-#[derive(Clone, Deserialize, Eq, PartialEq, Serialize)]
-pub struct Digest(pub [u8; LEN]);
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W, algo: Algorithm) -> Self {
+        HashWriter { inner, hasher: algo.hasher(), len: 0 }
+    }
+
+    /// Bytes written so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Returns the writer, and the digest and length of what went through it.
+    pub fn finish(self) -> (W, Digest, u64) {
+        (self.inner, self.hasher.finish(), self.len)
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // only hash what the writer took
+        let n = self.inner.write(buf)?;
+        self.hasher.input(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hashes everything read through it from `R`.
+pub struct HashReader<R> {
+    inner: R,
+    hasher: Hasher,
+    len: u64,
+}
+
+impl<R: Read> HashReader<R> {
+    pub fn new(inner: R, algo: Algorithm) -> Self {
+        HashReader { inner, hasher: algo.hasher(), len: 0 }
+    }
+
+    /// Bytes read so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns the reader, and the digest and length of what was read.
+    /// Anything left unread isn't hashed.
+    pub fn finish(self) -> (R, Digest, u64) {
+        (self.inner, self.hasher.finish(), self.len)
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.input(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Stores a 256-bit hash digest and the algorithm that produced it.
+#[derive(Clone, Eq, Hash, PartialEq)]
+pub struct Digest {
+    algo: Algorithm,
+    bytes: [u8; LEN],
+}
 
 impl Digest {
+    pub fn new(algo: Algorithm, bytes: [u8; LEN]) -> Self {
+        Digest { algo, bytes }
+    }
+
+    /// Hashes with the default algorithm.
     pub fn from_bytes(bytes: &[u8]) -> Self {
-        let mut hasher = Shake128::default();
-        hasher.process(bytes);
-        let mut result = [0u8; LEN];
-        hasher.variable_result(&mut result).expect("hashing");
-        Digest(result)
-    }
-
-    pub fn from_read<R: Read>(mut reader: R) -> io::Result<(Digest, usize)> {
-        let mut hasher = Shake128::default();
-        let mut buf = [0; 4096];
-        let mut len = 0;
-
-        loop {
-            match reader.read(&mut buf) {
-                Ok(0) => break,
-                Ok(n) => {
-                    len += n;
-                    hasher.process(&buf[..n]);
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
-                Err(e) => return Err(e),
-            }
-        }
+        Algorithm::default().digest(bytes)
+    }
+
+    /// Hashes with the default algorithm.
+    pub fn from_read<R: Read>(reader: R) -> io::Result<(Digest, usize)> {
+        Digest::from_read_with(Algorithm::default(), reader)
+    }
 
-        let mut result = [0u8; LEN];
-        hasher.variable_result(&mut result).expect("hashing");
-        Ok((Digest(result), len))
+    pub fn from_read_with<R: Read>(algo: Algorithm, reader: R) -> io::Result<(Digest, usize)> {
+        let mut reader = HashReader::new(reader, algo);
+        io::copy(&mut reader, &mut io::sink())?;
+        let (_, digest, len) = reader.finish();
+        Ok((digest, len as usize)) // unchecked cast
     }
 
-    /// Always returns valid ASCII.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algo
+    }
+
+    /// The digest proper, without its tag.
+    pub fn as_bytes(&self) -> &[u8; LEN] {
+        &self.bytes
+    }
+
+    /// Whether `bytes` hash to this, by this digest's own algorithm.
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        &self.algo.digest(bytes) == self
+    }
+
+    /// Hex of the digest proper, without its tag. Always returns valid ASCII.
     pub fn hex_bytes(&self) -> [u8; LEN * 2] {
         let mut ascii = [b'x'; LEN * 2];
-        for (i, octet) in self.0.iter().enumerate() {
+        for (i, octet) in self.bytes.iter().enumerate() {
             ascii[i * 2] = HEX_CHARS[(octet >> 4) as usize];
             ascii[i * 2 + 1] = HEX_CHARS[(octet & 0x0f) as usize];
         }
@@ -60,8 +240,26 @@ impl Digest {
         hex.to_owned()
     }
 
+    /// Name for files stored under this digest. Untagged for the default
+    /// algorithm, so files named before digests were tagged stay put.
+    pub fn file_name(&self) -> String {
+        if self.algo == Algorithm::default() {
+            let ascii = self.hex_bytes();
+            unsafe { str::from_utf8_unchecked(&ascii) }.to_owned()
+        } else {
+            self.to_string()
+        }
+    }
+
+    /// Whether this digest's hex (as in `short_hex`) starts with `prefix`.
+    /// Case-insensitive.
+    pub fn has_prefix(&self, prefix: &str) -> bool {
+        let ascii = self.hex_bytes();
+        ascii.starts_with(prefix.to_lowercase().as_bytes())
+    }
+
     pub fn zero() -> Self {
-        Digest([0; LEN])
+        Digest { algo: Algorithm::default(), bytes: [0; LEN] }
     }
 
     #[cfg(test)]
@@ -70,7 +268,34 @@ impl Digest {
         bytes[1] = 0x55;
         bytes[12] = 0x23;
         bytes[LEN - 2] = 0xf0;
-        Digest(bytes)
+        Digest { algo: Algorithm::default(), bytes }
+    }
+}
+
+/// Finds the one digest among `candidates` that `prefix` abbreviates, so
+/// people can type back what `short_hex` printed. A whole digest, tagged or
+/// not, must match exactly.
+pub fn resolve_prefix<I>(prefix: &str, candidates: I) -> ::errors::Result<Digest>
+where
+    I: IntoIterator<Item = Digest>,
+{
+    use errors::ErrorKind;
+
+    let mut matches: Vec<Digest> = match prefix.parse::<Digest>() {
+        Ok(whole) => candidates.into_iter().filter(|d| d == &whole).collect(),
+        Err(()) => {
+            if prefix.is_empty() || !prefix.bytes().all(|b| (b as char).is_digit(16)) {
+                bail!("{:?} is not a hex digest", prefix);
+            }
+            candidates.into_iter().filter(|d| d.has_prefix(prefix)).collect()
+        }
+    };
+    matches.sort_by(|a, b| a.bytes.cmp(&b.bytes).then(a.algo.code().cmp(&b.algo.code())));
+    matches.dedup();
+    match matches.len() {
+        0 => bail!(ErrorKind::PrefixNotFound(prefix.to_owned())),
+        1 => Ok(matches.pop().expect("one match")),
+        _ => bail!(ErrorKind::AmbiguousPrefix(prefix.to_owned(), matches)),
     }
 }
 
@@ -82,6 +307,10 @@ impl Debug for Digest {
 
 impl Display for Digest {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tag = [self.algo.code(), LEN as u8];
+        for octet in &tag {
+            write!(f, "{:02x}", octet)?;
+        }
         let ascii = self.hex_bytes();
         let hex = unsafe { str::from_utf8_unchecked(&ascii) };
         f.write_str(&hex)
@@ -90,35 +319,112 @@ impl Display for Digest {
 
 impl FromStr for Digest {
     type Err = ();
+    /// Takes tagged hex, or bare hex as SHAKE128.
     fn from_str(s: &str) -> Result<Self, ()> {
-        if s.len() != LEN * 2 {
-            return Err(())
-        }
+        let (algo, hex) = if s.len() == HEX_LEN {
+            let code = parse_octet(&s.as_bytes()[..2])?;
+            let len = parse_octet(&s.as_bytes()[2..4])?;
+            if len as usize != LEN {
+                return Err(());
+            }
+            (Algorithm::from_code(code).ok_or(())?, &s[4..])
+        } else if s.len() == LEN * 2 {
+            (Algorithm::Shake128, s)
+        } else {
+            return Err(());
+        };
         let mut bytes = [0u8; LEN];
-        let mut ascii = s.bytes();
-        for byte in bytes.iter_mut() {
-            let hi = ascii.next().unwrap();
-            let lo = ascii.next().unwrap();
-            if hi > b'f' || lo > b'f' {
-                return Err(())
+        for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            *byte = parse_octet(pair)?;
+        }
+        Ok(Digest { algo, bytes })
+    }
+}
+
+fn parse_octet(pair: &[u8]) -> Result<u8, ()> {
+    let digit = |c: u8| match c {
+        b'0'...b'9' => Ok(c - b'0'),
+        b'a'...b'f' => Ok(c - b'a' + 10),
+        _ => Err(()),
+    };
+    Ok(digit(pair[0])? << 4 | digit(pair[1])?)
+}
+
+impl Serialize for Digest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(3)?;
+        tuple.serialize_element(&self.algo.code())?;
+        tuple.serialize_element(&(LEN as u8))?;
+        tuple.serialize_element(&self.bytes)?;
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Tagged;
+
+        impl<'de> Visitor<'de> for Tagged {
+            type Value = Digest;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a tagged digest")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Digest, A::Error> {
+                let missing = || de::Error::custom("truncated digest");
+                let code: u8 = seq.next_element()?.ok_or_else(&missing)?;
+                let len: u8 = seq.next_element()?.ok_or_else(&missing)?;
+                let algo = Algorithm::from_code(code)
+                    .ok_or_else(|| de::Error::custom(format!("unknown digest algorithm {:#x}", code)))?;
+                if len as usize != LEN {
+                    return Err(de::Error::custom(format!("{}-byte {} digest", len, algo.name())));
+                }
+                let bytes = seq.next_element()?.ok_or_else(&missing)?;
+                Ok(Digest { algo, bytes })
             }
-            let pair = [hi, lo];
-            let utf8 = unsafe { str::from_utf8_unchecked(&pair) };
-            *byte = u8::from_str_radix(utf8, 16).map_err(|_| ())?
         }
-        Ok(Digest(bytes))
+
+        deserializer.deserialize_tuple(3, Tagged)
+    }
+}
+
+/// The bare SHAKE128 form that digests were bincoded in before tagging,
+/// for `#[serde(with = "digest::untagged")]` on fields of old formats.
+pub mod untagged {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::ser::Error;
+
+    use super::{Algorithm, Digest, LEN};
+
+    pub fn serialize<S: Serializer>(digest: &Digest, serializer: S) -> Result<S::Ok, S::Error> {
+        if digest.algo != Algorithm::Shake128 {
+            return Err(S::Error::custom("only SHAKE128 digests have an untagged form"));
+        }
+        digest.bytes.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Digest, D::Error> {
+        let bytes = <[u8; LEN]>::deserialize(deserializer)?;
+        Ok(Digest { algo: Algorithm::Shake128, bytes })
     }
 }
 
 #[test]
 fn hex() {
-    let digest = Digest([0xff; LEN]);
+    let digest = Digest::new(Algorithm::Shake128, [0xff; LEN]);
     let hex = format!("{}", digest);
-    assert_eq!(hex.len(), LEN * 2);
-    for b in hex.bytes() {
+    assert_eq!(hex.len(), HEX_LEN);
+    assert!(hex.starts_with("1820"));
+    for b in hex[4..].bytes() {
         assert_eq!(b, b'f');
     }
     assert_eq!(digest.short_hex(), "ffffffffffff");
+    assert_eq!(digest.file_name(), &hex[4..]);
+
+    let sha3 = Digest::new(Algorithm::Sha3_256, [0xff; LEN]);
+    assert!(sha3.to_string().starts_with("1620"));
+    assert_eq!(sha3.file_name(), sha3.to_string());
 }
 
 #[test]
@@ -129,6 +435,69 @@ fn eq() {
     assert_eq!(x, x.clone());
     assert_eq!(z, z);
     assert!(x != z && z != x);
+    assert!(x != Digest::new(Algorithm::Sha3_256, *x.as_bytes()));
+}
+
+#[test]
+fn algorithms() {
+    let shake = Digest::from_bytes(b"");
+    assert_eq!(shake.algorithm(), Algorithm::Shake128);
+    assert_eq!(shake.to_string(), "18207f9c2ba4e88f827d616045507605853ed73b8093f6efbc88eb1a6eacfa66ef26");
+
+    let sha3 = Algorithm::Sha3_256.digest(b"");
+    assert_eq!(sha3.to_string(), "1620a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a");
+    assert!(sha3.matches(b"") && !sha3.matches(b"x"));
+
+    let (read, len) = Digest::from_read_with(Algorithm::Sha3_256, &b"abc"[..]).unwrap();
+    assert_eq!((read, len), (Algorithm::Sha3_256.digest(b"abc"), 3));
+}
+
+#[test]
+fn adapters() {
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+
+    let mut writer = HashWriter::new(Vec::new(), Algorithm::Sha3_256);
+    for piece in data.chunks(777) {
+        writer.write_all(piece).unwrap();
+    }
+    let (copy, digest, len) = writer.finish();
+    assert_eq!(copy, data);
+    assert_eq!(len, data.len() as u64);
+    assert_eq!(digest, Algorithm::Sha3_256.digest(&data));
+
+    let mut reader = HashReader::new(&data[..], Algorithm::Shake128);
+    let mut head = [0; 100];
+    reader.read_exact(&mut head).unwrap();
+    assert_eq!(reader.len(), 100);
+    let (rest, digest, _) = reader.finish();
+    assert_eq!(rest.len(), data.len() - 100);
+    assert_eq!(digest, Digest::from_bytes(&data[..100]));
+}
+
+#[test]
+fn prefixes() {
+    use errors::{Error, ErrorKind};
+
+    let a = Digest::new(Algorithm::Shake128, [0x3f; LEN]);
+    let mut bytes = [0x3f; LEN];
+    bytes[3] = 0;
+    let b = Digest::new(Algorithm::Sha3_256, bytes);
+    let all = || vec![a.clone(), b.clone(), a.clone()];
+
+    assert!(a.has_prefix("3F3f3f") && !a.has_prefix("3f3e") && a.has_prefix(""));
+    assert_eq!(resolve_prefix("3f3f3f3f", all()).unwrap(), a);
+    assert_eq!(resolve_prefix("3f3f3f00", all()).unwrap(), b);
+    assert_eq!(resolve_prefix(&b.to_string(), all()).unwrap(), b);
+    match resolve_prefix("3f3f", all()) {
+        Err(Error(ErrorKind::AmbiguousPrefix(_, ref found), _)) => assert_eq!(found.len(), 2),
+        other => panic!("{:?}", other),
+    }
+    match resolve_prefix("3f3f3f01", all()) {
+        Err(Error(ErrorKind::PrefixNotFound(ref prefix), _)) => assert_eq!(prefix, "3f3f3f01"),
+        other => panic!("{:?}", other),
+    }
+    assert!(resolve_prefix("", all()).is_err());
+    assert!(resolve_prefix("3fg", all()).is_err());
 }
 
 #[test]
@@ -140,13 +509,20 @@ fn parse() {
 
     let a: Digest = "0123456789abcdef02468ace13579bdf000102030405060708090a0b0c0d0e0f"
         .parse().unwrap();
-    let b = Digest([
+    let b = Digest::new(Algorithm::Shake128, [
         0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef,
         0x02, 0x46, 0x8a, 0xce, 0x13, 0x57, 0x9b, 0xdf,
         0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
         0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
     ]);
     assert_eq!(a, b);
+    assert_eq!(b.to_string().parse::<Digest>(), Ok(b.clone()));
+
+    let sha3 = Algorithm::Sha3_256.digest(b"abc");
+    assert_eq!(sha3.to_string().parse::<Digest>(), Ok(sha3.clone()));
+    assert_eq!(format!("1620{}", &sha3.to_string()[4..]).parse::<Digest>(), Ok(sha3));
+    assert_eq!(format!("9920{}", &b.to_string()[4..]).parse::<Digest>(), Err(()));
+    assert_eq!(format!("1821{}", &b.to_string()[4..]).parse::<Digest>(), Err(()));
 
     assert_eq!("0000".parse::<Digest>(), Err(()));
     assert_eq!(
@@ -172,6 +548,23 @@ fn bincoded_repr() {
 
     let orig = Digest::sample();
     let coded = Bincoded::new(&orig).expect("bincode digest");
-    assert_eq!(coded.as_ref().len(), LEN);
-    assert_eq!(&orig.0[..], coded.as_ref());
+    assert_eq!(coded.as_ref().len(), LEN + 2);
+    assert_eq!(&coded.as_ref()[..2], &[0x18, 0x20]);
+    assert_eq!(&orig.as_bytes()[..], &coded.as_ref()[2..]);
+}
+
+#[test]
+fn untagged_repr() {
+    use bincoded::{self, bincode};
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Old(#[serde(with = "untagged")] Digest);
+
+    let orig = Old(Digest::sample());
+    let coded = bincode::serialize(&orig, bincode::Infinite).unwrap();
+    assert_eq!(&coded[..], &orig.0.as_bytes()[..]);
+    assert_eq!(bincoded::deserialize_exact::<_, Old>(&coded).unwrap(), orig);
+
+    let sha3 = Old(Algorithm::Sha3_256.digest(b""));
+    assert!(bincode::serialize(&sha3, bincode::Infinite).is_err());
 }
@@ -0,0 +1,253 @@
+//! Self-describing bincode: each value goes out behind a header naming its
+//! type and schema version, so a reader expecting one type can't silently
+//! misinterpret another.
+//!
+//! The header is `MAGIC`, a one-byte tag length, the tag, and a big-endian
+//! `u16` version. The bincoded value follows.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use bincoded::{self, ErrorKind, Result, bincode};
+
+pub static MAGIC: &[u8] = b"\0env";
+
+/// A type that may be sent or stored in an envelope.
+pub trait Schema {
+    /// Stable name for this type. Never reuse one for a different type.
+    fn tag() -> &'static str;
+
+    /// Bump this whenever the type's encoding changes incompatibly.
+    fn version() -> u16 {
+        1
+    }
+
+    /// Decodes a payload written under an older `version`.
+    /// By default, old versions are refused.
+    fn migrate(version: u16, payload: &[u8]) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let _ = payload;
+        let msg = format!("{} has schema version {}, but we speak {}", Self::tag(), version, Self::version());
+        Err(mismatch(msg))
+    }
+}
+
+/// Length of the header in front of every `T`.
+pub fn header_len<T: Schema>() -> usize {
+    MAGIC.len() + 1 + T::tag().len() + 2
+}
+
+fn write_header<T: Schema, W: Write>(writer: &mut W) -> io::Result<()> {
+    let tag = T::tag();
+    assert!(tag.len() <= 0xff, "schema tag {:?} is too long", tag);
+    let version = T::version();
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[tag.len() as u8])?;
+    writer.write_all(tag.as_bytes())?;
+    writer.write_all(&[(version >> 8) as u8, version as u8])
+}
+
+/// Checks that `bytes` holds a `T` we can read, returning its version and payload.
+pub fn open<T: Schema>(bytes: &[u8]) -> Result<(u16, &[u8])> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(mismatch(format!("expected a {} envelope, got unlabeled bytes", T::tag())));
+    }
+    let rest = &bytes[MAGIC.len()..];
+    let (tag, rest) = match rest.split_first() {
+        Some((&len, rest)) if rest.len() >= len as usize + 2 => rest.split_at(len as usize),
+        _ => return Err(mismatch(format!("expected a {} envelope, got a truncated header", T::tag()))),
+    };
+    let version = (rest[0] as u16) << 8 | rest[1] as u16;
+    if tag != T::tag().as_bytes() {
+        let msg = format!("expected a {}, got a {}", T::tag(), String::from_utf8_lossy(tag));
+        return Err(mismatch(msg));
+    }
+    if version > T::version() {
+        let msg = format!("{} schema version {} is newer than ours ({})", T::tag(), version, T::version());
+        return Err(mismatch(msg));
+    }
+    Ok((version, &rest[2..]))
+}
+
+/// Size of `value` once enveloped.
+pub fn encoded_size<T: Serialize + Schema>(value: &T) -> Result<usize> {
+    Ok(header_len::<T>() + bincoded::serialized_size(value)?)
+}
+
+/// Writes `value` in its envelope.
+pub fn encode_into<W: Write, T: Serialize + Schema>(writer: &mut W, value: &T) -> Result<()> {
+    write_header::<T, _>(writer)?;
+    bincode::serialize_into(writer, value, bincode::Infinite)
+}
+
+/// Reads back a `T` written by `encode_into`, rejecting any other type.
+/// Older versions go through `Schema::migrate`.
+pub fn decode<R, T>(bytes: R) -> Result<T>
+where
+    R: AsRef<[u8]>,
+    for<'de> T: Deserialize<'de> + Schema,
+{
+    match open::<T>(bytes.as_ref())? {
+        (version, payload) if version == T::version() => bincoded::deserialize_exact(payload),
+        (version, payload) => T::migrate(version, payload),
+    }
+}
+
+fn mismatch(msg: String) -> bincoded::Error {
+    Box::new(ErrorKind::Custom(msg))
+}
+
+/// An enveloped `T`, ready for the wire or disk.
+#[derive(Clone)]
+pub struct Envelope<T> {
+    bytes: Bytes,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Envelope<T> {
+    /// Includes the header.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl<T: Schema> Envelope<T> {
+    /// Wraps received bytes, checking that they hold a `T`.
+    pub fn from_bytes(bytes: Bytes) -> Result<Self> {
+        open::<T>(&bytes)?;
+        Ok(Envelope { bytes, _phantom: PhantomData })
+    }
+
+    /// Reads a file written by `write_to_path`, checking that it holds a `T`.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Envelope::from_bytes(bytes.into())
+    }
+}
+
+impl<T: Serialize + Schema> Envelope<T> {
+    pub fn new(value: &T) -> Result<Self> {
+        let len = encoded_size(value)?;
+        let mut bytes = BytesMut::with_capacity(len).writer();
+        encode_into(&mut bytes, value)?;
+        Ok(Envelope { bytes: bytes.into_inner().freeze(), _phantom: PhantomData })
+    }
+}
+
+impl<T> Envelope<T> {
+    pub fn write_to_path(&self, path: &Path) -> io::Result<()> {
+        let mut f = File::create(path)?;
+        f.write_all(&self.bytes)?;
+        f.sync_all()
+    }
+}
+
+impl<T> Envelope<T>
+where
+    for<'de> T: Deserialize<'de> + Schema,
+{
+    pub fn deserialize(&self) -> Result<T> {
+        decode(&self.bytes)
+    }
+}
+
+impl<T> AsRef<[u8]> for Envelope<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.bytes.as_ref()
+    }
+}
+
+impl<T> Into<Bytes> for Envelope<T> {
+    fn into(self) -> Bytes {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use bincoded::ErrorKind;
+    use bytes::Bytes;
+    use self::tempdir::TempDir;
+
+    use super::{Envelope, Schema, decode, header_len};
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Ping(u32);
+
+    impl Schema for Ping {
+        fn tag() -> &'static str {
+            "test::Ping"
+        }
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Pong(u32);
+
+    impl Schema for Pong {
+        fn tag() -> &'static str {
+            "test::Pong"
+        }
+        fn version() -> u16 {
+            2
+        }
+    }
+
+    fn message(err: ::bincoded::Error) -> String {
+        match *err {
+            ErrorKind::Custom(msg) => msg,
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let env = Envelope::new(&Ping(7)).unwrap();
+        assert_eq!(env.len(), header_len::<Ping>() + 4);
+        assert!(env.as_ref().starts_with(b"\0env\x0atest::Ping\0\x01"));
+        let bytes: Bytes = env.into();
+        assert_eq!(Envelope::<Ping>::from_bytes(bytes).unwrap().deserialize().unwrap(), Ping(7));
+    }
+
+    #[test]
+    fn wrong_type() {
+        let bytes: Bytes = Envelope::new(&Ping(7)).unwrap().into();
+        let err = Envelope::<Pong>::from_bytes(bytes.clone()).err().unwrap();
+        assert_eq!(message(err), "expected a test::Pong, got a test::Ping");
+
+        let err = decode::<_, Pong>(&[7u8, 0, 0, 0][..]).unwrap_err();
+        assert_eq!(message(err), "expected a test::Pong envelope, got unlabeled bytes");
+        assert!(decode::<_, Ping>(&bytes[..6]).is_err());
+    }
+
+    #[test]
+    fn wrong_version() {
+        let mut bytes = Envelope::new(&Pong(1)).unwrap().as_ref().to_vec();
+        let at = header_len::<Pong>() - 1;
+        bytes[at] = 1;
+        let err = decode::<_, Pong>(&bytes).unwrap_err();
+        assert_eq!(message(err), "test::Pong has schema version 1, but we speak 2");
+        bytes[at] = 3;
+        let err = decode::<_, Pong>(&bytes).unwrap_err();
+        assert_eq!(message(err), "test::Pong schema version 3 is newer than ours (2)");
+    }
+
+    #[test]
+    fn file() {
+        let dir = TempDir::new("envelope").unwrap();
+        let path = dir.path().join("ping");
+        Envelope::new(&Ping(3)).unwrap().write_to_path(&path).unwrap();
+        assert_eq!(Envelope::<Ping>::from_path(&path).unwrap().deserialize().unwrap(), Ping(3));
+        assert!(Envelope::<Pong>::from_path(&path).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+//! Length-prefixed framing for messages on the wire.
+//!
+//! Each frame is a LEB128 varint length followed by that many bytes.
+//! Readers refuse frames longer than their connection's maximum before
+//! allocating anything, so a bogus header can't balloon memory.
+
+use std::io::{self, Read, Write};
+
+/// Largest frame accepted unless a connection says otherwise.
+pub const DEFAULT_MAX: usize = 16 << 20;
+
+/// A `u64` takes at most ten varint bytes.
+pub const MAX_HEADER: usize = 10;
+
+/// An encoded length, ready to be written ahead of its frame.
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    buf: [u8; MAX_HEADER],
+    len: u8,
+}
+
+impl Header {
+    pub fn new(len: u64) -> Self {
+        let mut buf = [0; MAX_HEADER];
+        let mut n = 0;
+        let mut rest = len;
+        loop {
+            let byte = (rest & 0x7f) as u8;
+            rest >>= 7;
+            if rest == 0 {
+                buf[n] = byte;
+                n += 1;
+                break;
+            }
+            buf[n] = byte | 0x80;
+            n += 1;
+        }
+        Header { buf, len: n as u8 }
+    }
+}
+
+impl AsRef<[u8]> for Header {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+}
+
+/// Decodes a varint length one byte at a time, for readers that can't peek.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LenDecoder {
+    value: u64,
+    shift: u32,
+}
+
+impl LenDecoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Feeds in the next header byte, returning the length once it's complete.
+    pub fn push(&mut self, byte: u8) -> io::Result<Option<u64>> {
+        let bits = (byte & 0x7f) as u64;
+        if self.shift >= 64 || (self.shift == 63 && bits > 1) {
+            return Err(invalid("frame length overflows 64 bits"));
+        }
+        self.value |= bits << self.shift;
+        self.shift += 7;
+        if byte & 0x80 == 0 {
+            Ok(Some(self.value))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Fails if a frame of `len` bytes is over `max`.
+pub fn check_len(len: u64, max: usize) -> io::Result<usize> {
+    if len > max as u64 {
+        let msg = format!("{}-byte frame exceeds the {}-byte limit", len, max);
+        Err(invalid(msg))
+    } else {
+        Ok(len as usize)
+    }
+}
+
+/// Writes a header and then `bytes`.
+pub fn write_frame<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(Header::new(bytes.len() as u64).as_ref())?;
+    writer.write_all(bytes)
+}
+
+/// Reads just a header, checking it against `max`.
+pub fn read_len<R: Read>(reader: &mut R, max: usize) -> io::Result<usize> {
+    let mut decoder = LenDecoder::new();
+    let mut byte = [0];
+    loop {
+        reader.read_exact(&mut byte)?;
+        if let Some(len) = decoder.push(byte[0])? {
+            return check_len(len, max);
+        }
+    }
+}
+
+/// Reads a whole frame into memory.
+pub fn read_frame<R: Read>(reader: &mut R, max: usize) -> io::Result<Vec<u8>> {
+    let len = read_len(reader, max)?;
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads a header and hands back a reader over just the frame's bytes,
+/// for payloads too big to buffer. The frame must be read to its end
+/// before the next one.
+pub fn read_streamed<R: Read>(reader: R, max: usize) -> io::Result<io::Take<R>> {
+    let mut reader = reader;
+    let len = read_len(&mut reader, max)?;
+    Ok(reader.take(len as u64))
+}
+
+fn invalid<S: Into<String>>(msg: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read};
+
+    use super::{DEFAULT_MAX, Header, LenDecoder, read_frame, read_len, read_streamed, write_frame};
+
+    fn decode(bytes: &[u8]) -> io::Result<Option<u64>> {
+        let mut decoder = LenDecoder::new();
+        for &byte in bytes {
+            if let Some(len) = decoder.push(byte)? {
+                return Ok(Some(len));
+            }
+        }
+        Ok(None)
+    }
+
+    #[test]
+    fn varints() {
+        assert_eq!(Header::new(0).as_ref(), &[0]);
+        assert_eq!(Header::new(127).as_ref(), &[0x7f]);
+        assert_eq!(Header::new(128).as_ref(), &[0x80, 0x01]);
+        assert_eq!(Header::new(0xffff).as_ref(), &[0xff, 0xff, 0x03]);
+        assert_eq!(Header::new(u64::max_value()).as_ref().len(), 10);
+        for &len in &[0, 1, 300, 0x10000, 1 << 40, u64::max_value()] {
+            assert_eq!(decode(Header::new(len).as_ref()).unwrap(), Some(len));
+        }
+        assert!(decode(&[0xff; 11]).is_err());
+        assert!(decode(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02]).is_err());
+        assert_eq!(decode(&[0x80]).unwrap(), None);
+    }
+
+    #[test]
+    fn big_frames() {
+        let big = vec![7u8; 200_000];
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"hi").unwrap();
+        write_frame(&mut wire, &big).unwrap();
+
+        let mut reader = Cursor::new(&wire);
+        assert_eq!(read_frame(&mut reader, DEFAULT_MAX).unwrap(), b"hi");
+        assert_eq!(read_frame(&mut reader, DEFAULT_MAX).unwrap(), big);
+
+        let mut reader = Cursor::new(&wire);
+        read_frame(&mut reader, 2).unwrap();
+        let err = read_frame(&mut reader, 100_000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn streaming() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"first").unwrap();
+        write_frame(&mut wire, b"second").unwrap();
+
+        let mut body = read_streamed(Cursor::new(&wire), 8).unwrap();
+        let mut first = String::new();
+        body.read_to_string(&mut first).unwrap();
+        assert_eq!(first, "first");
+        let mut reader = body.into_inner();
+        assert_eq!(read_len(&mut reader, 8).unwrap(), 6);
+    }
+}
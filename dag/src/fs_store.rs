@@ -0,0 +1,409 @@
+//! The on-disk layout: objects in `o/` named by digest, a file per root in
+//! `r/` holding its digest, each root's history and lock in `h/`, writes
+//! in progress in `tmp/`, and the whole store's lock in `lock`.
+//!
+//! Locks are advisory `flock`s, so any number of processes can share a store.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use filetime::{self, FileTime};
+use tempfile::NamedTempFile;
+
+use digest::Digest;
+use errors::*;
+use fsck::Problem;
+use history::{self, RootChange};
+use lock::{self, LockMode};
+use store::{Lock, ObjectMeta, Staged, Store, is_young};
+
+pub struct FsStore {
+    objs: PathBuf,
+    roots: PathBuf,
+    /// Each root's `history` log and lock file.
+    history: PathBuf,
+    /// Writes in progress; on the same filesystem as `objs`, so they can be renamed in.
+    tmp: PathBuf,
+    /// Where `fsck` puts what it finds broken.
+    quarantine: PathBuf,
+    lock: PathBuf,
+}
+
+impl FsStore {
+    /// Creates the layout in `dir` if it isn't there yet.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        fn mkdir(dir: PathBuf) -> Result<PathBuf> {
+            match dir.metadata() {
+                Ok(meta) => {
+                    ensure!(meta.is_dir(), "not a directory");
+                    Ok(dir)
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    fs::create_dir_all(&dir)?;
+                    Ok(dir)
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+
+        let dir = dir.as_ref();
+        let objs = mkdir(dir.join("o"))?;
+        let roots = mkdir(dir.join("r"))?;
+        let history = mkdir(dir.join("h"))?;
+        let tmp = mkdir(dir.join("tmp"))?;
+        let quarantine = dir.join("quarantine");
+        let lock = dir.join("lock");
+        Ok(FsStore { objs, roots, history, tmp, quarantine, lock })
+    }
+
+    pub fn obj_path(&self, digest: &Digest) -> PathBuf {
+        self.objs.join(digest.file_name())
+    }
+
+    fn temp_file(&self) -> Result<NamedTempFile> {
+        // removed on drop, unless persisted
+        NamedTempFile::new_in(&self.tmp).chain_err(|| "couldn't create temp file")
+    }
+
+    fn root_path(&self, id: &str) -> PathBuf {
+        self.roots.join(id)
+    }
+
+    /// Where roots used to point, as symlinks: `../o/<hex>`.
+    fn parse_legacy_root(&self, id: &str, target_path: &Path) -> Result<Digest> {
+        let mut cs = target_path.components();
+        ensure!(cs.next().map(|c| c == Component::ParentDir).unwrap_or(false),
+                "root {:?} is corrupt (..)", id);
+        ensure!(cs.next().map(|c| c == Component::Normal("o".as_ref())).unwrap_or(false),
+                "root {:?} is corrupt (o)", id);
+        let digest = cs.next()
+            .ok_or_else(|| format!("root {:?} is corrupt (missing hex)", id))
+            .and_then(|c| {
+                match c {
+                    Component::Normal(os) => os.to_str().ok_or_else(|| "bad utf8".into()),
+                    _ => Err(format!("root {:?} is corrupt (bad hex)", id)),
+                }
+            })
+            .and_then(|s| s.parse::<Digest>().map_err(|()| format!("root {:?} bad hex", id)))?;
+
+        ensure!(cs.next().is_none(), "root {:?} is corrupt (trailer)", id);
+        Ok(digest)
+    }
+}
+
+impl Store for FsStore {
+    fn open(&self, digest: &Digest) -> Result<Option<Box<Read + Send>>> {
+        let path = self.obj_path(digest);
+        match File::open(&path) {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).chain_err(|| format!("couldn't open {}", path.display())),
+        }
+    }
+
+    fn stat(&self, digest: &Digest) -> Result<Option<ObjectMeta>> {
+        let path = self.obj_path(digest);
+        match fs::metadata(&path) {
+            Ok(meta) => Ok(Some(ObjectMeta { len: meta.len(), modified: meta.modified().ok() })),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).chain_err(|| format!("couldn't stat {}", path.display())),
+        }
+    }
+
+    fn put(&self, digest: &Digest, bytes: &[u8]) -> Result<()> {
+        let mut tmp = self.temp_file()?;
+        tmp.write_all(bytes)
+            .and_then(|()| tmp.sync_all())
+            .chain_err(|| format!("couldn't write {}", tmp.path().display()))?;
+        persist(tmp, &self.obj_path(digest))
+    }
+
+    fn stage<'a>(&'a self) -> Result<Box<Staged + 'a>> {
+        Ok(Box::new(FsStaged { tmp: self.temp_file()?, objs: &self.objs }))
+    }
+
+    fn touch(&self, digest: &Digest) -> Result<bool> {
+        let path = self.obj_path(digest);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let now = FileTime::from_seconds_since_1970(now.as_secs(), now.subsec_nanos());
+        match filetime::set_file_times(&path, now, now) {
+            Ok(()) => Ok(true),
+            // swept by `gc` just now
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).chain_err(|| format!("couldn't touch {}", path.display())),
+        }
+    }
+
+    /// Moves the object aside, then removes it unless a `touch` got to it
+    /// first, in which case it's put back.
+    fn sweep(&self, digest: &Digest, now: SystemTime, grace: Duration) -> Result<bool> {
+        let path = self.obj_path(digest);
+        let doomed = self.tmp.join(format!("{}.gc", digest));
+        match fs::rename(&path, &doomed) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e).chain_err(|| format!("couldn't move {} aside", path.display())),
+        }
+        // a `save` that touched it before the rename shows here; one
+        // after the rename misses it, and writes a fresh copy instead
+        if is_young(fs::metadata(&doomed)?.modified().ok(), now, grace) {
+            fs::rename(&doomed, &path).chain_err(|| format!("couldn't restore {}", path.display()))?;
+            return Ok(false);
+        }
+        remove_if_present(&doomed)?;
+        Ok(true)
+    }
+
+    fn list(&self) -> Result<Vec<Digest>> {
+        let mut digests = Vec::new();
+        for entry in fs::read_dir(&self.objs).chain_err(|| "couldn't list objects")? {
+            let entry = entry.chain_err(|| "couldn't list objects")?;
+            // anything else is a stray, for `problems`
+            if let Some(digest) = object_name(&entry)? {
+                digests.push(digest);
+            }
+        }
+        Ok(digests)
+    }
+
+    fn read_root(&self, id: &str) -> Result<Option<Digest>> {
+        let path = self.root_path(id);
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).chain_err(|| format!("couldn't read root {:?}", id)),
+        };
+        if meta.file_type().is_symlink() {
+            let target = fs::read_link(&path).chain_err(|| format!("couldn't read root {:?}", id))?;
+            return self.parse_legacy_root(id, &target).map(Some);
+        }
+        let mut hex = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut hex))
+            .chain_err(|| format!("couldn't read root {:?}", id))?;
+        let digest = hex.trim_right().parse().map_err(|()| format!("root {:?} is corrupt", id))?;
+        Ok(Some(digest))
+    }
+
+    /// Writes the new root off to the side, then renames it over the old one;
+    /// nobody else can be using this name while we hold the lock.
+    fn write_root(&self, id: &str, digest: &Digest) -> Result<()> {
+        let mut tmp = self.temp_file()?;
+        writeln!(tmp, "{}", digest)
+            .and_then(|()| tmp.sync_all())
+            .chain_err(|| format!("couldn't write root {:?}", id))?;
+        persist(tmp, &self.root_path(id)).chain_err(|| format!("couldn't move root {:?}", id))
+    }
+
+    fn root_ids(&self) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        let entries = fs::read_dir(&self.roots).chain_err(|| "couldn't list roots")?;
+        for entry in entries {
+            let name = entry.chain_err(|| "couldn't list roots")?.file_name();
+            // no `Dag` could have made it; `problems` reports it
+            if let Ok(id) = name.into_string() {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn lock(&self, mode: LockMode, timeout: Duration) -> Result<Box<Lock>> {
+        lock::lock_file(&self.lock, mode, timeout)
+    }
+
+    fn lock_root(&self, id: &str, timeout: Duration) -> Result<Box<Lock>> {
+        // never removed, or two processes could lock different files
+        lock::lock_file(&self.history.join(format!("{}.lock", id)), LockMode::Exclusive, timeout)
+    }
+
+    fn append_history(&self, id: &str, change: &RootChange) -> Result<()> {
+        history::append(&self.history.join(format!("{}.log", id)), change)
+    }
+
+    fn history(&self, id: &str) -> Result<Vec<RootChange>> {
+        history::read(&self.history.join(format!("{}.log", id)))
+    }
+
+    fn sweep_temps(&self, now: SystemTime, grace: Duration, dry_run: bool) -> Result<(usize, u64)> {
+        let (mut count, mut size) = (0, 0);
+        for entry in fs::read_dir(&self.tmp).chain_err(|| "couldn't list temp files")? {
+            let entry = entry.chain_err(|| "couldn't list temp files")?;
+            let meta = fs::symlink_metadata(entry.path())?;
+            if is_young(meta.modified().ok(), now, grace) {
+                continue;
+            }
+            if !dry_run {
+                remove_if_present(&entry.path())?;
+            }
+            count += 1;
+            size += meta.len();
+        }
+        Ok((count, size))
+    }
+
+    fn problems(&self) -> Result<Vec<Problem>> {
+        let mut problems = Vec::new();
+        for entry in fs::read_dir(&self.objs).chain_err(|| "couldn't list objects")? {
+            let entry = entry.chain_err(|| "couldn't list objects")?;
+            if object_name(&entry)?.is_none() {
+                problems.push(Problem::Misnamed(entry.file_name()));
+            }
+        }
+        for entry in fs::read_dir(&self.roots).chain_err(|| "couldn't list roots")? {
+            let id = entry.chain_err(|| "couldn't list roots")?.file_name();
+            if id.to_str().is_none() {
+                problems.push(Problem::BadRoot { id, error: "name isn't UTF-8".into() });
+            }
+        }
+        for entry in fs::read_dir(&self.tmp).chain_err(|| "couldn't list temp files")? {
+            let name = entry.chain_err(|| "couldn't list temp files")?.file_name();
+            problems.push(Problem::LeftoverTemp(name));
+        }
+        Ok(problems)
+    }
+
+    /// Moves the file into `quarantine/` rather than deleting it.
+    fn quarantine(&self, problem: &Problem) -> Result<Option<PathBuf>> {
+        let name: OsString = match *problem {
+            Problem::Corrupt { ref digest, .. } => digest.file_name().into(),
+            Problem::Misnamed(ref name) => name.clone(),
+            _ => return Ok(None),
+        };
+        let path = self.objs.join(&name);
+        let dir = &self.quarantine;
+        fs::create_dir_all(dir).chain_err(|| format!("couldn't create {}", dir.display()))?;
+        // never overwrite an earlier casualty
+        let mut dest = dir.join(&name);
+        let mut n = 1;
+        while fs::symlink_metadata(&dest).is_ok() {
+            let mut numbered = name.clone();
+            numbered.push(format!(".{}", n));
+            dest = dir.join(numbered);
+            n += 1;
+        }
+        fs::rename(&path, &dest).chain_err(|| format!("couldn't quarantine {}", path.display()))?;
+        Ok(Some(dest))
+    }
+}
+
+/// The object's digest, unless this isn't a file named like one.
+fn object_name(entry: &fs::DirEntry) -> Result<Option<Digest>> {
+    let digest = entry.file_name().to_str().and_then(|hex| hex.parse().ok());
+    match digest {
+        Some(digest) if entry.file_type()?.is_file() => Ok(Some(digest)),
+        _ => Ok(None),
+    }
+}
+
+struct FsStaged<'a> {
+    tmp: NamedTempFile,
+    objs: &'a Path,
+}
+
+impl<'a> Staged for FsStaged<'a> {
+    fn commit(self: Box<Self>, digest: &Digest) -> Result<()> {
+        let FsStaged { tmp, objs } = *self;
+        tmp.sync_all().chain_err(|| format!("couldn't write {}", tmp.path().display()))?;
+        persist(tmp, &objs.join(digest.file_name()))
+    }
+}
+
+impl<'a> Read for FsStaged<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.tmp.read(buf)
+    }
+}
+
+impl<'a> Write for FsStaged<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tmp.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tmp.flush()
+    }
+}
+
+impl<'a> Seek for FsStaged<'a> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.tmp.seek(pos)
+    }
+}
+
+/// Renames a synced temp file into place, durably.
+fn persist(tmp: NamedTempFile, dest: &Path) -> Result<()> {
+    tmp.persist(dest)
+        .map_err(|e| e.error)
+        .chain_err(|| format!("couldn't move {} into place", dest.display()))?;
+    // make the rename itself durable
+    let dir = dest.parent().expect("store dir");
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .chain_err(|| format!("couldn't sync {}", dir.display()))
+}
+
+fn remove_if_present(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r.chain_err(|| format!("couldn't remove {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    use self::tempdir::TempDir;
+
+    use Digest;
+    use store::Store;
+    use super::FsStore;
+
+    #[test]
+    fn roots_are_plain_files() {
+        let dir = TempDir::new("fs_store_roots").unwrap();
+        let store = FsStore::new(dir.path()).unwrap();
+        let digest = Digest::from_bytes(b"root");
+        store.write_root("current", &digest).unwrap();
+
+        let mut contents = String::new();
+        File::open(dir.path().join("r").join("current")).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, format!("{}\n", digest));
+        assert_eq!(store.read_root("current").unwrap(), Some(digest));
+        assert_eq!(store.read_root("absent").unwrap(), None);
+
+        File::create(dir.path().join("r").join("garbled")).unwrap().write_all(b"1820abc\n").unwrap();
+        assert!(store.read_root("garbled").is_err());
+        assert_eq!(store.root_ids().unwrap(), vec!["current".to_owned(), "garbled".to_owned()]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn legacy_symlink_roots() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("fs_store_symlinks").unwrap();
+        let store = FsStore::new(dir.path()).unwrap();
+        let digest = Digest::from_bytes(b"old root");
+        let target = format!("../o/{}", digest.file_name());
+        symlink(&target, dir.path().join("r").join("old")).unwrap();
+        symlink("../elsewhere", dir.path().join("r").join("weird")).unwrap();
+
+        assert_eq!(store.read_root("old").unwrap(), Some(digest.clone()));
+        assert!(store.read_root("weird").is_err());
+        // moving it replaces the link with a file
+        let next = Digest::from_bytes(b"new root");
+        store.write_root("old", &next).unwrap();
+        assert_eq!(store.read_root("old").unwrap(), Some(next));
+        assert!(!dir.path().join("r").join("old").symlink_metadata().unwrap().file_type().is_symlink());
+    }
+}
@@ -0,0 +1,215 @@
+//! Re-hashes every object and checks every root, optionally quarantining
+//! whatever's broken.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use digest::Digest;
+use errors::*;
+use lock::LockMode;
+use reader::CorruptObject;
+use super::Dag;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// Contents that don't hash to the object's name: truncated, or otherwise damaged.
+    Corrupt { digest: Digest, len: u64 },
+    /// Something in `o/` that isn't named like an object, or isn't a file.
+    Misnamed(OsString),
+    /// A root that doesn't hold a well-formed digest.
+    BadRoot { id: OsString, error: String },
+    /// A root pointing at an object that isn't there (or was corrupt).
+    DanglingRoot { id: String, digest: Digest },
+    /// A node linking to an object that isn't there (or was corrupt).
+    DanglingLink { node: Digest, name: String, digest: Digest },
+    /// An abandoned write, crashed or still in progress; `gc` removes old ones.
+    LeftoverTemp(OsString),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::Corrupt { ref digest, len } => write!(f, "object {} is corrupt ({} bytes)", digest, len),
+            Problem::Misnamed(ref name) => write!(f, "stray file {:?} in o/", name),
+            Problem::BadRoot { ref id, ref error } => write!(f, "root {:?} is broken: {}", id, error),
+            Problem::DanglingRoot { ref id, ref digest } => {
+                write!(f, "root {:?} points at missing object {}", id, digest)
+            }
+            Problem::DanglingLink { ref node, ref name, ref digest } => {
+                write!(f, "node {} links {:?} to missing object {}", node, name, digest)
+            }
+            Problem::LeftoverTemp(ref name) => write!(f, "leftover temp file {:?}", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FsckReport {
+    /// Intact objects.
+    pub objects: usize,
+    pub problems: Vec<Problem>,
+    /// Where repair moved bad files to.
+    pub quarantined: Vec<PathBuf>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Dag {
+    /// With `repair`, has the store quarantine corrupt objects and stray files;
+    /// on disk, they're moved into `quarantine/` rather than deleted. Roots and
+    /// temp files are only reported. Repairing locks out everyone else.
+    pub fn fsck(&self, repair: bool) -> Result<FsckReport> {
+        let _lock = self.lock(if repair { LockMode::Exclusive } else { LockMode::Shared })?;
+        self.under_lock().check(repair)
+    }
+
+    fn check(&self, repair: bool) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+        let mut intact = HashSet::new();
+
+        for digest in self.store.list()? {
+            let object = match self.store.open(&digest)? {
+                Some(object) => object,
+                None => continue, // removed meanwhile
+            };
+            let (found, len) = Digest::from_read_with(digest.algorithm(), object)
+                .chain_err(|| format!("couldn't read object {}", digest))?;
+            if found == digest || self.reassembles(&digest)? {
+                intact.insert(digest);
+            } else {
+                report.problems.push(Problem::Corrupt { digest, len: len as u64 });
+            }
+        }
+        report.objects = intact.len();
+
+        for digest in &intact {
+            for link in self.links(digest)? {
+                if !intact.contains(&link.digest) {
+                    report.problems.push(
+                        Problem::DanglingLink {
+                            node: digest.clone(),
+                            name: link.name,
+                            digest: link.digest,
+                        }
+                    );
+                }
+            }
+        }
+
+        for id in self.store.root_ids()? {
+            match self.root(&id) {
+                Ok(Some(digest)) => {
+                    if !intact.contains(&digest) {
+                        report.problems.push(Problem::DanglingRoot { id, digest });
+                    }
+                }
+                Ok(None) => (), // removed meanwhile
+                Err(e) => report.problems.push(Problem::BadRoot { id: id.into(), error: e.to_string() }),
+            }
+        }
+
+        report.problems.extend(self.store.problems()?);
+
+        if repair {
+            for problem in &report.problems {
+                if let Some(dest) = self.store.quarantine(problem)? {
+                    report.quarantined.push(dest);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Whether this is a chunked blob whose chunks add up to its digest.
+    /// Missing chunks don't count against it; they're reported as dangling links.
+    fn reassembles(&self, digest: &Digest) -> Result<bool> {
+        let mut reader = match self.manifest(digest) {
+            Ok(Some(_)) => {
+                match self.open(digest) {
+                    Ok(reader) => reader,
+                    Err(Error(ErrorKind::NotFound(_), _)) => return Ok(true),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(None) => return Ok(false),
+            Err(_) => return Ok(false), // a garbled manifest
+        };
+        match io::copy(&mut reader, &mut io::sink()) {
+            Ok(_) => Ok(true),
+            Err(ref e) if CorruptObject::is(e) => Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e).chain_err(|| format!("couldn't read object {}", digest)),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use self::tempdir::TempDir;
+
+    use {Dag, Node};
+    use super::Problem;
+
+    #[test]
+    fn clean() {
+        let dir = TempDir::new("dag_fsck_clean").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let blob = dag.save(b"blob").unwrap();
+        let node = dag.save_node(&Node::new().link("blob", blob)).unwrap();
+        dag.set_root("current", &node).unwrap();
+        let report = dag.fsck(false).unwrap();
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!(report.objects, 2);
+    }
+
+    #[test]
+    fn finds_and_quarantines_damage() {
+        let dir = TempDir::new("dag_fsck").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let blob = dag.save(b"a whole blob").unwrap();
+        let node = dag.save_node(&Node::new().link("blob", blob.clone())).unwrap();
+        dag.set_root("current", &node).unwrap();
+        dag.set_root("old", &blob).unwrap();
+
+        File::create(dir.path().join("o").join(blob.file_name())).unwrap().write_all(b"a whole").unwrap();
+        File::create(dir.path().join("o").join("notes.txt")).unwrap();
+        File::create(dir.path().join("r").join("weird")).unwrap().write_all(b"nonsense").unwrap();
+        File::create(dir.path().join("tmp").join(".tmpabc")).unwrap();
+
+        let report = dag.fsck(false).unwrap();
+        assert_eq!(report.problems.len(), 6, "{:?}", report.problems);
+        assert!(report.problems.contains(&Problem::Corrupt { digest: blob.clone(), len: 7 }));
+        assert!(report.problems.contains(&Problem::Misnamed("notes.txt".into())));
+        assert!(report.problems.contains(&Problem::DanglingRoot { id: "old".into(), digest: blob.clone() }));
+        assert!(
+            report.problems.contains(
+                &Problem::DanglingLink { node: node.clone(), name: "blob".into(), digest: blob.clone() }
+            )
+        );
+        assert!(report.problems.iter().any(|p| match *p {
+            Problem::BadRoot { ref id, .. } => id == "weird",
+            _ => false,
+        }));
+        assert!(report.quarantined.is_empty());
+        assert!(dag.contains_unchecked(&blob).unwrap());
+
+        let report = dag.fsck(true).unwrap();
+        assert_eq!(report.quarantined.len(), 2);
+        assert!(!dag.contains_unchecked(&blob).unwrap());
+        let quarantined = fs::read_dir(dir.path().join("quarantine")).unwrap().count();
+        assert_eq!(quarantined, 2);
+    }
+}
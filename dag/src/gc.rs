@@ -0,0 +1,205 @@
+//! Sweeps objects that no root (nor any root's history) can reach.
+//!
+//! Objects younger than the grace period are always kept, along with whatever
+//! they link to, which covers a concurrent `save` of something about to be
+//! linked: new objects are young, and re-saving an existing object touches it.
+//! Other processes are kept out entirely by an exclusive lock on the store.
+
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use digest::Digest;
+use errors::*;
+use lock::LockMode;
+use super::Dag;
+
+#[derive(Clone, Debug)]
+pub struct GcOptions {
+    /// Keep anything modified more recently than this.
+    pub grace: Duration,
+    /// Only report what would be removed.
+    pub dry_run: bool,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        GcOptions {
+            grace: Duration::from_secs(60 * 60),
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GcReport {
+    /// Objects reachable from roots or their histories.
+    pub live: usize,
+    /// Unreachable objects kept for being too young.
+    pub young: usize,
+    /// Objects removed (or that would be, in a dry run).
+    pub removed: Vec<Digest>,
+    /// Abandoned temp files removed.
+    pub temps: usize,
+    /// Size of everything removed.
+    pub reclaimed: u64,
+}
+
+impl Dag {
+    /// Fails without removing anything if a reachable object is missing or corrupt,
+    /// or with `LockTimeout` if the store stays in use for too long.
+    pub fn gc(&self, options: &GcOptions) -> Result<GcReport> {
+        let _lock = self.lock(LockMode::Exclusive)?;
+        let dag = self.under_lock();
+        let mut report = GcReport::default();
+        let live = dag.live_objects()?;
+        let now = SystemTime::now();
+
+        let mut unreachable = Vec::new();
+        for digest in self.store.list()? {
+            if live.contains(&digest) {
+                report.live += 1;
+            } else if let Some(meta) = self.store.stat(&digest)? {
+                unreachable.push((digest, meta));
+            }
+        }
+
+        let young = unreachable
+            .iter()
+            .filter(|&&(_, ref meta)| meta.is_young(now, options.grace))
+            .map(|&(ref digest, _)| digest.clone())
+            .collect();
+        let young = dag.young_objects(young)?;
+
+        for (digest, meta) in unreachable {
+            if young.contains(&digest) {
+                report.young += 1;
+                continue;
+            }
+            if !options.dry_run && !self.store.sweep(&digest, now, options.grace)? {
+                // touched by a concurrent `save`
+                report.young += 1;
+                continue;
+            }
+            report.reclaimed += meta.len;
+            report.removed.push(digest);
+        }
+
+        let (temps, size) = self.store.sweep_temps(now, options.grace, options.dry_run)?;
+        report.temps = temps;
+        report.reclaimed += size;
+
+        Ok(report)
+    }
+
+    fn live_objects(&self) -> Result<HashSet<Digest>> {
+        let mut roots = Vec::new();
+        for (id, digest) in self.roots()? {
+            for change in self.root_history(&id)? {
+                roots.extend(change.old);
+                roots.push(change.new);
+            }
+            roots.push(digest);
+        }
+        self.reachable(&roots).chain_err(|| "not collecting garbage from a broken dag (try fsck)")
+    }
+
+    /// These objects and everything they link to, as far as it exists.
+    fn young_objects(&self, young: Vec<Digest>) -> Result<HashSet<Digest>> {
+        let mut kept: HashSet<Digest> = young.iter().cloned().collect();
+        let mut stack = young;
+        while let Some(digest) = stack.pop() {
+            let links = match self.links(&digest) {
+                Ok(links) => links,
+                // linked before being saved, perhaps
+                Err(Error(ErrorKind::NotFound(_), _)) => continue,
+                Err(e) => return Err(e),
+            };
+            for link in links {
+                if kept.insert(link.digest.clone()) {
+                    stack.push(link.digest);
+                }
+            }
+        }
+        Ok(kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::time::Duration;
+
+    use self::tempdir::TempDir;
+    use filetime::{self, FileTime};
+
+    use {Dag, Node};
+    use super::GcOptions;
+
+    #[test]
+    fn sweeps_unreachable() {
+        let dir = TempDir::new("dag_gc").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let old = dag.save(b"old driver").unwrap();
+        let kept = dag.save(b"new driver").unwrap();
+        let garbage = dag.save(b"never linked").unwrap();
+        let release = dag.save_node(&Node::new().link("driver", kept.clone())).unwrap();
+        dag.set_root("current", &old).unwrap();
+        dag.set_root("current", &release).unwrap();
+        File::create(dir.path().join("tmp").join(".tmpcrash")).unwrap().write_all(b"half an obj").unwrap();
+
+        // everything is young by default
+        let report = dag.gc(&GcOptions::default()).unwrap();
+        assert_eq!((report.live, report.young, report.temps), (3, 1, 0));
+        assert!(report.removed.is_empty());
+
+        let now = GcOptions { grace: Duration::from_secs(0), dry_run: true };
+        let report = dag.gc(&now).unwrap();
+        assert_eq!(report.removed, vec![garbage.clone()]);
+        assert_eq!(report.temps, 1);
+        assert_eq!(report.reclaimed, b"never linked".len() as u64 + b"half an obj".len() as u64);
+        assert!(dag.contains(&garbage).unwrap());
+
+        let now = GcOptions { dry_run: false, ..now };
+        assert_eq!(dag.gc(&now).unwrap().removed, vec![garbage.clone()]);
+        assert!(!dag.contains(&garbage).unwrap());
+        // the old root target lives on in its history
+        for digest in &[old, kept, release] {
+            assert!(dag.contains(digest).unwrap());
+        }
+        assert_eq!(fs::read_dir(dir.path().join("tmp")).unwrap().count(), 0);
+        assert!(dag.gc(&now).unwrap().removed.is_empty());
+    }
+
+    #[test]
+    fn young_objects_keep_their_chunks() {
+        let dir = TempDir::new("dag_gc_chunks").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let bytes: Vec<u8> = (0..600_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 11) as u8).collect();
+        let blob = dag.save_chunked(&bytes).unwrap();
+        let long_ago = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+        for link in dag.links(&blob).unwrap() {
+            let path = dir.path().join("o").join(link.digest.file_name());
+            filetime::set_file_times(path, long_ago, long_ago).unwrap();
+        }
+
+        let grace = GcOptions { grace: Duration::from_secs(60), dry_run: false };
+        let report = dag.gc(&grace).unwrap();
+        assert!(report.removed.is_empty());
+        assert_eq!(&dag.load(&blob).unwrap()[..], &bytes[..]);
+    }
+
+    #[test]
+    fn refuses_broken_dags() {
+        let dir = TempDir::new("dag_gc_broken").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let garbage = dag.save(b"garbage").unwrap();
+        let missing = ::Digest::from_bytes(b"missing");
+        let node = dag.save_node(&Node::new().link("gone", missing)).unwrap();
+        dag.set_root("broken", &node).unwrap();
+        assert!(dag.gc(&GcOptions { grace: Duration::from_secs(0), dry_run: false }).is_err());
+        assert!(dag.contains(&garbage).unwrap());
+    }
+}
@@ -0,0 +1,96 @@
+//! Each root's log of where it used to point, one line per move:
+//! `<seconds since epoch> <old digest, or -> <new digest>`, with digests
+//! written as their `file_name`s so logs from before tagging read the same.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use digest::Digest;
+use errors::*;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RootChange {
+    /// To the second.
+    pub time: SystemTime,
+    /// `None` when the root was created.
+    pub old: Option<Digest>,
+    pub new: Digest,
+}
+
+impl RootChange {
+    fn to_line(&self) -> String {
+        let secs = self.time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let old = self.old.as_ref().map(Digest::file_name).unwrap_or_else(|| "-".into());
+        format!("{} {} {}\n", secs, old, self.new.file_name())
+    }
+
+    fn parse(line: &str) -> ::std::result::Result<Self, ()> {
+        let mut fields = line.split(' ');
+        let mut field = || fields.next().ok_or(());
+        let secs = field()?.parse().map_err(|_| ())?;
+        let old = match field()? {
+            "-" => None,
+            hex => Some(hex.parse()?),
+        };
+        let new = field()?.parse()?;
+        if field().is_ok() {
+            return Err(());
+        }
+        Ok(RootChange { time: UNIX_EPOCH + Duration::from_secs(secs), old, new })
+    }
+}
+
+/// The caller must hold the root's lock.
+pub fn append(path: &Path, change: &RootChange) -> Result<()> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| {
+            file.write_all(change.to_line().as_bytes())?;
+            file.sync_all()
+        })
+        .chain_err(|| format!("couldn't append to {}", path.display()))
+}
+
+/// Oldest first. A root that has never moved has no history.
+pub fn read(path: &Path) -> Result<Vec<RootChange>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).chain_err(|| format!("couldn't open {}", path.display())),
+    };
+    let mut changes = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.chain_err(|| format!("couldn't read {}", path.display()))?;
+        let change = RootChange::parse(&line)
+            .map_err(|()| format!("{}:{}: malformed history", path.display(), i + 1))?;
+        changes.push(change);
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use digest::Digest;
+    use super::RootChange;
+
+    #[test]
+    fn lines() {
+        let change = RootChange {
+            time: UNIX_EPOCH + Duration::from_secs(1497960191),
+            old: None,
+            new: Digest::sample(),
+        };
+        let line = change.to_line();
+        assert!(line.starts_with("1497960191 - 3355"));
+        let tagged = format!("1497960191 - {}", change.new);
+        assert_eq!(RootChange::parse(line.trim_right()), Ok(change.clone()));
+        assert_eq!(RootChange::parse(&tagged), Ok(change));
+        assert_eq!(RootChange::parse("12 - nope"), Err(()));
+    }
+}
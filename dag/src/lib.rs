@@ -6,116 +6,533 @@ extern crate bytes;
 extern crate digest as digest_crate;
 #[macro_use]
 extern crate error_chain;
+extern crate filetime;
+extern crate fs2;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate sha3;
+extern crate tempfile;
 
-use std::ffi::OsStr;
-use std::fs::{self, File};
+use std::collections::HashSet;
 use std::io;
 use std::io::prelude::*;
-use std::path::{Component, Path, PathBuf};
+use std::path::{Component, Path};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use digest::{Algorithm, HashWriter};
+use store::Lock;
 
 pub mod bincoded;
+pub mod chunk;
+pub mod delta;
 pub mod digest;
+pub mod envelope;
+pub mod frame;
+pub mod fs_store;
+pub mod fsck;
+pub mod gc;
+pub mod history;
+pub mod lock;
+pub mod mem_store;
+pub mod node;
+pub mod reader;
+pub mod store;
 
+pub use bytes::Bytes;
+pub use chunk::Manifest;
 pub use digest::Digest;
+pub use envelope::{Envelope, Schema};
 pub use errors::*;
+pub use fs_store::FsStore;
+pub use fsck::{FsckReport, Problem};
+pub use gc::{GcOptions, GcReport};
+pub use history::RootChange;
+pub use lock::LockMode;
+pub use mem_store::MemStore;
+pub use node::{Link, Node, Object};
+pub use reader::{CorruptObject, Reader};
+pub use store::{Overlay, Store};
 
 pub mod errors {
     use std::io;
 
+    use digest::Digest;
+
     error_chain! {
+        errors {
+            Corrupt(digest: Digest) {
+                description("corrupt object")
+                display("object {} doesn't match its digest", digest)
+            }
+            NotFound(digest: Digest) {
+                description("object not found")
+                display("object {} not found", digest)
+            }
+            PrefixNotFound(prefix: String) {
+                description("no digest has that prefix")
+                display("no digest starts with {:?}", prefix)
+            }
+            AmbiguousPrefix(prefix: String, matches: Vec<Digest>) {
+                description("ambiguous digest prefix")
+                display("{:?} is ambiguous; it could be {}", prefix, list_digests(matches))
+            }
+            LockTimeout(what: String, waited_ms: u64) {
+                description("timed out waiting for a lock")
+                display("gave up after {} ms waiting for {}; something else is using the dag",
+                        waited_ms, what)
+            }
+            RootMoved(id: String, found: Option<Digest>) {
+                description("root moved")
+                display("root {:?} has moved (to {})", id,
+                        found.as_ref().map(|d| d.to_string()).unwrap_or_else(|| "nothing".into()))
+            }
+        }
         foreign_links {
             Io(io::Error);
         }
     }
+
+    /// The first few of `digests`, in full, since their short hex may coincide.
+    fn list_digests(digests: &[Digest]) -> String {
+        const SHOWN: usize = 5;
+        let mut list = digests.iter().take(SHOWN).map(|d| d.to_string()).collect::<Vec<_>>().join(", ");
+        if digests.len() > SHOWN {
+            list.push_str(&format!(" or {} others", digests.len() - SHOWN));
+        }
+        list
+    }
 }
 
+/// How long to wait for whoever else is using the store, by default.
+const DEFAULT_LOCK_TIMEOUT_MS: u64 = 30_000;
+
+/// A store of objects and the roots pointing into it, which any number of
+/// processes can share. Reading and saving take a shared lock on the store;
+/// moving a root takes an exclusive lock on that root, and `gc` takes an
+/// exclusive lock on the whole store.
 pub struct Dag {
-    objs: PathBuf,
-    roots: PathBuf,
+    store: Arc<Store>,
+    lock_timeout: Duration,
+    /// Whether whoever made this view already holds the store's lock.
+    locked: bool,
 }
 
 impl Dag {
+    /// Opens the dag kept in `dir`, creating it if need be.
     pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        Ok(Dag::with_store(Arc::new(FsStore::new(dir)?)))
+    }
 
-        fn mkdir(dir: PathBuf) -> Result<PathBuf> {
-            match dir.metadata() {
-                Ok(meta) => {
-                    ensure!(meta.is_dir(), "not a directory");
-                    Ok(dir)
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-                    fs::create_dir_all(&dir)?;
-                    Ok(dir)
+    pub fn with_store(store: Arc<Store>) -> Self {
+        Dag {
+            store,
+            lock_timeout: Duration::from_millis(DEFAULT_LOCK_TIMEOUT_MS),
+            locked: false,
+        }
+    }
+
+    /// How long to wait for a lock before failing with `LockTimeout`.
+    pub fn set_lock_timeout(&mut self, timeout: Duration) {
+        self.lock_timeout = timeout;
+    }
+
+    /// Locks the store, unless this view was made under its lock.
+    fn lock(&self, mode: LockMode) -> Result<Option<Box<Lock>>> {
+        if self.locked {
+            return Ok(None);
+        }
+        self.store.lock(mode, self.lock_timeout).map(Some)
+    }
+
+    /// This dag, for use while holding the store's lock: asking for it
+    /// again would only wait for ourselves.
+    fn under_lock(&self) -> Dag {
+        Dag { store: self.store.clone(), lock_timeout: self.lock_timeout, locked: true }
+    }
+
+    /// Stores `bytes` under their digest.
+    ///
+    /// Objects are staged, then stored atomically (on disk: written to `tmp/`,
+    /// synced, and renamed into place), so a crash can't leave a truncated
+    /// object behind. If the object is already present, it's checked and
+    /// touched instead (and replaced if corrupt), so that `gc` treats it as
+    /// freshly written. Concurrent saves of the same object are fine.
+    ///
+    /// Blobs that look like nodes are refused; use `save_node` for those.
+    /// A blob already stored in chunks stays that way.
+    pub fn save(&self, bytes: &[u8]) -> Result<Digest> {
+        self.save_from(bytes)
+    }
+
+    /// Like `save`, but streams the blob from `reader`, hashing it on its way
+    /// into the store, so it's read just once and needn't fit in memory.
+    pub fn save_from<R: Read>(&self, reader: R) -> Result<Digest> {
+        let mut reader = reader;
+        let mut head = Vec::with_capacity(node::RESERVED.len());
+        (&mut reader)
+            .take(node::RESERVED.len() as u64)
+            .read_to_end(&mut head)
+            .chain_err(|| "couldn't read blob")?;
+        ensure!(!node::is_reserved(&head), "blob starts with a reserved header");
+
+        let _lock = self.lock(LockMode::Shared)?;
+        let mut writer = HashWriter::new(self.store.stage()?, Algorithm::default());
+        writer.write_all(&head)
+            .and_then(|()| io::copy(&mut reader, &mut writer))
+            .chain_err(|| "couldn't stage blob")?;
+        let (mut staged, digest, len) = writer.finish();
+
+        match self.manifest(&digest) {
+            Ok(Some(_)) => {
+                // chunking needs the whole blob anyway
+                let mut bytes = Vec::with_capacity(len as usize);
+                staged.seek(io::SeekFrom::Start(0))
+                    .and_then(|_| staged.read_to_end(&mut bytes))
+                    .chain_err(|| "couldn't reread staged blob")?;
+                self.save_chunks(digest, &bytes)
+            }
+            Ok(None) | Err(Error(ErrorKind::NotFound(_), _)) => {
+                if !self.touch_if_intact(&digest, len)? {
+                    staged.commit(&digest)?;
                 }
-                Err(e) => Err(e.into()),
+                Ok(digest)
             }
+            Err(e) => Err(e),
         }
+    }
 
-        let dir = dir.as_ref();
-        let objs = mkdir(dir.join("o"))?;
-        let roots = mkdir(dir.join("r"))?;
-        Ok(Dag { objs, roots })
+    /// Like `save`, but splits big blobs into content-defined chunks, each
+    /// stored as its own object, so that similar blobs share storage.
+    /// The blob's digest then holds a `Manifest`; reading it reassembles the blob.
+    pub fn save_chunked(&self, bytes: &[u8]) -> Result<Digest> {
+        ensure!(!node::is_reserved(bytes), "blob starts with a reserved header");
+        let _lock = self.lock(LockMode::Shared)?;
+        self.save_chunks(Digest::from_bytes(bytes), bytes)
     }
 
-    pub fn save(&self, bytes: &[u8]) -> Result<Digest> {
-        use std::os::unix::ffi::OsStrExt;
-
-        let digest = Digest::from_bytes(bytes);
-        let path = self.objs.join(OsStr::from_bytes(&digest.hex_bytes()));
-        // XXX write to temp dir then move!
-        let mut f = File::create(path)?;
-        f.write_all(bytes)?;
-        f.sync_all()?;
+    /// Stores a node. Its children needn't be stored yet, but should be before
+    /// anyone tries to walk it.
+    pub fn save_node(&self, node: &Node) -> Result<Digest> {
+        let bytes = node.encode();
+        let _lock = self.lock(LockMode::Shared)?;
+        self.save_raw(Digest::from_bytes(&bytes), &bytes)
+    }
+
+    fn save_chunks(&self, digest: Digest, bytes: &[u8]) -> Result<Digest> {
+        let pieces = chunk::split(bytes);
+        if pieces.len() < 2 {
+            return self.save_raw(digest, bytes);
+        }
+        // always (re)saved first, which touches them for `gc`
+        let mut chunks = Vec::with_capacity(pieces.len());
+        for piece in pieces {
+            chunks.push(self.save_raw(Digest::from_bytes(piece), piece)?);
+        }
+        let manifest = Manifest { len: bytes.len() as u64, chunks };
+        self.store.put(&digest, &manifest.encode())?;
         Ok(digest)
     }
 
-    pub fn set_root(&self, id: &str, digest: &Digest) -> Result<()> {
-        use std::os::unix::ffi::OsStrExt;
+    fn save_raw(&self, digest: Digest, bytes: &[u8]) -> Result<Digest> {
+        if !self.touch_if_intact(&digest, bytes.len() as u64)? {
+            self.store.put(&digest, bytes)?;
+        }
+        Ok(digest)
+    }
 
-        validate_root_name(Path::new(id))?;
-        let link = self.roots.join(id);
+    /// Touches the object if it's already stored intact, so `gc` treats it
+    /// as freshly written. `false` means it must be (re)written.
+    fn touch_if_intact(&self, digest: &Digest, len: u64) -> Result<bool> {
+        let existing = match self.store.open(digest)? {
+            Some(existing) => existing,
+            None => return Ok(false),
+        };
+        let (found, found_len) = Digest::from_read_with(digest.algorithm(), existing)
+            .chain_err(|| format!("couldn't read object {}", digest))?;
+        if &found != digest || found_len as u64 != len {
+            // corrupt, so overwrite it
+            return Ok(false);
+        }
+        // if it was swept just now, it's written afresh
+        self.store.touch(digest)
+    }
+
+    /// Reads a whole object, checking that it still matches `digest`.
+    pub fn load(&self, digest: &Digest) -> Result<Bytes> {
+        let bytes = self.load_unchecked(digest)?;
+        check_intact(digest, &digest.algorithm().digest(&bytes))?;
+        Ok(bytes)
+    }
+
+    /// Like `load`, but trusts the store.
+    pub fn load_unchecked(&self, digest: &Digest) -> Result<Bytes> {
+        let mut bytes = Vec::new();
+        self.open_unchecked(digest)?
+            .read_to_end(&mut bytes)
+            .chain_err(|| format!("couldn't read object {}", digest))?;
+        Ok(Bytes::from(bytes))
+    }
+
+    /// Streams an object; reading to the end checks it against `digest`.
+    /// The store stays locked until the reader is dropped.
+    pub fn open(&self, digest: &Digest) -> Result<Reader> {
+        self.reader(digest, true)
+    }
+
+    /// Like `open`, but trusts the store.
+    pub fn open_unchecked(&self, digest: &Digest) -> Result<Reader> {
+        self.reader(digest, false)
+    }
+
+    fn reader(&self, digest: &Digest, check: bool) -> Result<Reader> {
+        let lock = self.lock(LockMode::Shared)?;
+        let pieces = self.pieces(digest)?;
+        Ok(Reader::new(self.store.clone(), lock, pieces, digest.clone(), check))
+    }
+
+    /// Whether the object is stored. Errs if it is, but has been corrupted
+    /// (or lost some of its chunks).
+    pub fn contains(&self, digest: &Digest) -> Result<bool> {
+        let mut reader = match self.open(digest) {
+            Ok(reader) => reader,
+            Err(Error(ErrorKind::NotFound(ref missing), _)) if missing == digest => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        match io::copy(&mut reader, &mut io::sink()) {
+            Ok(_) => Ok(true),
+            Err(ref e) if CorruptObject::is(e) => bail!(ErrorKind::Corrupt(digest.clone())),
+            Err(e) => Err(e).chain_err(|| format!("couldn't read object {}", digest)),
+        }
+    }
 
-        let mut obj = PathBuf::new(); // capacity?
-        obj.push(Component::ParentDir.as_ref());
-        obj.push("o");
-        obj.push(OsStr::from_bytes(&digest.hex_bytes()));
-        //let target = self.objs.join(OsStr::from_bytes(&digest.hex_bytes()));
+    /// The objects holding an object's contents, in order: just itself,
+    /// unless it's stored in chunks.
+    fn pieces(&self, digest: &Digest) -> Result<Vec<Digest>> {
+        let mut pieces = Vec::new();
+        self.collect_pieces(digest, &mut pieces)?;
+        Ok(pieces)
+    }
 
-        std::os::unix::fs::symlink(obj, link).chain_err(|| format!("symlink {:?}", id))?;
+    fn collect_pieces(&self, digest: &Digest, pieces: &mut Vec<Digest>) -> Result<()> {
+        match self.manifest(digest)? {
+            // a chunk may itself be a chunked blob saved separately
+            Some(manifest) => {
+                for chunk in &manifest.chunks {
+                    self.collect_pieces(chunk, pieces)?;
+                }
+            }
+            None => pieces.push(digest.clone()),
+        }
         Ok(())
     }
 
-    pub fn root(&self, id: &str) -> Result<Option<Digest>> {
-        let name = Path::new(id);
-        validate_root_name(name)?;
-        let target_path = match fs::read_link(self.roots.join(name)) {
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
-            s => s,
-        }?;
-        let mut cs = target_path.components();
-        ensure!(cs.next().map(|c| c == Component::ParentDir).unwrap_or(false),
-                "root {:?} is corrupt (..)", id);
-        ensure!(cs.next().map(|c| c == Component::Normal("o".as_ref())).unwrap_or(false),
-                "root {:?} is corrupt (o)", id);
-        let digest = cs.next()
-            .ok_or_else(|| format!("root {:?} is corrupt (missing hex)", id))
-            .and_then(|c| {
-                match c {
-                    Component::Normal(os) => os.to_str().ok_or_else(|| "bad utf8".into()),
-                    _ => Err(format!("root {:?} is corrupt (bad hex)", id).into()),
+    /// `None` unless the object is stored in chunks.
+    fn manifest(&self, digest: &Digest) -> Result<Option<Manifest>> {
+        let mut file = self.open_obj(digest)?;
+        let mut bytes = Vec::new();
+        (&mut file).take(chunk::MAGIC.len() as u64).read_to_end(&mut bytes)?;
+        if bytes != chunk::MAGIC {
+            return Ok(None);
+        }
+        file.read_to_end(&mut bytes)?;
+        Manifest::decode(&bytes).chain_err(|| format!("couldn't parse manifest {}", digest))
+    }
+
+    /// Loads and parses an object, checking its digest.
+    pub fn load_object(&self, digest: &Digest) -> Result<Object> {
+        Object::parse(self.load(digest)?).chain_err(|| format!("couldn't parse object {}", digest))
+    }
+
+    pub fn load_node(&self, digest: &Digest) -> Result<Node> {
+        match self.load_object(digest)? {
+            Object::Node(node) => Ok(node),
+            Object::Blob(_) => bail!("object {} is a blob, not a node", digest),
+        }
+    }
+
+    /// An object's links, without reading all of it if it's a blob.
+    /// Nodes are checked against their digests; blobs aren't.
+    /// A chunked blob links to each of its chunks.
+    pub fn links(&self, digest: &Digest) -> Result<Vec<Link>> {
+        let _lock = self.lock(LockMode::Shared)?;
+        let mut header = Vec::with_capacity(node::MAGIC.len());
+        self.open_obj(digest)?
+            .take(node::MAGIC.len() as u64)
+            .read_to_end(&mut header)?;
+        if node::is_node(&header) {
+            return Ok(self.load_node(digest)?.links);
+        }
+        let chunks = self.manifest(digest)?.map(|m| m.chunks).unwrap_or_default();
+        let links = chunks.into_iter().map(|digest| Link { name: "chunk".into(), digest }).collect();
+        Ok(links)
+    }
+
+    /// Visits everything reachable from `root` once, parents before children.
+    /// Fails if any of it is missing.
+    pub fn walk<F>(&self, root: &Digest, mut visit: F) -> Result<()>
+    where
+        F: FnMut(&Digest, &[Link]) -> Result<()>,
+    {
+        let mut seen = HashSet::new();
+        self.walk_from(root, &mut seen, &mut visit)
+    }
+
+    /// Every object reachable from any of `roots`, including the roots.
+    pub fn reachable<'a, I>(&self, roots: I) -> Result<HashSet<Digest>>
+    where
+        I: IntoIterator<Item = &'a Digest>,
+    {
+        let mut seen = HashSet::new();
+        for root in roots {
+            self.walk_from(root, &mut seen, &mut |_: &Digest, _: &[Link]| Ok(()))?;
+        }
+        Ok(seen)
+    }
+
+    fn walk_from<F>(&self, root: &Digest, seen: &mut HashSet<Digest>, visit: &mut F) -> Result<()>
+    where
+        F: FnMut(&Digest, &[Link]) -> Result<()>,
+    {
+        let mut stack = vec![root.clone()];
+        while let Some(digest) = stack.pop() {
+            // marked when popped rather than pushed, so a shared child is
+            // visited where it's first linked, not where it's linked last
+            if !seen.insert(digest.clone()) {
+                continue;
+            }
+            let links = self.links(&digest)?;
+            visit(&digest, &links)?;
+            // reversed, so the first link is visited first
+            for link in links.into_iter().rev() {
+                if !seen.contains(&link.digest) {
+                    stack.push(link.digest);
                 }
-            })
-            .and_then(|s| s.parse::<Digest>().map_err(|()| format!("root {:?} bad hex", id)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Everything reachable from `root`, children before parents, ending with `root`.
+    /// Saving (or sending) objects in this order never leaves a dangling link.
+    pub fn topo_order(&self, root: &Digest) -> Result<Vec<Digest>> {
+        let mut order = Vec::new();
+        let mut seen = HashSet::new();
+        seen.insert(root.clone());
+        // each entry holds the links still to descend into
+        let mut stack = vec![(root.clone(), self.links(root)?.into_iter())];
+        loop {
+            let next = match stack.last_mut() {
+                Some(&mut (_, ref mut links)) => links.next(),
+                None => break,
+            };
+            match next {
+                Some(link) => {
+                    if seen.insert(link.digest.clone()) {
+                        let links = self.links(&link.digest)?;
+                        stack.push((link.digest, links.into_iter()));
+                    }
+                }
+                None => {
+                    let (digest, _) = stack.pop().expect("stack top");
+                    order.push(digest);
+                }
+            }
+        }
+        Ok(order)
+    }
+
+    /// Like `contains`, but doesn't read the object.
+    pub fn contains_unchecked(&self, digest: &Digest) -> Result<bool> {
+        let _lock = self.lock(LockMode::Shared)?;
+        Ok(self.store.stat(digest)?.is_some())
+    }
 
-        ensure!(cs.next().is_none(), "root {:?} is corrupt (trailer)");
-        Ok(Some(digest))
+    /// The one stored object whose digest starts with `prefix`, as printed
+    /// by `Digest::short_hex`. Fails with `AmbiguousPrefix` or `PrefixNotFound`
+    /// otherwise. Chunks and nodes count as objects too.
+    pub fn resolve_prefix(&self, prefix: &str) -> Result<Digest> {
+        let _lock = self.lock(LockMode::Shared)?;
+        digest::resolve_prefix(prefix, self.store.list()?)
+    }
+
+    fn open_obj(&self, digest: &Digest) -> Result<Box<Read + Send>> {
+        match self.store.open(digest)? {
+            Some(reader) => Ok(reader),
+            None => bail!(ErrorKind::NotFound(digest.clone())),
+        }
+    }
+
+    /// Points `id` at `digest`, wherever it pointed before, returning that.
+    pub fn set_root(&self, id: &str, digest: &Digest) -> Result<Option<Digest>> {
+        validate_root_name(Path::new(id))?;
+        let _lock = self.lock(LockMode::Shared)?;
+        let _root_lock = self.store.lock_root(id, self.lock_timeout)?;
+        let old = self.root(id)?;
+        self.move_root(id, old.clone(), digest)?;
+        Ok(old)
+    }
+
+    /// Points `id` at `digest`, but only if it currently points at `expected`
+    /// (or doesn't exist, for `None`). Otherwise fails with `RootMoved`.
+    pub fn update_root(&self, id: &str, expected: Option<&Digest>, digest: &Digest) -> Result<()> {
+        validate_root_name(Path::new(id))?;
+        let _lock = self.lock(LockMode::Shared)?;
+        let _root_lock = self.store.lock_root(id, self.lock_timeout)?;
+        let old = self.root(id)?;
+        if old.as_ref() != expected {
+            bail!(ErrorKind::RootMoved(id.to_owned(), old));
+        }
+        self.move_root(id, old, digest)
+    }
+
+    /// Every root and its target, sorted by name.
+    pub fn roots(&self) -> Result<Vec<(String, Digest)>> {
+        let _lock = self.lock(LockMode::Shared)?;
+        let mut roots = Vec::new();
+        for id in self.store.root_ids()? {
+            if let Some(digest) = self.root(&id)? {
+                roots.push((id, digest));
+            }
+        }
+        roots.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(roots)
+    }
+
+    /// Everywhere `id` has pointed, oldest first; the last entry is its current target.
+    pub fn root_history(&self, id: &str) -> Result<Vec<RootChange>> {
+        validate_root_name(Path::new(id))?;
+        let _lock = self.lock(LockMode::Shared)?;
+        self.store.history(id)
+    }
+
+    /// The caller must hold the root's lock.
+    fn move_root(&self, id: &str, old: Option<Digest>, digest: &Digest) -> Result<()> {
+        if old.as_ref() == Some(digest) {
+            return Ok(());
+        }
+
+        self.store.write_root(id, digest)?;
+
+        let change = RootChange { time: SystemTime::now(), old, new: digest.clone() };
+        self.store.append_history(id, &change)
+    }
+
+    pub fn root(&self, id: &str) -> Result<Option<Digest>> {
+        validate_root_name(Path::new(id))?;
+        let _lock = self.lock(LockMode::Shared)?;
+        self.store.read_root(id)
     }
 }
 
+fn check_intact(digest: &Digest, found: &Digest) -> Result<()> {
+    if found != digest {
+        bail!(ErrorKind::Corrupt(digest.clone()));
+    }
+    Ok(())
+}
+
 fn validate_root_name(name: &Path) -> Result<()> {
     let mut cs = name.components();
     match cs.next() {
@@ -130,15 +547,22 @@ fn validate_root_name(name: &Path) -> Result<()> {
 mod tests {
     extern crate tempdir;
 
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
     use self::tempdir::TempDir;
+    use filetime::{self, FileTime};
 
-    use super::Dag;
+    use super::{CorruptObject, Dag, Digest, Error, ErrorKind, GcOptions, Node};
 
     #[test]
     fn smoke() {
         let dir = TempDir::new("dag_smoke").unwrap();
         let dag = Dag::new(dir.path()).unwrap();
-        ::std::mem::forget(dir); // TEMP
         assert_eq!(dag.root("404").expect("404"), None);
         assert!(dag.root("/").is_err());
 
@@ -146,4 +570,339 @@ mod tests {
         dag.set_root("abc", &dest).unwrap();
         assert_eq!(dag.root("abc").expect("abc"), Some(dest));
     }
+
+    fn contents(path: &Path) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn obj_path(dir: &Path, digest: &Digest) -> PathBuf {
+        dir.join("o").join(digest.file_name())
+    }
+
+    fn leftover_temps(dir: &Path) -> usize {
+        fs::read_dir(dir.join("tmp")).unwrap().count()
+    }
+
+    #[test]
+    fn save_twice() {
+        let dir = TempDir::new("dag_save_twice").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let first = dag.save(b"hello").unwrap();
+        let path = obj_path(dir.path(), &first);
+        let long_ago = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+        filetime::set_file_times(&path, long_ago, long_ago).unwrap();
+        let second = dag.save(b"hello").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(contents(&path), b"hello");
+        // touched, so gc sees it as new
+        let mtime = FileTime::from_last_modification_time(&fs::metadata(&path).unwrap());
+        assert!(mtime > long_ago);
+        assert_eq!(leftover_temps(dir.path()), 0);
+    }
+
+    #[test]
+    fn save_replaces_corrupt_object() {
+        let dir = TempDir::new("dag_corrupt").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let digest = dag.save(b"intact").unwrap();
+        let path = obj_path(dir.path(), &digest);
+        File::create(&path).unwrap().write_all(b"int").unwrap();
+
+        assert_eq!(dag.save(b"intact").unwrap(), digest);
+        assert_eq!(contents(&path), b"intact");
+        assert_eq!(leftover_temps(dir.path()), 0);
+    }
+
+    #[test]
+    fn save_from_reader() {
+        let dir = TempDir::new("dag_save_from").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let blob = vec![9u8; 100_000];
+        let digest = dag.save_from(&blob[..]).unwrap();
+        assert_eq!(digest, Digest::from_bytes(&blob));
+        assert_eq!(&dag.load(&digest).unwrap()[..], &blob[..]);
+        assert_eq!(dag.save_from(&b""[..]).unwrap(), Digest::from_bytes(b""));
+
+        let mut reserved = ::node::MAGIC.to_vec();
+        reserved.extend_from_slice(b"not really");
+        assert!(dag.save_from(&reserved[..]).is_err());
+        assert!(dag.save(&::node::RESERVED[..3]).is_ok());
+        assert_eq!(leftover_temps(dir.path()), 0);
+    }
+
+    #[test]
+    fn resolve_prefix() {
+        let dir = TempDir::new("dag_prefix").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let a = dag.save(b"first").unwrap();
+        let b = dag.save(b"second").unwrap();
+        assert_eq!(dag.resolve_prefix(&a.short_hex()).unwrap(), a);
+        assert_eq!(dag.resolve_prefix(&b.short_hex().to_uppercase()).unwrap(), b);
+        assert_eq!(dag.resolve_prefix(&a.to_string()).unwrap(), a);
+        match dag.resolve_prefix("") {
+            Err(Error(ErrorKind::Msg(_), _)) => (),
+            other => panic!("{:?}", other),
+        }
+        let missing = Digest::from_bytes(b"third");
+        match dag.resolve_prefix(&missing.short_hex()) {
+            Err(Error(ErrorKind::PrefixNotFound(_), _)) => (),
+            other => panic!("{:?}", other),
+        }
+        // seventeen objects, so two share a first hex digit
+        let mut saved: Vec<Digest> = (0..15u8).map(|i| dag.save(&[i]).unwrap()).collect();
+        saved.push(a);
+        saved.push(b);
+        let shared = (0..16)
+            .map(|i| format!("{:x}", i))
+            .find(|p| saved.iter().filter(|d| d.has_prefix(p)).count() > 1)
+            .unwrap();
+        match dag.resolve_prefix(&shared) {
+            Err(Error(ErrorKind::AmbiguousPrefix(ref prefix, ref found), _)) => {
+                assert_eq!(prefix, &shared);
+                assert!(found.len() > 1);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn load_checks_digest() {
+        let dir = TempDir::new("dag_load").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let digest = dag.save(b"payload").unwrap();
+        assert_eq!(&dag.load(&digest).unwrap()[..], b"payload");
+        assert!(dag.contains(&digest).unwrap());
+
+        File::create(obj_path(dir.path(), &digest)).unwrap().write_all(b"paylode").unwrap();
+        match dag.load(&digest) {
+            Err(Error(ErrorKind::Corrupt(ref d), _)) if d == &digest => (),
+            other => panic!("{:?}", other),
+        }
+        match dag.contains(&digest) {
+            Err(Error(ErrorKind::Corrupt(_), _)) => (),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(&dag.load_unchecked(&digest).unwrap()[..], b"paylode");
+        assert!(dag.contains_unchecked(&digest).unwrap());
+    }
+
+    #[test]
+    fn missing_objects() {
+        let dir = TempDir::new("dag_missing").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let digest = Digest::from_bytes(b"never saved");
+        assert!(!dag.contains(&digest).unwrap());
+        assert!(!dag.contains_unchecked(&digest).unwrap());
+        match dag.load(&digest) {
+            Err(Error(ErrorKind::NotFound(_), _)) => (),
+            other => panic!("{:?}", other),
+        }
+        assert!(dag.open(&digest).is_err());
+    }
+
+    #[test]
+    fn streaming_reads() {
+        let dir = TempDir::new("dag_stream").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let bytes: Vec<u8> = (0..20_000).map(|i| (i * 7) as u8).collect();
+        let digest = dag.save(&bytes).unwrap();
+
+        let mut read = Vec::new();
+        dag.open(&digest).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, bytes);
+
+        let mut corrupted = bytes.clone();
+        corrupted[12_345] ^= 1;
+        File::create(obj_path(dir.path(), &digest)).unwrap().write_all(&corrupted).unwrap();
+
+        let err = dag.open(&digest).unwrap().read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(CorruptObject::is(&err));
+        let mut read = Vec::new();
+        dag.open_unchecked(&digest).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, corrupted);
+    }
+
+    #[test]
+    fn graph_traversal() {
+        let dir = TempDir::new("dag_graph").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let driver = dag.save(b"driver").unwrap();
+        let g = dag.save(b"g").unwrap();
+        let asset = dag.save(b"asset").unwrap();
+        let assets = dag.save_node(&Node::new().link("logo.png", asset.clone())).unwrap();
+        // g is shared, so it should only be visited once
+        let release = Node::new()
+            .link("driver", driver.clone())
+            .link("g", g.clone())
+            .link("assets", assets.clone())
+            .link("g-again", g.clone());
+        let release = dag.save_node(&release).unwrap();
+
+        let mut visited = Vec::new();
+        dag.walk(&release, |digest, _| {
+            visited.push(digest.clone());
+            Ok(())
+        }).unwrap();
+        assert_eq!(visited, vec![release.clone(), driver.clone(), g.clone(), assets.clone(), asset.clone()]);
+
+        let order = dag.topo_order(&release).unwrap();
+        assert_eq!(order, vec![driver.clone(), g.clone(), asset.clone(), assets.clone(), release.clone()]);
+
+        let reachable = dag.reachable(&[assets.clone()]).unwrap();
+        assert_eq!(reachable.len(), 2);
+        assert!(reachable.contains(&asset) && !reachable.contains(&driver));
+
+        assert_eq!(dag.load_node(&assets).unwrap().get("logo.png"), Some(&asset));
+        assert!(dag.load_node(&driver).is_err());
+    }
+
+    #[test]
+    fn dangling_links() {
+        let dir = TempDir::new("dag_dangling").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let missing = Digest::from_bytes(b"not saved");
+        let node = dag.save_node(&Node::new().link("gone", missing)).unwrap();
+        match dag.walk(&node, |_, _| Ok(())) {
+            Err(Error(ErrorKind::NotFound(_), _)) => (),
+            other => panic!("{:?}", other),
+        }
+        assert!(dag.save(&Node::new().encode()).is_err());
+    }
+
+    #[test]
+    fn moving_roots() {
+        let dir = TempDir::new("dag_roots").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let one = dag.save(b"one").unwrap();
+        let two = dag.save(b"two").unwrap();
+
+        assert_eq!(dag.set_root("current", &one).unwrap(), None);
+        assert_eq!(dag.set_root("current", &two).unwrap(), Some(one.clone()));
+        match dag.update_root("current", Some(&one), &one) {
+            Err(Error(ErrorKind::RootMoved(_, Some(ref found)), _)) if found == &two => (),
+            other => panic!("{:?}", other),
+        }
+        dag.update_root("current", Some(&two), &one).unwrap();
+        assert!(dag.update_root("other", Some(&one), &two).is_err());
+        dag.update_root("other", None, &two).unwrap();
+
+        assert_eq!(dag.roots().unwrap(), vec![("current".to_owned(), one.clone()), ("other".to_owned(), two.clone())]);
+        let history: Vec<_> = dag.root_history("current")
+            .unwrap()
+            .into_iter()
+            .map(|change| (change.old, change.new))
+            .collect();
+        assert_eq!(history, vec![(None, one.clone()), (Some(one.clone()), two.clone()), (Some(two), one)]);
+        assert!(dag.root_history("never").unwrap().is_empty());
+        assert_eq!(leftover_temps(dir.path()), 0);
+    }
+
+    #[test]
+    fn concurrent_root_updates() {
+        let dir = TempDir::new("dag_root_race").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let start = dag.save(b"start").unwrap();
+        dag.set_root("r", &start).unwrap();
+        let threads: Vec<_> = (0..8u8)
+            .map(|i| {
+                let dag = Dag::new(dir.path()).unwrap();
+                let start = start.clone();
+                thread::spawn(move || {
+                    let mine = dag.save(&[i]).unwrap();
+                    dag.update_root("r", Some(&start), &mine).is_ok()
+                })
+            })
+            .collect();
+        let winners = threads.into_iter().map(|t| t.join().unwrap()).filter(|&won| won).count();
+        // exactly one compare-and-swap can succeed
+        assert_eq!(winners, 1);
+        assert_eq!(dag.root_history("r").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn gc_waits_for_readers() {
+        let dir = TempDir::new("dag_locks").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let garbage = dag.save(b"being read").unwrap();
+        let kept = dag.save(b"kept").unwrap();
+        // as good as another process: it locks through its own files
+        let mut other = Dag::new(dir.path()).unwrap();
+        other.set_lock_timeout(Duration::from_millis(50));
+        let now = GcOptions { grace: Duration::from_secs(0), dry_run: false };
+
+        let reader = dag.open(&garbage).unwrap();
+        match other.gc(&now) {
+            Err(Error(ErrorKind::LockTimeout(..), _)) => (),
+            r => panic!("{:?}", r),
+        }
+        // readers don't block each other, nor root updates
+        assert!(other.contains(&garbage).unwrap());
+        other.set_root("r", &kept).unwrap();
+        drop(reader);
+
+        assert_eq!(other.gc(&now).unwrap().removed, vec![garbage.clone()]);
+        assert!(!dag.contains(&garbage).unwrap());
+    }
+
+    #[test]
+    fn chunked_blobs() {
+        let dir = TempDir::new("dag_chunks").unwrap();
+        let dag = Dag::new(dir.path()).unwrap();
+        let v1: Vec<u8> = (0..1_000_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        let mut v2 = v1.clone();
+        v2[500_000] ^= 0xff;
+
+        let d1 = dag.save_chunked(&v1).unwrap();
+        assert_eq!(d1, Digest::from_bytes(&v1));
+        let objects = fs::read_dir(dir.path().join("o")).unwrap().count();
+        let d2 = dag.save_chunked(&v2).unwrap();
+        // one changed chunk, and v2's manifest
+        let added = fs::read_dir(dir.path().join("o")).unwrap().count() - objects;
+        assert!(added <= 3, "{} new objects", added);
+
+        assert_eq!(&dag.load(&d1).unwrap()[..], &v1[..]);
+        let mut read = Vec::new();
+        dag.open(&d2).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, v2);
+        assert!(dag.contains(&d2).unwrap());
+        assert!(dag.links(&d1).unwrap().len() > 2);
+
+        // saving it again the plain way keeps the chunks
+        assert_eq!(dag.save(&v1).unwrap(), d1);
+        assert!(dag.links(&d1).unwrap().len() > 2);
+
+        // losing a chunk breaks the blob, but not its neighbour
+        let shared = dag.links(&d1).unwrap();
+        let lost = dag.links(&d2).unwrap().into_iter().find(|l| !shared.contains(l)).unwrap();
+        fs::remove_file(obj_path(dir.path(), &lost.digest)).unwrap();
+        assert!(dag.load(&d2).is_err());
+        assert!(dag.contains(&d2).is_err());
+        assert!(dag.contains(&d1).unwrap());
+
+        // small blobs aren't worth a manifest
+        let small = dag.save_chunked(b"small").unwrap();
+        assert!(dag.links(&small).unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_saves() {
+        let dir = TempDir::new("dag_concurrent").unwrap();
+        let bytes: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let bytes = Arc::new(bytes);
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                // as if from separate processes
+                let dag = Dag::new(dir.path()).unwrap();
+                let bytes = bytes.clone();
+                thread::spawn(move || dag.save(&bytes).unwrap())
+            })
+            .collect();
+        let digests: Vec<Digest> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(digests.iter().all(|d| d == &digests[0]));
+
+        assert_eq!(contents(&obj_path(dir.path(), &digests[0])), &bytes[..]);
+        assert_eq!(leftover_temps(dir.path()), 0);
+    }
 }
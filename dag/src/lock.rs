@@ -0,0 +1,191 @@
+//! Advisory locks that give up after a while: `lock_file` between processes,
+//! and `LocalLock` between the threads of one.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fs2::{self, FileExt};
+
+use errors::*;
+use store::Lock;
+
+/// How often to retry a lock held by another process.
+const POLL_MS: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    /// Any number of holders at once, as long as nobody's exclusive.
+    Shared,
+    Exclusive,
+}
+
+impl fmt::Display for LockMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(
+            match *self {
+                LockMode::Shared => "a shared",
+                LockMode::Exclusive => "an exclusive",
+            }
+        )
+    }
+}
+
+/// Locks `path`, creating it if need be, and keeps it locked until the lock
+/// is dropped. The OS releases it if we crash, so it can never go stale.
+///
+/// Each call opens the file anew, so within one process, holding an exclusive
+/// lock and asking for another lock on the same file waits for itself.
+pub fn lock_file(path: &Path, mode: LockMode, timeout: Duration) -> Result<Box<Lock>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .chain_err(|| format!("couldn't open {}", path.display()))?;
+    let start = Instant::now();
+    loop {
+        // spelled out, since newer std has inherent methods of the same names
+        // that report contention differently
+        let locked = match mode {
+            LockMode::Shared => FileExt::try_lock_shared(&file),
+            LockMode::Exclusive => FileExt::try_lock_exclusive(&file),
+        };
+        match locked {
+            // released when the file is closed
+            Ok(()) => return Ok(Box::new(file)),
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                if start.elapsed() >= timeout {
+                    let what = format!("{} lock on {}", mode, path.display());
+                    bail!(ErrorKind::LockTimeout(what, millis(timeout)));
+                }
+                thread::sleep(Duration::from_millis(POLL_MS));
+            }
+            Err(e) => return Err(e).chain_err(|| format!("couldn't lock {}", path.display())),
+        }
+    }
+}
+
+/// Like `lock_file`, for stores that only ever live in one process.
+#[derive(Clone, Default)]
+pub struct LocalLock {
+    state: Arc<(Mutex<Holders>, Condvar)>,
+}
+
+#[derive(Default)]
+struct Holders {
+    shared: usize,
+    exclusive: bool,
+}
+
+impl LocalLock {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// `what` names the lock in the error, should it time out.
+    pub fn acquire(&self, what: &str, mode: LockMode, timeout: Duration) -> Result<Box<Lock>> {
+        let &(ref holders, ref released) = &*self.state;
+        let deadline = Instant::now() + timeout;
+        let mut holders = holders.lock().expect("lock holders poisoned");
+        loop {
+            let free = match mode {
+                LockMode::Shared => !holders.exclusive,
+                LockMode::Exclusive => !holders.exclusive && holders.shared == 0,
+            };
+            if free {
+                break;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                bail!(ErrorKind::LockTimeout(format!("{} lock on {}", mode, what), millis(timeout)));
+            }
+            holders = released.wait_timeout(holders, deadline - now).expect("lock holders poisoned").0;
+        }
+        match mode {
+            LockMode::Shared => holders.shared += 1,
+            LockMode::Exclusive => holders.exclusive = true,
+        }
+        Ok(Box::new(LocalGuard { lock: self.clone(), mode }))
+    }
+}
+
+struct LocalGuard {
+    lock: LocalLock,
+    mode: LockMode,
+}
+
+impl Drop for LocalGuard {
+    fn drop(&mut self) {
+        let &(ref holders, ref released) = &*self.lock.state;
+        // still release it while unwinding
+        let mut holders = match holders.lock() {
+            Ok(holders) => holders,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match self.mode {
+            LockMode::Shared => holders.shared -= 1,
+            LockMode::Exclusive => holders.exclusive = false,
+        }
+        released.notify_all();
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::time::Duration;
+
+    use self::tempdir::TempDir;
+
+    use errors::{Error, ErrorKind};
+    use super::{LocalLock, LockMode, lock_file};
+
+    fn timed_out<T>(r: ::errors::Result<T>) -> bool {
+        match r {
+            Err(Error(ErrorKind::LockTimeout(..), _)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn file_locks() {
+        let dir = TempDir::new("dag_lock").unwrap();
+        let path = dir.path().join("lock");
+        let brief = Duration::from_millis(30);
+
+        let a = lock_file(&path, LockMode::Shared, brief).unwrap();
+        let b = lock_file(&path, LockMode::Shared, brief).unwrap();
+        assert!(timed_out(lock_file(&path, LockMode::Exclusive, brief)));
+        drop((a, b));
+
+        let held = lock_file(&path, LockMode::Exclusive, brief).unwrap();
+        assert!(timed_out(lock_file(&path, LockMode::Shared, brief)));
+        drop(held);
+        lock_file(&path, LockMode::Shared, brief).unwrap();
+    }
+
+    #[test]
+    fn local_locks() {
+        let lock = LocalLock::new();
+        let brief = Duration::from_millis(30);
+
+        let a = lock.acquire("test", LockMode::Shared, brief).unwrap();
+        let b = lock.acquire("test", LockMode::Shared, brief).unwrap();
+        assert!(timed_out(lock.acquire("test", LockMode::Exclusive, brief)));
+        drop((a, b));
+
+        let held = lock.acquire("test", LockMode::Exclusive, brief).unwrap();
+        assert!(timed_out(lock.acquire("test", LockMode::Shared, brief)));
+        drop(held);
+        lock.acquire("test", LockMode::Exclusive, brief).unwrap();
+    }
+}
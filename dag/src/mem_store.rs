@@ -0,0 +1,225 @@
+//! Keeps a whole dag in memory: for tests, and for caches that can be
+//! rebuilt from somewhere slower.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+
+use digest::Digest;
+use errors::*;
+use fsck::Problem;
+use history::RootChange;
+use lock::{LocalLock, LockMode};
+use store::{Lock, ObjectMeta, Staged, Store, is_young};
+
+#[derive(Default)]
+pub struct MemStore {
+    state: Mutex<State>,
+    lock: LocalLock,
+    /// Held while any root moves.
+    roots_lock: LocalLock,
+}
+
+#[derive(Default)]
+struct State {
+    objects: HashMap<Digest, (Bytes, SystemTime)>,
+    roots: BTreeMap<String, Digest>,
+    history: HashMap<String, Vec<RootChange>>,
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Total size of the stored objects.
+    pub fn size(&self) -> u64 {
+        self.state().objects.values().map(|&(ref bytes, _)| bytes.len() as u64).sum()
+    }
+
+    /// Forgets everything: objects, roots and their history.
+    pub fn clear(&self) {
+        *self.state() = State::default();
+    }
+
+    /// An object's bytes, without copying them.
+    pub fn get(&self, digest: &Digest) -> Option<Bytes> {
+        self.state().objects.get(digest).map(|&(ref bytes, _)| bytes.clone())
+    }
+
+    fn state(&self) -> MutexGuard<State> {
+        // nothing panics while holding it
+        self.state.lock().expect("mem store lock poisoned")
+    }
+
+    fn insert(&self, digest: &Digest, bytes: Bytes) {
+        self.state().objects.insert(digest.clone(), (bytes, SystemTime::now()));
+    }
+}
+
+impl Store for MemStore {
+    fn open(&self, digest: &Digest) -> Result<Option<Box<Read + Send>>> {
+        Ok(self.get(digest).map(|bytes| Box::new(Cursor::new(bytes)) as Box<Read + Send>))
+    }
+
+    fn stat(&self, digest: &Digest) -> Result<Option<ObjectMeta>> {
+        let meta = self.state().objects.get(digest).map(|&(ref bytes, modified)| {
+            ObjectMeta { len: bytes.len() as u64, modified: Some(modified) }
+        });
+        Ok(meta)
+    }
+
+    fn put(&self, digest: &Digest, bytes: &[u8]) -> Result<()> {
+        self.insert(digest, Bytes::from(bytes));
+        Ok(())
+    }
+
+    fn stage<'a>(&'a self) -> Result<Box<Staged + 'a>> {
+        Ok(Box::new(MemStaged { store: self, buf: Cursor::new(Vec::new()) }))
+    }
+
+    fn touch(&self, digest: &Digest) -> Result<bool> {
+        match self.state().objects.get_mut(digest) {
+            Some(&mut (_, ref mut modified)) => {
+                *modified = SystemTime::now();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn sweep(&self, digest: &Digest, now: SystemTime, grace: Duration) -> Result<bool> {
+        let mut state = self.state();
+        let young = match state.objects.get(digest) {
+            Some(&(_, modified)) => is_young(Some(modified), now, grace),
+            None => return Ok(true),
+        };
+        if !young {
+            state.objects.remove(digest);
+        }
+        Ok(!young)
+    }
+
+    fn list(&self) -> Result<Vec<Digest>> {
+        Ok(self.state().objects.keys().cloned().collect())
+    }
+
+    fn read_root(&self, id: &str) -> Result<Option<Digest>> {
+        Ok(self.state().roots.get(id).cloned())
+    }
+
+    fn write_root(&self, id: &str, digest: &Digest) -> Result<()> {
+        self.state().roots.insert(id.to_owned(), digest.clone());
+        Ok(())
+    }
+
+    fn root_ids(&self) -> Result<Vec<String>> {
+        Ok(self.state().roots.keys().cloned().collect())
+    }
+
+    fn lock(&self, mode: LockMode, timeout: Duration) -> Result<Box<Lock>> {
+        self.lock.acquire("the store", mode, timeout)
+    }
+
+    fn lock_root(&self, id: &str, timeout: Duration) -> Result<Box<Lock>> {
+        self.roots_lock.acquire(&format!("root {:?}", id), LockMode::Exclusive, timeout)
+    }
+
+    fn append_history(&self, id: &str, change: &RootChange) -> Result<()> {
+        self.state().history.entry(id.to_owned()).or_insert_with(Vec::new).push(change.clone());
+        Ok(())
+    }
+
+    fn history(&self, id: &str) -> Result<Vec<RootChange>> {
+        Ok(self.state().history.get(id).cloned().unwrap_or_default())
+    }
+
+    fn quarantine(&self, problem: &Problem) -> Result<Option<PathBuf>> {
+        // nowhere to put it aside, and nothing worth keeping
+        if let Problem::Corrupt { ref digest, .. } = *problem {
+            self.state().objects.remove(digest);
+        }
+        Ok(None)
+    }
+}
+
+struct MemStaged<'a> {
+    store: &'a MemStore,
+    buf: Cursor<Vec<u8>>,
+}
+
+impl<'a> Staged for MemStaged<'a> {
+    fn commit(self: Box<Self>, digest: &Digest) -> Result<()> {
+        self.store.insert(digest, Bytes::from(self.buf.into_inner()));
+        Ok(())
+    }
+}
+
+impl<'a> Read for MemStaged<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.buf.read(buf)
+    }
+}
+
+impl<'a> Write for MemStaged<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Seek for MemStaged<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.buf.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use {Dag, Digest, GcOptions, Node};
+    use store::Store;
+    use super::MemStore;
+
+    #[test]
+    fn whole_dag() {
+        let store = Arc::new(MemStore::new());
+        let dag = Dag::with_store(store.clone());
+        let blob = dag.save(b"in memory").unwrap();
+        let bytes: Vec<u8> = (0..600_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 11) as u8).collect();
+        let chunked = dag.save_chunked(&bytes).unwrap();
+        let node = dag.save_node(&Node::new().link("blob", blob.clone()).link("big", chunked.clone())).unwrap();
+        let garbage = dag.save(b"garbage").unwrap();
+        dag.set_root("current", &node).unwrap();
+
+        assert_eq!(&dag.load(&chunked).unwrap()[..], &bytes[..]);
+        assert_eq!(&store.get(&blob).unwrap()[..], b"in memory");
+        assert_eq!(dag.resolve_prefix(&blob.short_hex()).unwrap(), blob);
+        assert!(dag.fsck(false).unwrap().is_clean());
+
+        let report = dag.gc(&GcOptions { grace: Duration::from_secs(0), dry_run: false }).unwrap();
+        assert_eq!(report.removed, vec![garbage.clone()]);
+        assert!(!dag.contains(&garbage).unwrap());
+        assert!(dag.contains(&node).unwrap());
+
+        store.put(&blob, b"not what it was").unwrap();
+        let report = dag.fsck(true).unwrap();
+        assert_eq!(report.problems.len(), 2, "{:?}", report.problems);
+        assert!(report.quarantined.is_empty());
+        assert!(!dag.contains(&blob).unwrap());
+        assert!(!dag.contains_unchecked(&Digest::from_bytes(b"never")).unwrap());
+
+        store.clear();
+        assert_eq!(store.size(), 0);
+        assert!(dag.root("current").unwrap().is_none());
+    }
+}
@@ -0,0 +1,134 @@
+//! Objects that link to other objects, which is what makes this a DAG.
+//!
+//! A node is stored like any other object, but its bytes start with `MAGIC`
+//! and continue with a bincoded `Node`. Everything else is a plain blob.
+
+use bincode;
+use bytes::Bytes;
+
+use bincoded;
+use digest::Digest;
+use errors::*;
+
+/// Starts every object header (nodes', chunk manifests'); `Dag::save`
+/// refuses blobs that start with it.
+pub static RESERVED: &[u8] = b"\0dag\x01";
+
+/// Marks an object as a `Node`.
+pub static MAGIC: &[u8] = b"\0dag\x01node";
+
+/// A named reference to another object.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Link {
+    pub name: String,
+    pub digest: Digest,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Node {
+    /// In order; names needn't be unique.
+    pub links: Vec<Link>,
+    /// Whatever the node's owner wants to keep alongside its links.
+    pub data: Vec<u8>,
+}
+
+impl Node {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn link<S: Into<String>>(mut self, name: S, digest: Digest) -> Self {
+        self.links.push(Link { name: name.into(), digest });
+        self
+    }
+
+    pub fn with_data(mut self, data: Vec<u8>) -> Self {
+        self.data = data;
+        self
+    }
+
+    /// The first link called `name`.
+    pub fn get(&self, name: &str) -> Option<&Digest> {
+        self.links.iter().find(|l| l.name == name).map(|l| &l.digest)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bincode::serialize_into(&mut bytes, self, bincode::Infinite).expect("encode node");
+        bytes
+    }
+
+    /// `None` if `bytes` aren't a node at all.
+    pub fn decode(bytes: &[u8]) -> Result<Option<Node>> {
+        if !is_node(bytes) {
+            return Ok(None);
+        }
+        let node = bincoded::deserialize_exact(&bytes[MAGIC.len()..]).chain_err(|| "malformed node")?;
+        Ok(Some(node))
+    }
+}
+
+/// Whether these bytes (or just the start of them) belong to a node.
+pub fn is_node(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Whether these bytes start with some object header, and so can't be a blob.
+pub fn is_reserved(bytes: &[u8]) -> bool {
+    bytes.starts_with(RESERVED)
+}
+
+#[derive(Clone, Debug)]
+pub enum Object {
+    Blob(Bytes),
+    Node(Node),
+}
+
+impl Object {
+    pub fn parse(bytes: Bytes) -> Result<Self> {
+        Ok(match Node::decode(&bytes)? {
+            Some(node) => Object::Node(node),
+            None => Object::Blob(bytes),
+        })
+    }
+
+    /// Blobs have no links.
+    pub fn links(&self) -> &[Link] {
+        match *self {
+            Object::Blob(_) => &[],
+            Object::Node(ref node) => &node.links,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use digest::Digest;
+    use super::{MAGIC, Node, Object};
+
+    #[test]
+    fn roundtrip() {
+        let node = Node::new()
+            .link("driver", Digest::sample())
+            .link("g", Digest::zero())
+            .with_data(vec![7, 8]);
+        let bytes = node.encode();
+        assert!(bytes.starts_with(MAGIC));
+        assert_eq!(Node::decode(&bytes).unwrap(), Some(node.clone()));
+        assert_eq!(node.get("g"), Some(&Digest::zero()));
+        assert_eq!(node.get("client"), None);
+
+        match Object::parse(Bytes::from(&b"just bytes"[..])).unwrap() {
+            Object::Blob(ref b) if &b[..] == b"just bytes" => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = Node::new().link("x", Digest::sample()).encode();
+        assert!(Node::decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Streams objects out of the store, checking their digests on the way.
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use digest::{Digest, Hasher};
+use store::{self, Lock, Store};
+
+/// Reads an object's contents. When checked, reaching the end of a corrupt
+/// object fails with `io::ErrorKind::InvalidData`, carrying a `CorruptObject`.
+pub struct Reader {
+    store: Arc<Store>,
+    /// Keeps `gc` away until we're done.
+    _lock: Option<Box<Lock>>,
+    piece: Option<Box<Read + Send>>,
+    /// Objects still to be read, for objects stored in chunks.
+    pieces: VecDeque<Digest>,
+    /// `None` when unchecked, and once the end is reached.
+    hasher: Option<Hasher>,
+    digest: Digest,
+    corrupt: bool,
+}
+
+impl Reader {
+    /// Reads `pieces` one after the other.
+    pub(crate) fn new(
+        store: Arc<Store>,
+        lock: Option<Box<Lock>>,
+        pieces: Vec<Digest>,
+        digest: Digest,
+        check: bool,
+    ) -> Self {
+        let hasher = if check { Some(digest.algorithm().hasher()) } else { None };
+        Reader {
+            store,
+            _lock: lock,
+            piece: None,
+            pieces: pieces.into(),
+            hasher,
+            digest,
+            corrupt: false,
+        }
+    }
+
+    /// The digest this object is stored under.
+    pub fn digest(&self) -> &Digest {
+        &self.digest
+    }
+
+    fn open_piece(&self, digest: &Digest) -> io::Result<Box<Read + Send>> {
+        match self.store.open(digest).map_err(store::io_error)? {
+            Some(piece) => Ok(piece),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("object {} is missing", digest))),
+        }
+    }
+
+    fn corrupt(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, CorruptObject(self.digest.clone()))
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.corrupt {
+            return Err(self.corrupt());
+        }
+        let mut n;
+        loop {
+            n = match self.piece {
+                Some(ref mut piece) => piece.read(buf)?,
+                None => 0,
+            };
+            if n == 0 && !buf.is_empty() {
+                // on to the next chunk
+                if let Some(digest) = self.pieces.pop_front() {
+                    self.piece = Some(self.open_piece(&digest)?);
+                    continue;
+                }
+            }
+            break;
+        }
+        if n > 0 {
+            if let Some(ref mut hasher) = self.hasher {
+                hasher.input(&buf[..n]);
+            }
+        } else if !buf.is_empty() {
+            if let Some(hasher) = self.hasher.take() {
+                if hasher.finish() != self.digest {
+                    self.corrupt = true;
+                    return Err(self.corrupt());
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// What a checked `Reader` fails with when its object doesn't match its digest.
+#[derive(Debug)]
+pub struct CorruptObject(pub Digest);
+
+impl CorruptObject {
+    /// Whether `e` came from reading a corrupt object.
+    pub fn is(e: &io::Error) -> bool {
+        e.get_ref().map_or(false, |inner| inner.is::<CorruptObject>())
+    }
+}
+
+impl fmt::Display for CorruptObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "object {} is corrupt", self.0)
+    }
+}
+
+impl error::Error for CorruptObject {
+    fn description(&self) -> &str {
+        "corrupt object"
+    }
+}
@@ -0,0 +1,280 @@
+//! Where a `Dag` keeps its objects, roots, and root histories.
+//!
+//! `FsStore` is the usual on-disk layout, `MemStore` keeps everything in
+//! memory, and `Overlay` layers a writable store over one it never writes to.
+
+use std::collections::{BTreeSet, HashSet};
+use std::io::{self, Read, Seek, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use digest::Digest;
+use errors::*;
+use fsck::Problem;
+use history::RootChange;
+use lock::LockMode;
+
+/// Storage for a `Dag`. Objects are opaque bytes stored under their digest;
+/// checking them against it is up to the `Dag`.
+pub trait Store: Send + Sync {
+    /// `None` if the object isn't stored.
+    fn open(&self, digest: &Digest) -> Result<Option<Box<Read + Send>>>;
+
+    /// `None` if the object isn't stored.
+    fn stat(&self, digest: &Digest) -> Result<Option<ObjectMeta>>;
+
+    /// Stores `bytes` as `digest`, atomically replacing any old copy.
+    fn put(&self, digest: &Digest, bytes: &[u8]) -> Result<()>;
+
+    /// Somewhere to write an object whose digest isn't known yet.
+    /// Dropping it uncommitted throws it away.
+    fn stage<'a>(&'a self) -> Result<Box<Staged + 'a>>;
+
+    /// Marks the object as freshly written, so `sweep` leaves it be.
+    /// `false` if it's gone.
+    fn touch(&self, digest: &Digest) -> Result<bool>;
+
+    /// Removes the object, unless it was written or touched within `grace`
+    /// of `now`. Returns whether it's gone.
+    fn sweep(&self, digest: &Digest, now: SystemTime, grace: Duration) -> Result<bool>;
+
+    /// Every stored object, in no particular order.
+    fn list(&self) -> Result<Vec<Digest>>;
+
+    fn read_root(&self, id: &str) -> Result<Option<Digest>>;
+
+    /// The caller must hold the root's lock.
+    fn write_root(&self, id: &str, digest: &Digest) -> Result<()>;
+
+    /// Every root's name, sorted.
+    fn root_ids(&self) -> Result<Vec<String>>;
+
+    /// Locks the whole store: shared by anything that reads or adds to it,
+    /// exclusive for sweeping it. Fails with `LockTimeout` after `timeout`.
+    fn lock(&self, mode: LockMode, timeout: Duration) -> Result<Box<Lock>>;
+
+    /// Serializes moves of one root. Dropping the lock releases it.
+    /// Fails with `LockTimeout` after `timeout`.
+    fn lock_root(&self, id: &str, timeout: Duration) -> Result<Box<Lock>>;
+
+    /// The caller must hold the root's lock.
+    fn append_history(&self, id: &str, change: &RootChange) -> Result<()>;
+
+    /// Oldest first.
+    fn history(&self, id: &str) -> Result<Vec<RootChange>>;
+
+    /// Removes abandoned writes older than `grace`, returning how many there
+    /// were and their total size.
+    fn sweep_temps(&self, now: SystemTime, grace: Duration, dry_run: bool) -> Result<(usize, u64)> {
+        let _ = (now, grace, dry_run);
+        Ok((0, 0))
+    }
+
+    /// Damage only the store itself can see, like stray files, for `fsck`.
+    fn problems(&self) -> Result<Vec<Problem>> {
+        Ok(Vec::new())
+    }
+
+    /// Moves a corrupt object or stray out of the way, returning where it went,
+    /// if anywhere. Other problems are left alone.
+    fn quarantine(&self, problem: &Problem) -> Result<Option<PathBuf>>;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ObjectMeta {
+    pub len: u64,
+    /// `None` if unknowable.
+    pub modified: Option<SystemTime>,
+}
+
+impl ObjectMeta {
+    /// Whether this was written or touched within `grace` of `now`.
+    pub fn is_young(&self, now: SystemTime, grace: Duration) -> bool {
+        is_young(self.modified, now, grace)
+    }
+}
+
+pub fn is_young(modified: Option<SystemTime>, now: SystemTime, grace: Duration) -> bool {
+    match modified.map(|mtime| now.duration_since(mtime)) {
+        Some(Ok(age)) => age < grace,
+        // from the future, or unknowable
+        _ => true,
+    }
+}
+
+/// An object being written by `Store::stage`. It can be read back before
+/// it's committed.
+pub trait Staged: Read + Write + Seek {
+    /// Stores what was written as `digest`, replacing any old copy.
+    fn commit(self: Box<Self>, digest: &Digest) -> Result<()>;
+}
+
+/// Held while a store or root is locked; dropping it releases the lock.
+pub trait Lock: Send {}
+
+impl<T: Send> Lock for T {}
+
+/// Reads from `upper`, falling back to `lower`, but only ever writes to `upper`.
+/// Useful for trying things out on a copy of a dag that mustn't change,
+/// whether it's read-only on disk or just precious.
+///
+/// A root moved in `upper` hides the one in `lower`, and its history follows
+/// on from `lower`'s. Only `upper` is locked, since `lower` may well be
+/// read-only; whatever else uses `lower` mustn't sweep it.
+pub struct Overlay<U, L> {
+    upper: U,
+    lower: L,
+}
+
+impl<U: Store, L: Store> Overlay<U, L> {
+    pub fn new(upper: U, lower: L) -> Self {
+        Overlay { upper, lower }
+    }
+
+    pub fn upper(&self) -> &U {
+        &self.upper
+    }
+
+    pub fn lower(&self) -> &L {
+        &self.lower
+    }
+}
+
+impl<U: Store, L: Store> Store for Overlay<U, L> {
+    fn open(&self, digest: &Digest) -> Result<Option<Box<Read + Send>>> {
+        match self.upper.open(digest)? {
+            Some(reader) => Ok(Some(reader)),
+            None => self.lower.open(digest),
+        }
+    }
+
+    fn stat(&self, digest: &Digest) -> Result<Option<ObjectMeta>> {
+        match self.upper.stat(digest)? {
+            Some(meta) => Ok(Some(meta)),
+            None => self.lower.stat(digest),
+        }
+    }
+
+    fn put(&self, digest: &Digest, bytes: &[u8]) -> Result<()> {
+        self.upper.put(digest, bytes)
+    }
+
+    fn stage<'a>(&'a self) -> Result<Box<Staged + 'a>> {
+        self.upper.stage()
+    }
+
+    fn touch(&self, digest: &Digest) -> Result<bool> {
+        // nothing sweeps the lower layer, so it needn't be touched
+        Ok(self.upper.touch(digest)? || self.lower.stat(digest)?.is_some())
+    }
+
+    fn sweep(&self, digest: &Digest, now: SystemTime, grace: Duration) -> Result<bool> {
+        Ok(self.upper.sweep(digest, now, grace)? && self.lower.stat(digest)?.is_none())
+    }
+
+    fn list(&self) -> Result<Vec<Digest>> {
+        let mut digests = self.upper.list()?;
+        let upper: HashSet<_> = digests.iter().cloned().collect();
+        digests.extend(self.lower.list()?.into_iter().filter(|d| !upper.contains(d)));
+        Ok(digests)
+    }
+
+    fn read_root(&self, id: &str) -> Result<Option<Digest>> {
+        match self.upper.read_root(id)? {
+            Some(digest) => Ok(Some(digest)),
+            None => self.lower.read_root(id),
+        }
+    }
+
+    fn write_root(&self, id: &str, digest: &Digest) -> Result<()> {
+        self.upper.write_root(id, digest)
+    }
+
+    fn root_ids(&self) -> Result<Vec<String>> {
+        let mut ids: BTreeSet<String> = self.lower.root_ids()?.into_iter().collect();
+        ids.extend(self.upper.root_ids()?);
+        Ok(ids.into_iter().collect())
+    }
+
+    fn lock(&self, mode: LockMode, timeout: Duration) -> Result<Box<Lock>> {
+        self.upper.lock(mode, timeout)
+    }
+
+    fn lock_root(&self, id: &str, timeout: Duration) -> Result<Box<Lock>> {
+        self.upper.lock_root(id, timeout)
+    }
+
+    fn append_history(&self, id: &str, change: &RootChange) -> Result<()> {
+        self.upper.append_history(id, change)
+    }
+
+    fn history(&self, id: &str) -> Result<Vec<RootChange>> {
+        let mut changes = self.lower.history(id)?;
+        changes.extend(self.upper.history(id)?);
+        Ok(changes)
+    }
+
+    fn sweep_temps(&self, now: SystemTime, grace: Duration, dry_run: bool) -> Result<(usize, u64)> {
+        self.upper.sweep_temps(now, grace, dry_run)
+    }
+
+    fn problems(&self) -> Result<Vec<Problem>> {
+        let mut problems = self.upper.problems()?;
+        problems.extend(self.lower.problems()?);
+        Ok(problems)
+    }
+
+    fn quarantine(&self, problem: &Problem) -> Result<Option<PathBuf>> {
+        self.upper.quarantine(problem)
+    }
+}
+
+/// For readers that fail at the io level.
+pub(crate) fn io_error(e: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use {Dag, Digest, MemStore, Node};
+    use super::{Overlay, Store};
+
+    #[test]
+    fn overlay() {
+        let base = Arc::new(MemStore::new());
+        let kept = {
+            let dag = Dag::with_store(base.clone());
+            let kept = dag.save(b"shipped").unwrap();
+            dag.set_root("current", &kept).unwrap();
+            kept
+        };
+        let base = Arc::try_unwrap(base).ok().unwrap();
+
+        let overlay = Arc::new(Overlay::new(MemStore::new(), base));
+        let dag = Dag::with_store(overlay.clone());
+        let added = dag.save(b"trying this out").unwrap();
+        let node = dag.save_node(&Node::new().link("old", kept.clone()).link("new", added.clone())).unwrap();
+        assert_eq!(dag.set_root("current", &node).unwrap(), Some(kept.clone()));
+        assert_eq!(dag.root_history("current").unwrap().len(), 2);
+        assert_eq!(dag.reachable(&[node.clone()]).unwrap().len(), 3);
+        assert_eq!(dag.roots().unwrap(), vec![("current".to_owned(), node.clone())]);
+
+        let mut read = Vec::new();
+        overlay.open(&kept).unwrap().unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"shipped");
+        assert_eq!(overlay.list().unwrap().len(), 3);
+        assert!(overlay.stat(&Digest::from_bytes(b"nope")).unwrap().is_none());
+        // only the upper layer is swept
+        assert!(!overlay.sweep(&kept, SystemTime::now(), Duration::from_secs(0)).unwrap());
+
+        // the base never changed
+        let base = overlay.lower();
+        assert_eq!(base.read_root("current").unwrap(), Some(kept.clone()));
+        assert_eq!(base.history("current").unwrap().len(), 1);
+        assert_eq!(base.list().unwrap(), vec![kept]);
+    }
+}
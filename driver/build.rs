@@ -6,17 +6,32 @@ fn main() {
                     env::var_os("CARGO_FEATURE_STATIC_METAL").is_some();
 
     if !is_static {
-        let profile = env::var("PROFILE").expect("profile");
-        let mut deps = PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect("cargo manifest dir"));
-        deps.pop();
-        deps.push("g");
-        deps.push("target");
-        deps.push(profile);
-        deps.push("deps");
+        // the builder tells us exactly where it put g (profile, target triple and all)
+        let deps = match env::var_os("EXUDE_G_DEPS") {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                let profile = env::var("PROFILE").expect("profile");
+                let mut deps =
+                    PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect("cargo manifest dir"));
+                deps.pop();
+                deps.push("g");
+                deps.push("target");
+                deps.push(profile);
+                deps.push("deps");
+                deps
+            }
+        };
 
         println!("cargo:rustc-link-lib=g");
         println!("cargo:rustc-link-search={}", deps.display());
+
+        // when g changes, the builder changes this, which forces us to relink
+        if let Ok(g_digest) = env::var("EXUDE_G_DIGEST") {
+            println!("cargo:rustc-env=EXUDE_G_DIGEST={}", g_digest);
+        }
     }
 
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=EXUDE_G_DEPS");
+    println!("cargo:rerun-if-env-changed=EXUDE_G_DIGEST");
 }
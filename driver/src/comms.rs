@@ -4,16 +4,16 @@ use std::ptr;
 
 use errors::*;
 use driver_abi::DriverCallbacks;
-use proto::bincoded;
+use proto::{Schema, envelope};
 use proto::serde::{Deserialize, Serialize};
 
 pub trait Pipe {
-    fn send_on_chan<T: Serialize>(&self, Chan, &T) -> Result<()>;
+    fn send_on_chan<T: Serialize + Schema>(&self, Chan, &T) -> Result<()>;
     fn try_recv<T>(&self) -> Result<Option<T>>
     where
-        for<'de> T: Deserialize<'de>;
+        for<'de> T: Deserialize<'de> + Schema;
 
-    fn send<T: Serialize>(&self, msg: &T) -> Result<()> {
+    fn send<T: Serialize + Schema>(&self, msg: &T) -> Result<()> {
         self.send_on_chan(Chan::Server, msg)
     }
 }
@@ -41,14 +41,14 @@ impl Wrapper {
 }
 
 impl Pipe for Wrapper {
-    fn send_on_chan<T: Serialize>(&self, chan: Chan, msg: &T) -> Result<()> {
+    fn send_on_chan<T: Serialize + Schema>(&self, chan: Chan, msg: &T) -> Result<()> {
         let cbs = unsafe { &*self.0 };
 
-        let len = bincoded::serialized_size(msg)? as i32;
+        let len = envelope::encoded_size(msg)? as i32;
         let packet = (cbs.alloc_fn)(cbs.ctx, len);
         assert!(!packet.is_null());
         let mut packet_ref = unsafe { ::std::slice::from_raw_parts_mut(packet, len as usize) };
-        match bincoded::bincode::serialize_into(&mut packet_ref, msg, bincoded::bincode::Infinite) {
+        match envelope::encode_into(&mut packet_ref, msg) {
             Ok(()) => {
                 let send = match chan {
                     Chan::Server => cbs.send_fn,
@@ -67,14 +67,14 @@ impl Pipe for Wrapper {
 
     fn try_recv<T>(&self) -> Result<Option<T>>
     where
-        for<'de> T: Deserialize<'de>,
+        for<'de> T: Deserialize<'de> + Schema,
     {
         let cbs = unsafe { &*self.0 };
         let mut packet = ptr::null_mut();
         let len = (cbs.try_recv_fn)(cbs.ctx, &mut packet);
         if len > 0 {
             let slice = unsafe { ::std::slice::from_raw_parts(packet, len as usize) };
-            let result = bincoded::deserialize_exact(slice).chain_err(|| "couldn't decode message");
+            let result = envelope::decode(slice).chain_err(|| "couldn't decode message");
             (cbs.free_fn)(cbs.ctx, packet, len);
             result.map(Some)
         } else if len == 0 {
@@ -11,9 +11,10 @@ use std::io::{self, Read, Write};
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 
+use proto::digest::{self, Algorithm, HashReader};
 use sodiumoxide::crypto::{pwhash, secretbox, sign};
 
-pub use proto::{Bincoded, Digest, DriverInfo, Signature};
+pub use proto::{Digest, DriverInfo, Endpoints, Envelope, Signature, config, frame};
 pub use secret::Secret;
 
 pub mod errors {
@@ -26,6 +27,9 @@ pub use errors::*;
 const OPS: pwhash::OpsLimit = pwhash::OPSLIMIT_SENSITIVE;
 const MEM: pwhash::MemLimit = pwhash::MEMLIMIT_SENSITIVE;
 
+/// If set, `load_keys` reads the passphrase from here instead of the terminal.
+pub const PASSPHRASE_VAR: &str = "EXUDE_PASSPHRASE";
+
 pub type InsecureKeys = (sign::PublicKey, sign::SecretKey);
 
 pub fn keygen(dir: &Path, password: Secret) -> Result<()> {
@@ -78,24 +82,27 @@ pub fn keygen(dir: &Path, password: Secret) -> Result<()> {
     Ok(())
 }
 
+/// Loads just the public key, which is enough for `verify` and `restore`.
+pub fn load_public_key() -> Result<sign::PublicKey> {
+    let dir = cred_path()?;
+    let mut pub_bytes = [0; sign::PUBLICKEYBYTES];
+    let eof = File::open(dir.join("public"))
+        .and_then(
+            |mut f| {
+                f.read_exact(&mut pub_bytes)?;
+                Ok(f.read(&mut [0u8])? == 0)
+            }
+        )
+        .chain_err(|| "couldn't load public key")?;
+    ensure!(eof, "public key too long");
+    Ok(sign::PublicKey(pub_bytes))
+}
+
 pub fn load_keys() -> Result<InsecureKeys> {
     let dir = cred_path()?;
     println!("Keys will be read from: {}", dir.display());
 
-    let public_key;
-    {
-        let mut pub_bytes = [0; sign::PUBLICKEYBYTES];
-        let eof = File::open(dir.join("public"))
-            .and_then(
-                |mut f| {
-                    f.read_exact(&mut pub_bytes)?;
-                    Ok(f.read(&mut [0u8])? == 0)
-                }
-            )
-            .chain_err(|| "couldn't load public key")?;
-        ensure!(eof, "public key too long");
-        public_key = sign::PublicKey(pub_bytes);
-    }
+    let public_key = load_public_key()?;
 
     let nonce;
     let salt;
@@ -116,7 +123,10 @@ pub fn load_keys() -> Result<InsecureKeys> {
         salt = pwhash::Salt(salt_bytes);
     }
 
-    let password = Secret::from_user_input("Passphrase: ")?;
+    let password = match Secret::from_env(PASSPHRASE_VAR) {
+        Some(password) => password,
+        None => Secret::from_user_input("Passphrase: ")?,
+    };
 
     println!("Deriving encryption key...");
     let mut box_key = secretbox::Key([0; secretbox::KEYBYTES]);
@@ -154,48 +164,46 @@ fn verify_keys(pk: &sign::PublicKey, sk: &sign::SecretKey) -> Result<()> {
     Ok(())
 }
 
-pub fn sign(driver_path: &Path, keys: &InsecureKeys, out_dir: &Path) -> Result<DriverInfo> {
+/// Signs the driver, along with the digest of the g it was linked against.
+pub fn sign(
+    driver_path: &Path,
+    g_digest: &Digest,
+    keys: &InsecureKeys,
+    out_dir: &Path,
+) -> Result<DriverInfo> {
     assert!(sodiumoxide::init());
 
-    println!("Reading driver: {}", driver_path.display());
+    println!("Reading and hashing driver: {}", driver_path.display());
     let mut driver_bytes = Vec::new();
-    File::open(driver_path)
-        .and_then(|mut f| f.read_to_end(&mut driver_bytes))
-        .chain_err(|| "could not read driver binary")?;
+    let file = File::open(driver_path).chain_err(|| "could not open driver binary")?;
+    let mut reader = HashReader::new(file, Algorithm::default());
+    reader.read_to_end(&mut driver_bytes).chain_err(|| "could not read driver binary")?;
+    let (_, digest, _) = reader.finish();
     let len = driver_bytes.len();
 
-    println!("Hashing driver...");
-    let digest = Digest::from_bytes(&driver_bytes);
-
     println!("Signing driver hash...");
-    let sig = Signature(sign::sign_detached(&digest.0, &keys.1).0);
+    let msg = proto::handshake::signed_message(&digest, g_digest);
+    let sig = Signature(sign::sign_detached(&msg, &keys.1).0);
 
-    // write unsigned metadata
     // How do we prevent people from using old sigs to distribute old buggy drivers?
     // Expiry dates? Revocation?
-    let descriptor;
-    {
-        descriptor = DriverInfo { len: len, digest: digest, sig: sig };
-        let bincoded = Bincoded::new(&descriptor)
-            .chain_err(|| "driver metadata encoding issue")?;
+    let descriptor = DriverInfo { len: len, digest: digest, g_digest: g_digest.clone(), sig: sig };
+    let envelope = Envelope::new(&descriptor).chain_err(|| "driver metadata encoding issue")?;
 
-        let descriptor_path = out_dir.join("latest.meta");
-        bincoded.write_to_path(&descriptor_path)
-            .chain_err(|| "couldn't write metadata")?;
+    // keep every signed driver around for `restore`
+    let archive = out_dir.join("archive");
+    match fs::create_dir(&archive) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+        Err(e) => Err(e).chain_err(|| "couldn't create archive dir")?,
     }
+    let (bin_path, meta_path) = archive_paths(out_dir, &descriptor.digest);
+    write_file(&bin_path, &driver_bytes).chain_err(|| "couldn't archive driver")?;
+    envelope.write_to_path(&meta_path).chain_err(|| "couldn't archive metadata")?;
 
-    // temp: write a copy conveniently
-    {
-        let dest_path = out_dir.join("latest.bin");
-        File::create(dest_path)
-            .and_then(
-                |mut dest| {
-                    dest.write_all(&driver_bytes)?;
-                    dest.sync_all()
-                }
-            )
-            .chain_err(|| "couldn't copy driver")?;
-    }
+    // then publish it, metadata last, just as `restore` does
+    copy_atomically(&bin_path, &out_dir.join("latest.bin")).chain_err(|| "couldn't copy driver")?;
+    copy_atomically(&meta_path, &out_dir.join("latest.meta")).chain_err(|| "couldn't write metadata")?;
 
     println!("Wrote signature.");
 
@@ -204,32 +212,97 @@ pub fn sign(driver_path: &Path, keys: &InsecureKeys, out_dir: &Path) -> Result<D
 
 /// Verifies the output of `sign`.
 pub fn verify(pk: &sign::PublicKey, dir: &Path) -> Result<DriverInfo> {
-    assert!(sodiumoxide::init());
+    verify_pair(pk, &dir.join("latest.bin"), &dir.join("latest.meta"))
+}
 
-    let ref bin_path = dir.join("latest.bin");
-    let ref meta_path = dir.join("latest.meta");
+/// Makes a previously signed driver the latest one again.
+pub fn restore(pk: &sign::PublicKey, digest: &Digest, dir: &Path) -> Result<DriverInfo> {
+    let (bin_path, meta_path) = archive_paths(dir, digest);
+    ensure!(meta_path.exists(), "driver {} was never signed here", digest.short_hex());
+    let info = verify_pair(pk, &bin_path, &meta_path)?;
+    ensure!(&info.digest == digest, "archived driver {} is mislabeled", digest.short_hex());
+
+    // the metadata is what announces a driver, so it goes last
+    copy_atomically(&bin_path, &dir.join("latest.bin")).chain_err(|| "couldn't restore driver")?;
+    copy_atomically(&meta_path, &dir.join("latest.meta")).chain_err(|| "couldn't restore metadata")?;
+    println!("Restored driver {}.", digest.short_hex());
+    Ok(info)
+}
 
-    let (digest, len) = File::open(bin_path)
-        .and_then(Digest::from_read)
-        .chain_err(|| format!("could not hash driver ({})", bin_path.display()))?;
+/// The one archived driver whose digest starts with `prefix`, as printed by
+/// `Digest::short_hex`. The archive isn't a dag store, so this matches its
+/// signed drivers the way `Dag::resolve_prefix` matches objects.
+pub fn find_archived(dir: &Path, prefix: &str) -> Result<Digest> {
+    let archive = dir.join("archive");
+    let entries = match fs::read_dir(&archive) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => bail!("no drivers have been signed here"),
+        Err(e) => return Err(e).chain_err(|| "couldn't list archive"),
+    };
+    let mut signed = Vec::new();
+    for entry in entries {
+        let name = entry.chain_err(|| "couldn't list archive")?.file_name();
+        // drivers count once their metadata is archived
+        let hex = match name.to_str() {
+            Some(name) if name.ends_with(".meta") => name.trim_right_matches(".meta"),
+            _ => continue,
+        };
+        if let Ok(digest) = hex.parse() {
+            signed.push(digest);
+        }
+    }
+    digest::resolve_prefix(prefix, signed).map_err(|e| e.to_string().into())
+}
+
+/// Returns `(driver, metadata)` paths for an archived driver.
+fn archive_paths(dir: &Path, digest: &Digest) -> (PathBuf, PathBuf) {
+    let hex = digest.file_name();
+    let archive = dir.join("archive");
+    (archive.join(&hex), archive.join(hex + ".meta"))
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut f = File::create(path)?;
+    f.write_all(bytes)?;
+    f.sync_all()
+}
+
+/// Copies `src` over `dest` by way of a temp file beside it, so nobody
+/// reading `dest` ever sees half of it.
+fn copy_atomically(src: &Path, dest: &Path) -> io::Result<()> {
+    let mut tmp_name = dest.file_name().expect("file name").to_owned();
+    tmp_name.push(".tmp");
+    let tmp = dest.with_file_name(tmp_name);
+    fs::copy(src, &tmp)?;
+    File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, dest)
+}
+
+fn verify_pair(pk: &sign::PublicKey, bin_path: &Path, meta_path: &Path) -> Result<DriverInfo> {
+    assert!(sodiumoxide::init());
 
     let info: DriverInfo =
-        unsafe { Bincoded::from_path(meta_path) }
+        Envelope::from_path(meta_path)
             .chain_err(|| format!("couldn't open metadata ({})", meta_path.display()))?
             .deserialize()
             .chain_err(|| format!("couldn't read metadata ({})", meta_path.display()))?;
 
+    let (digest, len) = File::open(bin_path)
+        .and_then(|file| Digest::from_read_with(info.digest.algorithm(), file))
+        .chain_err(|| format!("could not hash driver ({})", bin_path.display()))?;
+
     ensure!(info.len == len, "incorrect driver length");
     ensure!(info.digest == digest, "mismatched driver digest");
 
     let sig = sign::Signature(info.sig.0);
-    let verified = sign::verify_detached(&sig, &info.digest.0, pk);
+    let verified = info.verify_with(|msg| sign::verify_detached(&sig, msg, pk));
     ensure!(verified, "invalid driver signature");
 
     Ok(info)
 }
 
 pub mod secret {
+    use std::env;
     use std::fmt;
     use std::io::{self, Write};
     use sodiumoxide::utils::{memcmp, memzero};
@@ -249,6 +322,12 @@ pub mod secret {
             Ok(pass)
         }
 
+        /// Reads a secret from the environment, if present.
+        pub fn from_env(var: &str) -> Option<Self> {
+            use std::os::unix::ffi::OsStringExt;
+            env::var_os(var).map(|s| Secret(s.into_vec().into_boxed_slice()))
+        }
+
         pub fn expose<F: FnOnce(&[u8]) -> T, T>(&self, f: F) -> T {
             f(&*self.0)
         }
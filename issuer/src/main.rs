@@ -3,12 +3,13 @@ extern crate error_chain;
 extern crate issuer;
 
 use std::env;
+use std::fs::File;
 use std::io::{self, Write};
 use std::path::PathBuf;
 use std::process;
 
 use issuer::errors::*;
-use issuer::Secret;
+use issuer::{Digest, Secret};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -33,7 +34,8 @@ fn main() {
 fn dispatch(args: &[String]) -> Result<()> {
     match &*args[0] {
         "keygen" => keygen(),
-        "sign" => sign(),
+        "sign" => sign(&args[1..]),
+        "restore" if args.len() == 2 => restore(&args[1]),
         cmd => {
             let _ = writeln!(io::stderr(), "Unknown command: {}", cmd);
             usage()
@@ -55,27 +57,61 @@ fn keygen() -> Result<()> {
     issuer::keygen(&dir, password)
 }
 
-fn sign() -> Result<()> {
+fn root_path() -> PathBuf {
     let mut root_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     root_path.pop();
-    let root_path = root_path;
+    root_path
+}
+
+fn sign(args: &[String]) -> Result<()> {
+    let root_path = root_path();
+
+    let mut profile = "debug";
+    let mut triple = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &**arg {
+            "--release" => profile = "release",
+            "--target" => triple = Some(args.next().ok_or("--target needs a triple")?),
+            _ => usage(),
+        }
+    }
 
-    let mut driver_path = root_path.clone();
-    driver_path.push("driver");
-    driver_path.push("target");
-    driver_path.push("debug"); // xxx
-    driver_path.push("libdriver.dylib"); // xxx
+    let artifact_dir = |krate: &str| {
+        let mut dir = root_path.join(krate);
+        dir.push("target");
+        if let Some(triple) = triple {
+            dir.push(triple);
+        }
+        dir.push(profile);
+        dir
+    };
+    let driver_path = artifact_dir("driver").join("libdriver.dylib"); // xxx
+    let g_path = artifact_dir("g").join("deps").join("libg.dylib"); // xxx
+
+    let (g_digest, _) = File::open(&g_path)
+        .and_then(Digest::from_read)
+        .chain_err(|| format!("couldn't hash g ({})", g_path.display()))?;
 
     let keys = issuer::load_keys()?;
 
-    issuer::sign(&driver_path, &keys, &root_path).map(|_info| ())
+    issuer::sign(&driver_path, &g_digest, &keys, &root_path).map(|_info| ())
+}
+
+/// Makes an archived driver the latest again; any unique prefix of its digest will do.
+fn restore(prefix: &str) -> Result<()> {
+    let root_path = root_path();
+    let digest = issuer::find_archived(&root_path, prefix)?;
+    let pk = issuer::load_public_key()?;
+    issuer::restore(&pk, &digest, &root_path).map(|_info| ())
 }
 
 fn usage() -> ! {
     println!(
         "Command patterns:
     keygen
-    sign
+    sign [--release] [--target TRIPLE]
+    restore DIGEST
 "
     );
     process::exit(1)
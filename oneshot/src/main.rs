@@ -12,6 +12,7 @@ extern crate tokio_io;
 
 use std::io::{self, Write};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::process;
 use std::sync::mpsc::{self, TryRecvError};
 use std::thread;
@@ -29,8 +30,8 @@ use client::net;
 use client::common::{self, OurFuture};
 use client::render_loop::{self, Engine};
 use driver::{DriverState, RenderImpl};
-use proto::{Bincoded, Bytes, Digest, DriverInfo};
-use proto::bincoded;
+use proto::{Bytes, Digest, DriverInfo, Endpoints, Envelope, Schema};
+use proto::envelope;
 use proto::handshake::{Hello, Welcome};
 use proto::serde::{Deserialize, Serialize};
 
@@ -48,9 +49,12 @@ const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
 const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
 
 fn main() {
-    let addr: SocketAddr = ([127, 0, 0, 1], 2001).into();
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+    let endpoints = Endpoints::from_command_line(&root, "oneshot")
+        .and_then(|endpoints| Ok((endpoints.connect_addr()?, endpoints.max_message)))
+        .chain_err(|| "couldn't configure endpoints");
 
-    if let Err(e) = oneshot(addr) {
+    if let Err(e) = endpoints.and_then(|(addr, max)| oneshot(addr, max)) {
         let stderr = io::stderr();
         let oops = "couldn't write to stderr";
         let mut log = stderr.lock();
@@ -66,7 +70,7 @@ fn main() {
     }
 }
 
-fn oneshot(server_addr: SocketAddr) -> Result<()> {
+fn oneshot(server_addr: SocketAddr, max_message: usize) -> Result<()> {
 
     let io_comms;
     let net_comms;
@@ -90,17 +94,17 @@ fn oneshot(server_addr: SocketAddr) -> Result<()> {
                     )
                 })
                 .and_then(
-                |sock| -> OurFuture<_> {
+                move |sock| -> OurFuture<_> {
                     let (reader, writer) = sock.split();
 
                     let greeting = {
                         let cached_driver = Digest::zero(); // TEMP
                         let hello = Hello::Oneshot(cached_driver);
-                        common::write_bincoded(writer, &hello)
+                        common::write_envelope(writer, &hello)
                             .and_then(|(w, _)| Ok(w))
                     };
 
-                    let welcome = common::read_bincoded::<_, Welcome<Box<DriverInfo>>>(reader)
+                    let welcome = common::read_envelope::<_, Welcome<Box<DriverInfo>>>(reader, max_message)
                         .and_then(
                         |(reader, welcome)| {
                             match welcome {
@@ -193,9 +197,9 @@ struct StaticComms {
 }
 
 impl driver::comms::Pipe for StaticComms {
-    fn send<T: Serialize>(&self, msg: &T) -> driver::Result<()> {
+    fn send<T: Serialize + Schema>(&self, msg: &T) -> driver::Result<()> {
         // so many copies... ugh!
-        let bin = Bincoded::new(msg)?;
+        let bin = Envelope::new(msg)?;
         let bytes: Bytes = bin.into();
         assert!(bytes.len() <= ::std::i32::MAX as usize);
         let res = self.tx.send(bytes);
@@ -204,11 +208,11 @@ impl driver::comms::Pipe for StaticComms {
 
     fn try_recv<T>(&self) -> driver::Result<Option<T>>
     where
-        for<'de> T: Deserialize<'de>,
+        for<'de> T: Deserialize<'de> + Schema,
     {
         match self.rx.try_recv() {
             Ok(boxed_slice) => {
-                let val = bincoded::deserialize_exact(boxed_slice)?;
+                let val = envelope::decode(boxed_slice)?;
                 Ok(Some(val))
             }
             Err(TryRecvError::Empty) => Ok(None),
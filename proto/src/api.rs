@@ -1,4 +1,4 @@
-use super::DriverInfo;
+use super::{DriverInfo, Schema};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum UpRequest {
@@ -12,3 +12,18 @@ pub enum DownResponse {
     Pong(u32),
     Goats(u32),
 }
+
+impl Schema for UpRequest {
+    fn tag() -> &'static str {
+        "api::UpRequest"
+    }
+}
+
+impl Schema for DownResponse {
+    fn tag() -> &'static str {
+        "api::DownResponse"
+    }
+    fn version() -> u16 {
+        2
+    }
+}
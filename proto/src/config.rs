@@ -0,0 +1,251 @@
+//! Where everything listens and connects, shared by the builder, server and clients.
+//!
+//! Settings come from `exude.toml` (or the file named by `$EXUDE_CONFIG`),
+//! then `EXUDE_*` environment variables, then each binary's flags; later wins.
+//!
+//! ```toml
+//! listen = "0.0.0.0:2001"
+//! connect = "exude.example.com:2001"
+//! download_base = "https://exude.example.com/drivers"
+//! max_message = 16777216
+//! ```
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use toml;
+
+use frame;
+
+error_chain! {
+    foreign_links {
+        Io(io::Error);
+        Toml(toml::de::Error);
+    }
+}
+
+/// Names a config file to use instead of `exude.toml`.
+pub const CONFIG_VAR: &str = "EXUDE_CONFIG";
+
+/// Every setting, as spelled in the config file.
+/// Flags use dashes (`--download-base`), env vars shout (`EXUDE_DOWNLOAD_BASE`).
+pub static KEYS: &[&str] = &["listen", "control", "http", "connect", "announce", "download_base", "max_message"];
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Endpoints {
+    /// Where the server accepts clients.
+    pub listen: SocketAddr,
+    /// Where the server accepts driver announcements.
+    pub control: SocketAddr,
+    /// Where the server serves driver downloads.
+    pub http: SocketAddr,
+    /// Where clients find the server, as `host:port`.
+    pub connect: String,
+    /// Where the builder announces new drivers, as `host:port`.
+    pub announce: String,
+    /// Public URL under which `http` is reachable; driver digests are appended.
+    pub download_base: String,
+    /// Longest message, in bytes, that a connection will read.
+    pub max_message: usize,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            listen: ([127, 0, 0, 1], 2001).into(),
+            control: ([127, 0, 0, 1], 2002).into(),
+            http: ([127, 0, 0, 1], 2003).into(),
+            connect: "127.0.0.1:2001".into(),
+            announce: "127.0.0.1:2002".into(),
+            download_base: "http://localhost:2003".into(),
+            max_message: frame::DEFAULT_MAX,
+        }
+    }
+}
+
+impl Endpoints {
+    /// Reads `dir/exude.toml` (if present) and applies the environment on top.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut endpoints = match env::var_os(CONFIG_VAR) {
+            Some(path) => Endpoints::from_file(&PathBuf::from(path))?,
+            None => {
+                let path = dir.join("exude.toml");
+                if path.exists() {
+                    Endpoints::from_file(&path)?
+                } else {
+                    Endpoints::default()
+                }
+            }
+        };
+        endpoints.apply_env()?;
+        Ok(endpoints)
+    }
+
+    /// For binaries without flags of their own: `load`s, then applies
+    /// our arguments. Prints usage and exits on `--help`.
+    pub fn from_command_line(dir: &Path, program: &str) -> Result<Self> {
+        let mut endpoints = Endpoints::load(dir)?;
+        let rest = endpoints.apply_args(env::args().skip(1))?;
+        if rest.iter().any(|arg| arg == "--help" || arg == "-h") {
+            println!("Usage: {} [OPTIONS]\n\nOptions:\n{}", program, FLAGS_HELP);
+            process::exit(0);
+        }
+        if let Some(arg) = rest.first() {
+            bail!("unexpected argument {:?} (see --help)", arg);
+        }
+        Ok(endpoints)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .chain_err(|| format!("couldn't read {}", path.display()))?;
+        Endpoints::from_toml(&text).chain_err(|| format!("couldn't parse {}", path.display()))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        for key in KEYS {
+            let var = format!("EXUDE_{}", key.to_uppercase());
+            if let Some(value) = env::var_os(&var) {
+                let value = value.into_string().map_err(|_| format!("${} isn't UTF-8", var))?;
+                self.set(key, &value).chain_err(|| format!("bad ${}", var))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Overrides one setting by its `KEYS` name.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let addr = || -> Result<SocketAddr> {
+            value.parse().map_err(|_| format!("{:?} is not an IP address and port", value).into())
+        };
+        match key {
+            "listen" => self.listen = addr()?,
+            "control" => self.control = addr()?,
+            "http" => self.http = addr()?,
+            "connect" => self.connect = value.to_owned(),
+            "announce" => self.announce = value.to_owned(),
+            "download_base" => self.download_base = value.to_owned(),
+            "max_message" => {
+                self.max_message = value.parse().map_err(|_| format!("{:?} is not a byte count", value))?
+            }
+            _ => bail!("unknown setting {:?}", key),
+        }
+        Ok(())
+    }
+
+    /// Applies `--key value` and `--key=value` flags, returning all other arguments.
+    pub fn apply_args<I: IntoIterator<Item = String>>(&mut self, args: I) -> Result<Vec<String>> {
+        let mut rest = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let key = match flag_key(&arg) {
+                Some(key) => key,
+                None => {
+                    rest.push(arg);
+                    continue;
+                }
+            };
+            let value = match arg.find('=') {
+                Some(i) => arg[i + 1..].to_owned(),
+                None => args.next().ok_or_else(|| format!("{} needs a value", arg))?,
+            };
+            self.set(&key, &value)?;
+        }
+        Ok(rest)
+    }
+
+    /// The address clients should connect to.
+    pub fn connect_addr(&self) -> Result<SocketAddr> {
+        resolve(&self.connect)
+    }
+
+    /// Where to download the driver with this (hex) digest.
+    pub fn driver_url<D: ::std::fmt::Display>(&self, digest: D) -> String {
+        format!("{}/{}", self.download_base.trim_right_matches('/'), digest)
+    }
+}
+
+/// The `KEYS` entry for a flag like `--download-base` or `--listen=...`.
+pub fn flag_key(arg: &str) -> Option<String> {
+    if !arg.starts_with("--") {
+        return None;
+    }
+    let name = arg[2..].split('=').next().unwrap_or("").replace('-', "_");
+    if KEYS.contains(&&*name) { Some(name) } else { None }
+}
+
+/// Looks up a `host:port`, taking the first address.
+pub fn resolve(addr: &str) -> Result<SocketAddr> {
+    let mut addrs = addr.to_socket_addrs().chain_err(|| format!("couldn't resolve {}", addr))?;
+    addrs.next().ok_or_else(|| format!("{} has no addresses", addr).into())
+}
+
+/// Usage text for the flags that `apply_args` understands.
+pub static FLAGS_HELP: &str = "    --listen ADDR         where the server accepts clients (default 127.0.0.1:2001)
+    --control ADDR        where the server accepts announcements (default 127.0.0.1:2002)
+    --http ADDR           where the server serves downloads (default 127.0.0.1:2003)
+    --connect HOST:PORT   where clients find the server
+    --announce HOST:PORT  where the builder announces drivers
+    --download-base URL   public URL of the download server (default http://localhost:2003)
+    --max-message BYTES   longest message a connection accepts (default 16 MiB)
+Each may also be set in exude.toml (or $EXUDE_CONFIG), or as e.g. $EXUDE_DOWNLOAD_BASE.";
+
+#[cfg(test)]
+mod tests {
+    use super::{Endpoints, flag_key};
+
+    #[test]
+    fn partial_file() {
+        let endpoints = Endpoints::from_toml(
+            r#"
+            listen = "0.0.0.0:3001"
+            download_base = "https://exude.example.com/drivers/"
+            "#,
+        ).unwrap();
+        assert_eq!(endpoints.listen, ([0, 0, 0, 0], 3001).into());
+        assert_eq!(endpoints.control, Endpoints::default().control);
+        assert_eq!(endpoints.driver_url("abc"), "https://exude.example.com/drivers/abc");
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(Endpoints::from_toml("lisen = \"0.0.0.0:3001\"").is_err());
+        assert!(Endpoints::from_toml("listen = \"localhost\"").is_err());
+    }
+
+    #[test]
+    fn args_override() {
+        let mut endpoints = Endpoints::default();
+        let args = vec!["--listen=0.0.0.0:4001", "-v", "--download-base", "http://h:4003", "x"];
+        let rest = endpoints.apply_args(args.into_iter().map(String::from)).unwrap();
+        assert_eq!(rest, vec!["-v", "x"]);
+        assert_eq!(endpoints.listen, ([0, 0, 0, 0], 4001).into());
+        assert_eq!(endpoints.download_base, "http://h:4003");
+
+        endpoints.apply_args(vec!["--max-message=1000000".to_owned()]).unwrap();
+        assert_eq!(endpoints.max_message, 1_000_000);
+        assert!(endpoints.apply_args(vec!["--max-message=lots".to_owned()]).is_err());
+
+        assert!(endpoints.apply_args(vec!["--control".to_owned()]).is_err());
+        assert!(endpoints.apply_args(vec!["--http=nope".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn flag_names() {
+        assert_eq!(flag_key("--download-base=x"), Some("download_base".to_owned()));
+        assert_eq!(flag_key("--announce"), Some("announce".to_owned()));
+        assert_eq!(flag_key("--json"), None);
+        assert_eq!(flag_key("listen"), None);
+    }
+}
@@ -2,15 +2,87 @@
 
 use std::borrow::Borrow;
 
-use super::Digest;
+use super::{Digest, Schema, Signature};
+use super::{bincoded, digest};
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct DriverInfo {
     pub len: usize,
     pub digest: super::Digest,
+    /// Digest of the g dylib this driver was linked against.
+    pub g_digest: super::Digest,
     pub sig: super::Signature,
 }
 
+/// Length of a `signed_message`.
+pub const SIGNED_LEN: usize = (1 + digest::LEN) * 2;
+
+impl DriverInfo {
+    /// The bytes covered by `sig`.
+    pub fn signed_message(&self) -> [u8; SIGNED_LEN] {
+        signed_message(&self.digest, &self.g_digest)
+    }
+
+    /// What version 1 signed: the bare digests, which could only be SHAKE128.
+    /// `None` for any other algorithm, so an old signature can't be relabeled.
+    pub fn legacy_signed_message(&self) -> Option<[u8; digest::LEN * 2]> {
+        let shake = digest::Algorithm::Shake128;
+        if self.digest.algorithm() != shake || self.g_digest.algorithm() != shake {
+            return None;
+        }
+        let mut msg = [0u8; digest::LEN * 2];
+        msg[..digest::LEN].copy_from_slice(self.digest.as_bytes());
+        msg[digest::LEN..].copy_from_slice(self.g_digest.as_bytes());
+        Some(msg)
+    }
+
+    /// Whether `verify` accepts `sig` over `signed_message`, or over the
+    /// `legacy_signed_message` of metadata migrated from version 1.
+    pub fn verify_with<F: Fn(&[u8]) -> bool>(&self, verify: F) -> bool {
+        verify(&self.signed_message()) ||
+            self.legacy_signed_message().map_or(false, |msg| verify(&msg))
+    }
+}
+
+/// The driver's digest followed by its g's, each led by its algorithm code,
+/// so neither can be swapped out or relabeled.
+pub fn signed_message(driver: &Digest, g: &Digest) -> [u8; SIGNED_LEN] {
+    let half = 1 + digest::LEN;
+    let mut msg = [0u8; SIGNED_LEN];
+    msg[0] = driver.algorithm().code();
+    msg[1..half].copy_from_slice(driver.as_bytes());
+    msg[half] = g.algorithm().code();
+    msg[half + 1..].copy_from_slice(g.as_bytes());
+    msg
+}
+
+/// Query parameter naming the driver a download may be patched against.
+pub static DELTA_BASE_PARAM: &str = "base";
+/// Response header marking a download as a `delta::Patch` against this (hex) base.
+pub static DELTA_BASE_HEADER: &str = "X-Exude-Delta-Base";
+
+/// `url`, asking for a patch against `base` instead of the whole driver.
+pub fn delta_url(url: &str, base: &Digest) -> String {
+    let sep = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}={}", url, sep, DELTA_BASE_PARAM, base)
+}
+
+/// The base a download's query string asks to be patched against, if any.
+pub fn delta_base(query: Option<&str>) -> Option<Digest> {
+    query.and_then(|query| {
+        query
+            .split('&')
+            .filter_map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(key), Some(hex)) if key == DELTA_BASE_PARAM => hex.parse().ok(),
+                    _ => None,
+                }
+            })
+            .next()
+    })
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Welcome<M: Borrow<DriverInfo> = Box<DriverInfo>> {
     Current,
@@ -30,3 +102,127 @@ pub enum Hello {
 pub enum UpControl {
     Download(String, Box<DriverInfo>),
 }
+
+impl Schema for DriverInfo {
+    fn tag() -> &'static str {
+        "handshake::DriverInfo"
+    }
+
+    /// Version 2 tagged the digests.
+    fn version() -> u16 {
+        2
+    }
+
+    fn migrate(version: u16, payload: &[u8]) -> bincoded::Result<Self> {
+        if version != 1 {
+            let msg = format!("no DriverInfo schema version {}", version);
+            return Err(Box::new(bincoded::ErrorKind::Custom(msg)));
+        }
+        let old: DriverInfoV1 = bincoded::deserialize_exact(payload)?;
+        Ok(DriverInfo { len: old.len, digest: old.digest, g_digest: old.g_digest, sig: old.sig })
+    }
+}
+
+/// `DriverInfo` as signed (and stored in `latest.meta`) before digests were tagged.
+#[derive(Deserialize)]
+struct DriverInfoV1 {
+    len: usize,
+    #[serde(with = "digest::untagged")]
+    digest: Digest,
+    #[serde(with = "digest::untagged")]
+    g_digest: Digest,
+    sig: Signature,
+}
+
+/// The same for any `M`, since they all encode alike.
+impl<M: Borrow<DriverInfo>> Schema for Welcome<M> {
+    fn tag() -> &'static str {
+        "handshake::Welcome"
+    }
+    fn version() -> u16 {
+        2
+    }
+}
+
+impl Schema for Hello {
+    fn tag() -> &'static str {
+        "handshake::Hello"
+    }
+    fn version() -> u16 {
+        2
+    }
+}
+
+impl Schema for UpControl {
+    fn tag() -> &'static str {
+        "handshake::UpControl"
+    }
+    fn version() -> u16 {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use digest::{Algorithm, Digest};
+    use bincode;
+    use envelope::{self, Envelope};
+    use sig::Signature;
+    use super::{DriverInfo, Hello, Welcome, delta_base, delta_url, signed_message};
+
+    fn sample_info() -> DriverInfo {
+        DriverInfo {
+            len: 3,
+            digest: Digest::from_bytes(b"drv"),
+            g_digest: Digest::from_bytes(b"g"),
+            sig: Signature::sample(),
+        }
+    }
+
+    #[test]
+    fn delta_query() {
+        let base = Digest::from_bytes(b"old driver");
+        let url = delta_url("http://localhost:2003/abc", &base);
+        assert_eq!(url, format!("http://localhost:2003/abc?base={}", base));
+        let query = url.splitn(2, '?').nth(1);
+        assert_eq!(delta_base(query), Some(base.clone()));
+        assert_eq!(delta_base(Some(&format!("x=1&base={}", base))), Some(base));
+        assert_eq!(delta_base(Some("base=nope")), None);
+        assert_eq!(delta_base(None), None);
+    }
+
+    #[test]
+    fn welcome_envelopes() {
+        let info = sample_info();
+        let sent: Welcome<&DriverInfo> = Welcome::Download("http://h/drv".into(), &info);
+        let bytes = Envelope::new(&sent).unwrap();
+        match envelope::decode::<_, Welcome>(&bytes).unwrap() {
+            Welcome::Download(url, got) => {
+                assert_eq!(url, "http://h/drv");
+                assert_eq!(*got, info);
+            }
+            _ => panic!("wrong welcome"),
+        }
+        assert!(envelope::decode::<_, Hello>(&bytes).is_err());
+    }
+
+    #[test]
+    fn signatures_cover_algorithms() {
+        let info = sample_info();
+        let relabeled = Digest::new(Algorithm::Sha3_256, *info.digest.as_bytes());
+        assert!(signed_message(&relabeled, &info.g_digest)[..] != info.signed_message()[..]);
+        assert!(info.legacy_signed_message().is_some());
+        let info = DriverInfo { digest: relabeled, ..info };
+        assert!(info.legacy_signed_message().is_none());
+        assert!(!info.verify_with(|msg| msg.len() == 64));
+    }
+
+    #[test]
+    fn untagged_metadata() {
+        let info = sample_info();
+        let mut old = b"\0env\x15handshake::DriverInfo\0\x01".to_vec();
+        let fields = (info.len, *info.digest.as_bytes(), *info.g_digest.as_bytes(), &info.sig);
+        old.extend(bincode::serialize(&fields, bincode::Infinite).unwrap());
+        assert_eq!(envelope::decode::<_, DriverInfo>(&old).unwrap(), info);
+    }
+}
@@ -1,17 +1,32 @@
+#![recursion_limit = "1024"]
+
 pub extern crate bytes;
 extern crate dag;
+#[macro_use]
+extern crate error_chain;
 pub extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate toml;
 
 pub mod api;
+pub mod config;
 pub mod handshake;
+pub mod release;
 pub mod sig;
 
 pub use dag::bincode;
 pub use dag::bincoded::{self, Bincoded};
+pub use dag::delta;
 pub use bytes::{Bytes, BytesMut};
 pub use dag::digest::{self, Digest};
+pub use dag::envelope::{self, Envelope, Schema};
+pub use dag::frame;
+pub use dag::mem_store::MemStore;
+pub use dag::node::{self, Link, Node};
+pub use dag::store::{self, Store};
+pub use self::config::Endpoints;
 pub use self::handshake::DriverInfo;
+pub use self::release::Release;
 pub use self::sig::Signature;
 
@@ -0,0 +1,88 @@
+//! A release as a graph in the dag: one node linking a driver blob, the `g`
+//! blob it was linked against, and any assets it ships with.
+
+use dag::{self, Node};
+
+use super::{Digest, DriverInfo};
+
+static DRIVER: &str = "driver";
+static G: &str = "g";
+/// Asset links are named `asset/<name>`.
+static ASSET_PREFIX: &str = "asset/";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Release {
+    pub driver: Digest,
+    pub g: Digest,
+    pub assets: Vec<(String, Digest)>,
+}
+
+impl Release {
+    pub fn new(info: &DriverInfo) -> Self {
+        Release {
+            driver: info.digest.clone(),
+            g: info.g_digest.clone(),
+            assets: Vec::new(),
+        }
+    }
+
+    pub fn to_node(&self) -> Node {
+        let mut node = Node::new().link(DRIVER, self.driver.clone()).link(G, self.g.clone());
+        for &(ref name, ref digest) in &self.assets {
+            node = node.link(format!("{}{}", ASSET_PREFIX, name), digest.clone());
+        }
+        node
+    }
+
+    pub fn from_node(node: &Node) -> dag::Result<Self> {
+        let mut driver = None;
+        let mut g = None;
+        let mut assets = Vec::new();
+        for link in &node.links {
+            if link.name == DRIVER && driver.is_none() {
+                driver = Some(link.digest.clone());
+            } else if link.name == G && g.is_none() {
+                g = Some(link.digest.clone());
+            } else if link.name.starts_with(ASSET_PREFIX) {
+                assets.push((link.name[ASSET_PREFIX.len()..].to_owned(), link.digest.clone()));
+            } else {
+                bail!("unexpected link {:?} in release", link.name);
+            }
+        }
+        Ok(
+            Release {
+                driver: driver.ok_or("release has no driver")?,
+                g: g.ok_or("release has no g")?,
+                assets,
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dag::Node;
+
+    use digest::Digest;
+    use super::Release;
+
+    #[test]
+    fn roundtrip() {
+        let release = Release {
+            driver: Digest::from_bytes(b"driver"),
+            g: Digest::from_bytes(b"g"),
+            assets: vec![("logo.png".into(), Digest::from_bytes(b"png"))],
+        };
+        let node = release.to_node();
+        assert_eq!(node.get("asset/logo.png"), Some(&release.assets[0].1));
+        assert_eq!(Release::from_node(&node).unwrap(), release);
+    }
+
+    #[test]
+    fn malformed() {
+        let g_only = Node::new().link("g", Digest::zero());
+        assert!(Release::from_node(&g_only).is_err());
+        let extra = Release::from_node(&g_only.link("driver", Digest::zero()).link("x", Digest::zero()));
+        assert!(extra.is_err());
+    }
+}
@@ -1,10 +1,15 @@
 //! Shared messaging code between client and server.
 
-use futures::future::{self, Future};
+use std::io::Write;
+
+use futures::future::{self, Future, Loop};
+use futures::stream::Stream;
 use tokio_io::{self, AsyncRead, AsyncWrite};
 
 use errors::*;
-use proto::{Bincoded, BytesMut};
+use proto::{BytesMut, Digest, Envelope, Schema};
+use proto::digest::{Algorithm, HashWriter};
+use proto::frame::{self, Header, LenDecoder};
 use proto::serde::{Deserialize, Serialize};
 
 
@@ -24,25 +29,38 @@ macro_rules! try_box {
 /// No `Send` needed.
 pub type OurFuture<T> = Box<Future<Item = T, Error = Error>>;
 
-/// Reads a 16-bit length header and then bytes asynchronously.
-pub fn read_with_length<R: AsyncRead + 'static>(reader: R) -> OurFuture<(R, BytesMut)> {
-    let buf = [0u8, 0];
-    box tokio_io::io::read_exact(reader, buf)
-            .and_then(
-        |(reader, len_buf)| {
-            let len = ((len_buf[0] as usize) << 8) | len_buf[1] as usize;
+/// Reads a varint length header, refusing frames longer than `max`.
+pub fn read_len<R: AsyncRead + 'static>(reader: R, max: usize) -> OurFuture<(R, usize)> {
+    let header = future::loop_fn((reader, LenDecoder::new()), |(reader, mut decoder)| {
+        tokio_io::io::read_exact(reader, [0u8]).and_then(
+            move |(reader, byte)| {
+                Ok(match decoder.push(byte[0])? {
+                    Some(len) => Loop::Break((reader, len)),
+                    None => Loop::Continue((reader, decoder)),
+                })
+            }
+        )
+    });
+    box header
+        .and_then(move |(reader, len)| frame::check_len(len, max).map(|len| (reader, len)))
+        .then(|res| res.chain_err(|| "couldn't read frame header"))
+}
+
+/// Reads a length header and then bytes asynchronously.
+pub fn read_with_length<R: AsyncRead + 'static>(reader: R, max: usize) -> OurFuture<(R, BytesMut)> {
+    box read_len(reader, max).and_then(
+        |(reader, len)| {
             let mut bytes = BytesMut::with_capacity(len);
             unsafe {
                 bytes.set_len(len);
             }
-            tokio_io::io::read_exact(reader, BytesMutAsMut(bytes))
-        }
-    )
-            .then(|res| {
+            tokio_io::io::read_exact(reader, BytesMutAsMut(bytes)).then(|res| {
                 res
                     .map(|(r, bmam)| (r, bmam.0))
                     .chain_err(|| "couldn't read length-delimited packet")
             })
+        }
+    )
 }
 
 // BytesMut does not impl AsMut<[u8]>; workaround
@@ -54,24 +72,24 @@ impl AsMut<[u8]> for BytesMutAsMut {
     }
 }
 
-/// Reads a 16-bit length header and then buffers and deserializes bytes.
-pub fn read_bincoded<R, T>(reader: R) -> OurFuture<(R, T)>
+/// Reads a length header, then buffers and deserializes an enveloped `T`.
+/// Any other type of message is an error.
+pub fn read_envelope<R, T>(reader: R, max: usize) -> OurFuture<(R, T)>
 where
     R: AsyncRead + 'static,
-    for<'de> T: Deserialize<'de> + 'static,
+    for<'de> T: Deserialize<'de> + Schema + 'static,
 {
-    box read_with_length(reader).and_then(
+    box read_with_length(reader, max).and_then(
         |(reader, bytes)| {
-            let bincoded = unsafe { Bincoded::<T>::from_bytes(bytes.freeze()) };
-            bincoded
-                .deserialize()
+            Envelope::<T>::from_bytes(bytes.freeze())
+                .and_then(|envelope| envelope.deserialize())
                 .map(|val| (reader, val))
                 .map_err(Into::into)
         }
     )
 }
 
-/// Write a 16-bit length header, and then `buf` asynchronously.
+/// Write a length header, and then `buf` asynchronously.
 ///
 /// The future returns `(write_half, buf)`.
 pub fn write_with_length<W, B>(writer: W, buf: B) -> OurFuture<(W, B)>
@@ -79,24 +97,38 @@ where
     W: AsyncWrite + 'static,
     B: AsRef<[u8]> + 'static,
 {
-    let len = buf.as_ref().len();
-    if len > 0xffff {
-        return box future::err(format!("written message too long: {}", len).into());
-    }
-    let len_buf = [(len >> 8) as u8, len as u8];
-    box tokio_io::io::write_all(writer, len_buf)
+    let header = Header::new(buf.as_ref().len() as u64);
+    box tokio_io::io::write_all(writer, header)
             .and_then(move |(writer, _)| tokio_io::io::write_all(writer, buf))
             .then(|res| res.chain_err(|| "couldn't write length-delimited packet"))
 }
 
-/// Write a 16-bit length header, and then the serialized bytes.
-pub fn write_bincoded<W, T>(writer: W, value: &T) -> OurFuture<(W, Bincoded<T>)>
+/// Write a length header, and then the enveloped value.
+pub fn write_envelope<W, T>(writer: W, value: &T) -> OurFuture<(W, Envelope<T>)>
 where
     W: AsyncWrite + 'static,
-    T: Serialize + 'static,
+    T: Serialize + Schema + 'static,
 {
-    match Bincoded::new(value) {
-        Ok(bincoded) => write_with_length(writer, bincoded),
+    match Envelope::new(value) {
+        Ok(envelope) => write_with_length(writer, envelope),
         Err(e) => box future::err(e.into()),
     }
 }
+
+/// Drains a stream of chunks into `writer`, hashing them on the way through.
+/// The async counterpart to `HashWriter`.
+///
+/// The future returns `(writer, digest, len)`.
+pub fn write_hashed<S, W>(chunks: S, writer: W, algo: Algorithm) -> OurFuture<(W, Digest, u64)>
+where
+    S: Stream<Error = Error> + 'static,
+    S::Item: AsRef<[u8]>,
+    W: Write + 'static,
+{
+    box chunks
+        .fold(HashWriter::new(writer, algo), |mut writer, chunk| -> Result<_> {
+            writer.write_all(chunk.as_ref()).chain_err(|| "couldn't write chunk")?;
+            Ok(writer)
+        })
+        .map(HashWriter::finish)
+}
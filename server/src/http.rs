@@ -1,15 +1,52 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
 
 use hyper::{self, Method, StatusCode};
 use hyper::header::ContentLength;
 use hyper::server::{Request, Response, Service};
 use futures::{Future, Stream, future};
+use futures_cpupool::CpuPool;
+use proto::{Bytes, Digest, MemStore, Store, delta, handshake};
 use tokio_core::net::TcpListener;
 use tokio_core::reactor::Handle;
 
-use super::{CurrentDriver, DriverInfo};
+use super::{CurrentDriver, HashedHeapFile};
 
-pub struct DriverService(pub CurrentDriver);
+/// Patches by `(base, target)`, from the moment we start computing them.
+pub type PatchCache = Rc<RefCell<HashMap<(Digest, Digest), PatchState>>>;
+
+pub enum PatchState {
+    /// Still on the pool; meanwhile, clients get the whole driver.
+    Computing,
+    Ready(Bytes),
+    /// We don't have the base, or the patch would hardly be smaller than the driver.
+    Useless,
+}
+
+/// Forget every finished patch past this many; they're cheap enough to recompute.
+const MAX_CACHED_PATCHES: usize = 32;
+
+/// Archived drivers already read (and checked), so patching from a popular
+/// base doesn't hit the disk every time.
+pub type DriverCache = Arc<MemStore>;
+
+/// Forget every cached driver once they add up to this many bytes.
+const MAX_CACHED_DRIVER_BYTES: u64 = 64 * 1024 * 1024;
+
+pub struct DriverService {
+    pub current: CurrentDriver,
+    pub patches: PatchCache,
+    pub drivers: DriverCache,
+    /// Where patches get computed, off the reactor.
+    pub pool: CpuPool,
+    pub handle: Handle,
+}
 
 impl Service for DriverService {
     type Request = Request;
@@ -23,9 +60,18 @@ impl Service for DriverService {
             println!("404: {} {}", req.method(), req.path());
             return not_found();
         }
-        match *self.0.borrow() {
+        match *self.current.borrow() {
             Some(ref file) => {
-                if &req.path().as_bytes()[1..] == &file.info.digest.hex_bytes()[..] {
+                if req.path()[1..].parse::<Digest>().ok().as_ref() == Some(&file.info.digest) {
+                    if let Some(base) = handshake::delta_base(req.query()) {
+                        if let Some(patch) = self.patch(&base, file) {
+                            let mut response = Response::new()
+                                .with_header(ContentLength(patch.len() as u64))
+                                .with_body(patch);
+                            response.headers_mut().set_raw(handshake::DELTA_BASE_HEADER, base.to_string());
+                            return future::ok(response);
+                        }
+                    }
                     let bytes = file.bytes.clone();
                     future::ok(
                         Response::new()
@@ -45,20 +91,120 @@ impl Service for DriverService {
     }
 }
 
-pub fn driver_url(info: &DriverInfo) -> String {
-    format!("http://localhost:2003/{}", info.digest)
+impl DriverService {
+    /// A patch from `base` to the current driver, if one's ready. Otherwise
+    /// starts computing it, unless that's under way or pointless already,
+    /// and leaves this client to download the whole driver.
+    fn patch(&self, base: &Digest, file: &HashedHeapFile) -> Option<Bytes> {
+        let key = (base.clone(), file.info.digest.clone());
+        if base == &key.1 {
+            return None;
+        }
+        match self.patches.borrow().get(&key) {
+            Some(&PatchState::Ready(ref patch)) => return Some(patch.clone()),
+            Some(_) => return None,
+            None => (),
+        }
+
+        {
+            let mut patches = self.patches.borrow_mut();
+            if patches.len() >= MAX_CACHED_PATCHES {
+                // keep the ones in flight, or the next request would start them again
+                patches.retain(|_, state| match *state {
+                    PatchState::Computing => true,
+                    _ => false,
+                });
+            }
+            patches.insert(key.clone(), PatchState::Computing);
+        }
+
+        let drivers = self.drivers.clone();
+        let (base, target, bytes) = (base.clone(), key.1.clone(), file.bytes.clone());
+        let computed = self.pool.spawn_fn(move || -> Result<_, ()> {
+            Ok(compute_patch(&drivers, &base, &target, &bytes))
+        });
+        let patches = self.patches.clone();
+        self.handle.spawn(computed.map(move |state| {
+            patches.borrow_mut().insert(key, state);
+        }));
+        None
+    }
+}
+
+/// Runs on the pool.
+fn compute_patch(drivers: &MemStore, base: &Digest, target: &Digest, bytes: &[u8]) -> PatchState {
+    let base_bytes = match archived_driver(drivers, base) {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return PatchState::Useless,
+        Err(e) => {
+            println!("delta from {}: {}", base.short_hex(), e);
+            return PatchState::Useless;
+        }
+    };
+    let patch = delta::Patch::new(&base_bytes, bytes);
+    let patch = Bytes::from(patch.encode());
+    if patch.len() >= bytes.len() / 10 * 9 {
+        return PatchState::Useless;
+    }
+    println!("delta {} -> {}: {} bytes", base.short_hex(), target.short_hex(), patch.len());
+    PatchState::Ready(patch)
+}
+
+/// An archived driver, from the cache if it's there.
+fn archived_driver(drivers: &MemStore, digest: &Digest) -> io::Result<Option<Bytes>> {
+    if let Some(bytes) = drivers.get(digest) {
+        return Ok(Some(bytes));
+    }
+    let bytes = match read_archived_driver(digest)? {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+    if drivers.size() + bytes.len() as u64 > MAX_CACHED_DRIVER_BYTES {
+        drivers.clear();
+    }
+    // a `MemStore` never fails to store
+    drivers.put(digest, &bytes).expect("caching driver");
+    Ok(Some(Bytes::from(bytes)))
+}
+
+/// A driver signed earlier, from the issuer's archive, if it's still intact.
+fn read_archived_driver(digest: &Digest) -> io::Result<Option<Vec<u8>>> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.pop();
+    path.push("archive");
+    path.push(digest.file_name());
+
+    let mut bytes = Vec::new();
+    match File::open(&path) {
+        Ok(mut file) => file.read_to_end(&mut bytes)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if !digest.matches(&bytes) {
+        let msg = format!("{} is corrupt", path.display());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    Ok(Some(bytes))
 }
 
-pub fn serve(handle: Handle, current_driver: CurrentDriver) {
-    let addr: SocketAddr = ([127, 0, 0, 1], 2003).into();
-    let listener = TcpListener::bind(&addr, &handle).expect("http");
+pub fn serve(handle: Handle, addr: &SocketAddr, current_driver: CurrentDriver) {
+    let listener = TcpListener::bind(addr, &handle).expect("http");
     let h = hyper::server::Http::new();
     let handle2 = handle.clone();
+    let patches = PatchCache::default();
+    let drivers = DriverCache::default();
+    let pool = CpuPool::new_num_cpus();
     let server = listener
         .incoming()
         .for_each(
             move |(sock, addr)| {
-                let service = DriverService(current_driver.clone());
+                let service = DriverService {
+                    current: current_driver.clone(),
+                    patches: patches.clone(),
+                    drivers: drivers.clone(),
+                    pool: pool.clone(),
+                    handle: handle.clone(),
+                };
                 h.bind_connection(&handle, sock, addr, service);
                 Ok(())
             }
@@ -5,6 +5,7 @@
 #[macro_use]
 extern crate error_chain;
 extern crate futures;
+extern crate futures_cpupool;
 extern crate hyper;
 extern crate proto;
 extern crate tokio_core;
@@ -17,7 +18,7 @@ mod http;
 
 use std::cell::RefCell;
 use std::collections::BTreeMap;
-use std::fs::{self, File};
+use std::fs::File;
 use std::io::{self, Read, Write};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
@@ -34,7 +35,7 @@ use tokio_io::AsyncRead;
 use tokio_io::io::{ReadHalf, WriteHalf};
 
 use common::OurFuture;
-use proto::{Bincoded, Bytes, BytesMut, DriverInfo, api, handshake};
+use proto::{Bytes, BytesMut, DriverInfo, Endpoints, Envelope, Schema, api, handshake};
 use proto::serde::Serialize;
 
 mod errors {
@@ -49,10 +50,13 @@ mod errors {
 use errors::*;
 
 fn main() {
-    let addr: SocketAddr = ([127, 0, 0, 1], 2001).into();
     let oops = "couldn't write to stderr";
 
-    match serve(&addr) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+    let endpoints = Endpoints::from_command_line(&root, "server")
+        .chain_err(|| "couldn't configure endpoints");
+
+    match endpoints.and_then(|endpoints| serve(&endpoints)) {
         Ok(()) => (),
         Err(Error(ErrorKind::AlreadyRunning, _)) => {
             writeln!(io::stderr(), "Server already listening.").expect(oops);
@@ -74,7 +78,10 @@ fn main() {
     }
 }
 
-fn serve(addr: &SocketAddr) -> Result<()> {
+fn serve(endpoints: &Endpoints) -> Result<()> {
+    let addr = &endpoints.listen;
+    let endpoints = Rc::new(endpoints.clone());
+
     // preload the latest driver (if any)
     let current_driver = HashedHeapFile::latest();
 
@@ -118,6 +125,7 @@ fn serve(addr: &SocketAddr) -> Result<()> {
                     upstream: god.clone(),
                     outbox_rx,
                     current_driver: current.clone(),
+                    endpoints: endpoints.clone(),
                 };
                 handle.spawn(serve_client(io));
                 Ok(())
@@ -126,21 +134,22 @@ fn serve(addr: &SocketAddr) -> Result<()> {
 
     // listen for upgrades
     let (upgrade_tx, upgrade_rx) = unbounded();
-    serve_controller(core.handle(), upgrade_tx);
+    serve_controller(core.handle(), &endpoints.control, endpoints.max_message, upgrade_tx);
 
     // serve upgrade binaries via HTTP
-    http::serve(core.handle(), current_driver.clone());
+    http::serve(core.handle(), &endpoints.http, current_driver.clone());
 
     // broadcast upgrades to clients
     let god = god.clone();
     let handle = core.handle();
+    let urls = endpoints.clone();
     handle.spawn(upgrade_rx.for_each(move |info| {
         use api::DownResponse::ProposeUpgrade;
 
-        let uri = http::driver_url(&info);
+        let uri = urls.driver_url(&info.digest);
         let msg = ProposeUpgrade(uri, box info);
-        match Bincoded::new(&msg) {
-            Ok(bincoded) => {
+        match Envelope::new(&msg) {
+            Ok(envelope) => {
                 // smelly!
                 let info = match msg { ProposeUpgrade(_, box info) => info, _ => unreachable!() };
                 let digest = info.digest.short_hex();
@@ -150,7 +159,7 @@ fn serve(addr: &SocketAddr) -> Result<()> {
                 // the update seems OK, so save it for future clients
                 *current_driver.borrow_mut() = Some(driver);
 
-                let bytes = bincoded.into();
+                let bytes = envelope.into();
                 let n = god.borrow_mut().broadcast(bytes);
                 if n > 0 {
                     println!("Sent {} to {} client(s)", digest, n);
@@ -169,18 +178,18 @@ fn serve(addr: &SocketAddr) -> Result<()> {
     core.run(server).chain_err(|| "core listener failed")
 }
 
-fn serve_controller(handle: Handle, tx: UnboundedSender<DriverInfo>) {
-    let addr: SocketAddr = ([127, 0, 0, 1], 2002).into();
-    let listener = TcpListener::bind(&addr, &handle).expect("couldn't bind controller");
+fn serve_controller(handle: Handle, addr: &SocketAddr, max: usize, tx: UnboundedSender<DriverInfo>) {
+    let listener = TcpListener::bind(addr, &handle).expect("couldn't bind controller");
     println!("Controller listening on: {}", addr);
 
     fn relay_upgrade(
         sock: TcpStream,
+        max: usize,
         tx: UnboundedSender<DriverInfo>)
         -> Box<Future<Item = (), Error = ()>> {
 
         let (r, _) = sock.split();
-        box common::read_bincoded::<_, DriverInfo>(r).and_then(move |(_, info)| {
+        box common::read_envelope::<_, DriverInfo>(r, max).and_then(move |(_, info)| {
             let digest = info.digest.short_hex();
             tx.send(info).chain_err(|| "couldn't send upgrade")?;
             println!("control: received upgrade {}", digest);
@@ -194,7 +203,7 @@ fn serve_controller(handle: Handle, tx: UnboundedSender<DriverInfo>) {
         .incoming()
         .for_each(
             move |(sock, _)| {
-                handle2.spawn(relay_upgrade(sock, tx.clone()));
+                handle2.spawn(relay_upgrade(sock, max, tx.clone()));
                 Ok(())
             }
         )
@@ -297,7 +306,7 @@ fn start_heartbeat(god: Rc<RefCell<God>>) -> Box<Future<Item = (), Error = ()>>
                 Err(())
             } else {
                 let msg = api::DownResponse::Goats(god.goats);
-                let coded = Bincoded::new(&msg).expect("encode heartbeat");
+                let coded = Envelope::new(&msg).expect("encode heartbeat");
                 god.broadcast(coded.into());
                 Ok(())
             }
@@ -319,11 +328,12 @@ struct ClientIO<U: Upstream> {
     upstream: Rc<RefCell<U>>,
     outbox_rx: UnboundedReceiver<Bytes>,
     current_driver: CurrentDriver,
+    endpoints: Rc<Endpoints>,
 }
 
 fn serve_client<U: Upstream + 'static>(io: ClientIO<U>) -> Box<Future<Item = (), Error = ()>> {
 
-    let ClientIO { id, r, w, client, upstream, outbox_rx, current_driver } = io;
+    let ClientIO { id, r, w, client, upstream, outbox_rx, current_driver, endpoints } = io;
     let remove_myself = {
         let up = upstream.clone();
         move || up.borrow_mut().remove_client(id)
@@ -332,7 +342,8 @@ fn serve_client<U: Upstream + 'static>(io: ClientIO<U>) -> Box<Future<Item = (),
     let addr = client.addr;
     println!("new client #{} from {}", id, addr);
 
-    let hello = common::read_bincoded(r);
+    let max = endpoints.max_message;
+    let hello = common::read_envelope(r, max);
 
     box hello
             .and_then(
@@ -353,16 +364,16 @@ fn serve_client<U: Upstream + 'static>(io: ClientIO<U>) -> Box<Future<Item = (),
             let write: OurFuture<_> = match hello {
                 Cached(ref d) | Oneshot(ref d) if d == &info.digest => {
                     let msg: Welcome<&DriverInfo> = Current;
-                    box common::write_bincoded(w, &msg).map(|(w, _)| w)
+                    box common::write_envelope(w, &msg).map(|(w, _)| w)
                 }
                 Newbie | Cached(_) => {
-                    let uri = http::driver_url(&info);
-                    let bincoded = try_box!(Bincoded::new(&Download(uri, info)));
-                    box common::write_with_length(w, bincoded).map(|(w, _)| w)
+                    let uri = endpoints.driver_url(&info.digest);
+                    let envelope = try_box!(Envelope::new(&Download(uri, info)));
+                    box common::write_with_length(w, envelope).map(|(w, _)| w)
                 }
                 Oneshot(digest) => {
                     let msg: Welcome<&DriverInfo> = Obsolete;
-                    box common::write_bincoded(w, &msg).and_then(
+                    box common::write_envelope(w, &msg).and_then(
                         move |_| {
                             bail!("{} has an obsolete oneshot: {}", addr, digest)
                         }
@@ -380,7 +391,7 @@ fn serve_client<U: Upstream + 'static>(io: ClientIO<U>) -> Box<Future<Item = (),
                 (b, a)
             }
 
-            let requests = stream::unfold(r, |r| Some(common::read_bincoded(r).map(swap)))
+            let requests = stream::unfold(r, |r| Some(common::read_envelope(r, max).map(swap)))
                 .for_each(move |req| client.handle_request(req));
 
             let writes = outbox_rx
@@ -423,8 +434,8 @@ impl ClientEntry {
         }
     }
 
-    fn send<T: Serialize>(&self, msg: &T) -> Result<()> {
-        let coded = Bincoded::new(msg)?;
+    fn send<T: Serialize + Schema>(&self, msg: &T) -> Result<()> {
+        let coded = Envelope::new(msg)?;
         let bytes = coded.into();
         self.outbox_tx
             .send(bytes)
@@ -433,24 +444,10 @@ impl ClientEntry {
 }
 
 fn read_metadata(path: &Path) -> Result<DriverInfo> {
-    let len = fs::metadata(path)
-        .chain_err(|| format!("couldn't stat metadata ({})", path.display()))?
-        .len() as usize; // unchecked cast
-    if len == 0 {
-        bail!("metadata ({}) is empty", path.display());
-    }
-    let mut bytes = BytesMut::with_capacity(len);
-    unsafe {
-        bytes.set_len(len);
-    }
-    File::open(path)
-        .and_then(|mut f| f.read_exact(&mut bytes))
-        .chain_err(|| format!("couldn't open metadata ({})", path.display()))?;
-    // omitted: eof check
-    let info: DriverInfo =
-        unsafe { Bincoded::from_bytes(bytes.freeze()) }
-            .deserialize()
-            .chain_err(|| format!("couldn't decode metadata ({})", path.display()))?;
+    let info: DriverInfo = Envelope::from_path(path)
+        .chain_err(|| format!("couldn't open metadata ({})", path.display()))?
+        .deserialize()
+        .chain_err(|| format!("couldn't decode metadata ({})", path.display()))?;
     Ok(info)
 }
 
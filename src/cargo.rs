@@ -2,44 +2,52 @@
 
 use std::borrow::Cow;
 use std::cmp;
+use std::env;
+use std::ffi::OsStr;
 use std::io::{self, BufRead, BufReader, Write};
 use std::mem;
-use std::path::Path;
-use std::process::{self, Child, ChildStdout, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ChildStdout, ExitStatus, Stdio};
 use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
+use libc;
+use serde::de::{self, IgnoredAny};
 use serde_json;
 use terminal_size::terminal_size;
 
 use errors::*;
 pub use self::diagnostic::Diagnostic;
-pub use self::target::Kind;
+pub use self::target::{Kind, LibKind};
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "reason")]
+/// One line of `cargo --message-format json`, keyed on its `reason`.
+#[derive(Debug)]
 pub enum Output<'a> {
-    #[serde(rename = "compiler-artifact")]
-    Artifact(
-        #[serde(borrow)]
-        Artifact<'a>
-    ),
-    #[serde(rename = "compiler-message")]
-    Message(
-        #[serde(borrow)]
-        Message<'a>
-    ),
-    #[serde(rename = "build-script-executed")]
-    BuildStep(
-        #[serde(borrow)]
-        BuildStep<'a>
-    ),
+    /// "compiler-artifact"
+    Artifact(Artifact<'a>),
+    /// "compiler-message"
+    Message(Message<'a>),
+    /// "build-script-executed"
+    BuildStep(BuildStep<'a>),
+    /// "build-finished"
+    Finished(BuildFinished),
+    /// Any reason added to cargo after this was written.
+    Unknown(Cow<'a, str>),
+    /// A line from libtest's JSON output, which has a `type` instead of a `reason`.
+    /// See `Command::test_json`.
+    Test(TestEvent<'a>),
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Artifact<'a> {
     pub features: Vec<&'a str>,
     pub filenames: Vec<&'a Path>,
+    /// Only reported by newer cargos, and only for binaries.
+    pub executable: Option<&'a Path>,
     pub fresh: bool,
+    pub manifest_path: Option<&'a Path>,
     #[serde(borrow)]
     pub package_id: Cow<'a, str>,
     #[serde(borrow)]
@@ -52,14 +60,55 @@ pub struct Artifact<'a> {
 pub struct BuildStep<'a> {
     #[serde(borrow)]
     pub cfgs: Vec<Cow<'a, str>>,
+    #[serde(borrow, default)]
+    pub env: Vec<(Cow<'a, str>, Cow<'a, str>)>,
     #[serde(borrow)]
     pub linked_libs: Vec<Cow<'a, str>>,
     #[serde(borrow)]
     pub linked_paths: Vec<&'a Path>,
+    pub out_dir: Option<&'a Path>,
     #[serde(borrow)]
     pub package_id: Cow<'a, str>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct BuildFinished {
+    pub success: bool,
+}
+
+/// Progress of a test binary, e.g. `{"type": "test", "event": "ok", "name": "tests::foo"}`.
+#[derive(Debug, Deserialize)]
+pub struct TestEvent<'a> {
+    /// "suite", "test" or "bench".
+    #[serde(rename = "type", borrow)]
+    pub kind: Cow<'a, str>,
+    /// "started", "ok", "failed", "ignored", ...
+    #[serde(borrow)]
+    pub event: Cow<'a, str>,
+    /// The test's path; absent for suites.
+    #[serde(borrow, default)]
+    pub name: Option<Cow<'a, str>>,
+    /// Its captured output, for failed tests.
+    #[serde(borrow, default)]
+    pub stdout: Option<Cow<'a, str>>,
+    /// When a suite starts.
+    pub test_count: Option<usize>,
+    /// When a suite finishes.
+    pub passed: Option<usize>,
+    pub failed: Option<usize>,
+    pub ignored: Option<usize>,
+}
+
+impl<'a> TestEvent<'a> {
+    pub fn is_test(&self) -> bool {
+        self.kind == "test"
+    }
+
+    pub fn is_suite(&self) -> bool {
+        self.kind == "suite"
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Profile<'a> {
     pub debug_assertions: bool,
@@ -83,9 +132,10 @@ pub mod target {
     use std::fmt;
     use serde::de;
 
-    #[derive(Debug)]
+    #[derive(Debug, PartialEq)]
     pub enum Kind<'a> {
-        Lib(Vec<&'a str>),
+        Lib(Vec<LibKind<'a>>),
+        ProcMacro,
         Bin,
         Test,
         Bench,
@@ -93,6 +143,17 @@ pub mod target {
         CustomBuild,
     }
 
+    /// The flavours of library listed in a lib target's `kind`.
+    #[derive(Debug, PartialEq)]
+    pub enum LibKind<'a> {
+        Lib,
+        Rlib,
+        Dylib,
+        Cdylib,
+        Staticlib,
+        Other(&'a str),
+    }
+
     impl<'a> Kind<'a> {
         pub fn is_bin(&self) -> bool {
             match *self {
@@ -100,6 +161,30 @@ pub mod target {
                 _ => false,
             }
         }
+
+        /// Whether this produces something loadable at runtime (dylib or cdylib).
+        pub fn is_dynamic(&self) -> bool {
+            match *self {
+                Kind::Lib(ref libs) => {
+                    libs.iter().any(|lib| *lib == LibKind::Dylib || *lib == LibKind::Cdylib)
+                }
+                Kind::ProcMacro => true,
+                _ => false,
+            }
+        }
+    }
+
+    impl<'a> From<&'a str> for LibKind<'a> {
+        fn from(kind: &'a str) -> Self {
+            match kind {
+                "lib" => LibKind::Lib,
+                "rlib" => LibKind::Rlib,
+                "dylib" => LibKind::Dylib,
+                "cdylib" => LibKind::Cdylib,
+                "staticlib" => LibKind::Staticlib,
+                other => LibKind::Other(other),
+            }
+        }
     }
 
     impl<'de: 'a, 'a> de::Deserialize<'de> for Kind<'a> {
@@ -127,14 +212,17 @@ pub mod target {
                 "bench" => Kind::Bench,
                 "example" => Kind::Example,
                 "custom-build" => Kind::CustomBuild,
+                "proc-macro" => Kind::ProcMacro,
                 first_lib => {
-                    let mut libs = vec![first_lib];
+                    let mut libs = vec![first_lib.into()];
                     while let Some(lib) = seq.next_element::<&'de str>()? {
-                        libs.push(lib);
+                        libs.push(lib.into());
                     }
                     Kind::Lib(libs)
                 }
             };
+            // don't choke on anything trailing a non-lib kind
+            while let Some(_) = seq.next_element::<de::IgnoredAny>()? {}
             Ok(kind)
         }
     }
@@ -190,6 +278,8 @@ pub mod diagnostic {
         Note,
         #[serde(rename = "help")]
         Help,
+        #[serde(rename = "failure-note")]
+        FailureNote,
     }
 
     impl Level {
@@ -197,7 +287,7 @@ pub mod diagnostic {
             use self::Level::*;
             match *self {
                 InternalCompilerError | Error => true,
-                Warning | Note | Help => false,
+                Warning | Note | Help | FailureNote => false,
             }
         }
     }
@@ -212,6 +302,7 @@ pub mod diagnostic {
                     Warning => "warning",
                     Note => "note",
                     Help => "help",
+                    FailureNote => "failure-note",
                 }
             )
         }
@@ -249,11 +340,11 @@ pub mod diagnostic {
     #[derive(Debug, Deserialize)]
     pub struct SpanMacroExpansion<'a> {
         #[serde(borrow)]
-        span: Span<'a>,
+        pub span: Span<'a>,
         #[serde(borrow)]
-        macro_decl_name: Cow<'a, str>,
+        pub macro_decl_name: Cow<'a, str>,
         #[serde(borrow)]
-        def_site_span: Option<Span<'a>>,
+        pub def_site_span: Option<Span<'a>>,
     }
 }
 
@@ -262,6 +353,13 @@ pub struct Command<'a> {
     path: Option<&'a Path>,
     features: Option<&'a [&'a str]>,
     bin_only: Option<&'a str>,
+    release: bool,
+    target: Option<&'a str>,
+    target_dir: Option<&'a Path>,
+    no_default_features: bool,
+    envs: Vec<(&'a str, &'a OsStr)>,
+    args: Vec<&'a OsStr>,
+    test_json: bool,
 }
 
 impl<'a> Command<'a> {
@@ -270,12 +368,12 @@ impl<'a> Command<'a> {
     }
 
     /// Set the location of Cargo.toml.
-    pub fn manifest_path(&'a mut self, path: &'a Path) -> &'a mut Command<'a> {
+    pub fn manifest_path(&mut self, path: &'a Path) -> &mut Command<'a> {
         self.path = Some(path);
         self
     }
 
-    pub fn features(&'a mut self, features: &'a [&'a str]) -> &'a mut Command<'a> {
+    pub fn features(&mut self, features: &'a [&'a str]) -> &mut Command<'a> {
         assert!(
             features.iter().all(|feat| !feat.contains(" ")),
             "{:?} contains spaces",
@@ -285,12 +383,79 @@ impl<'a> Command<'a> {
         self
     }
 
+    pub fn no_default_features(&mut self) -> &mut Command<'a> {
+        self.no_default_features = true;
+        self
+    }
+
     /// Build only the specified binary.
-    pub fn bin_only(&'a mut self, bin: &'a str) -> &'a mut Command<'a> {
+    pub fn bin_only(&mut self, bin: &'a str) -> &mut Command<'a> {
         self.bin_only = Some(bin);
         self
     }
 
+    /// Build with the release profile.
+    pub fn release(&mut self) -> &mut Command<'a> {
+        self.release = true;
+        self
+    }
+
+    /// Cross-compile for the given target triple.
+    pub fn target(&mut self, triple: &'a str) -> &mut Command<'a> {
+        self.target = Some(triple);
+        self
+    }
+
+    pub fn target_dir(&mut self, dir: &'a Path) -> &mut Command<'a> {
+        self.target_dir = Some(dir);
+        self
+    }
+
+    /// Set an environment variable for cargo (and thus build scripts and rustc).
+    pub fn env<V: AsRef<OsStr> + ?Sized>(&mut self, key: &'a str, val: &'a V) -> &mut Command<'a> {
+        self.envs.push((key, val.as_ref()));
+        self
+    }
+
+    /// Pass an extra argument through to cargo, after all the others.
+    pub fn arg<S: AsRef<OsStr> + ?Sized>(&mut self, arg: &'a S) -> &mut Command<'a> {
+        self.args.push(arg.as_ref());
+        self
+    }
+
+    /// For `cargo test`: have libtest report results as JSON lines too.
+    /// Needs a nightly libtest.
+    pub fn test_json(&mut self) -> &mut Command<'a> {
+        self.test_json = true;
+        self
+    }
+
+    /// The profile's output directory name: "debug" or "release".
+    pub fn profile(&self) -> &'static str {
+        if self.release { "release" } else { "debug" }
+    }
+
+    /// Where cargo will put this build's artifacts, e.g. `target/x86_64-apple-darwin/release`.
+    pub fn artifact_dir(&self) -> PathBuf {
+        let mut dir = match self.target_dir {
+            Some(dir) => dir.to_owned(),
+            None => {
+                match env::var_os("CARGO_TARGET_DIR") {
+                    Some(dir) => PathBuf::from(dir),
+                    None => {
+                        let manifest_dir = self.path.and_then(Path::parent).unwrap_or(Path::new(""));
+                        manifest_dir.join("target")
+                    }
+                }
+            }
+        };
+        if let Some(triple) = self.target {
+            dir.push(triple);
+        }
+        dir.push(self.profile());
+        dir
+    }
+
     pub fn spawn(&self, cmd: &str) -> Result<JsonStream> {
         let mut spawner = process::Command::new("cargo");
         spawner
@@ -307,43 +472,156 @@ impl<'a> Command<'a> {
             spawner.arg("--features");
             spawner.arg(features.join(" "));
         }
+        if self.no_default_features {
+            spawner.arg("--no-default-features");
+        }
         if let Some(ref bin) = self.bin_only {
             spawner.args(&["--bin", bin]);
         }
+        if self.release {
+            spawner.arg("--release");
+        }
+        if let Some(ref triple) = self.target {
+            spawner.args(&["--target", triple]);
+        }
+        if let Some(ref dir) = self.target_dir {
+            spawner.arg("--target-dir");
+            spawner.arg(dir);
+        }
+        for &(key, val) in &self.envs {
+            spawner.env(key, val);
+        }
+        spawner.args(&self.args);
+        if self.test_json {
+            spawner.args(&["--", "-Z", "unstable-options", "--format", "json"]);
+        }
+        own_process_group(&mut spawner);
 
         let mut child = spawner.spawn().chain_err(|| "couldn't run cargo")?;
 
         let stdout = mem::replace(&mut child.stdout, None).expect("child stdout");
         let stdout = BufReader::new(stdout);
 
+        let child = Arc::new(Mutex::new(child));
         let stream = JsonStream { child, stdout, len_guess: 500 };
         Ok(stream)
     }
 }
 
+/// Puts cargo in its own process group, so that killing it takes rustc down too.
+#[cfg(unix)]
+fn own_process_group(spawner: &mut process::Command) {
+    use std::os::unix::process::CommandExt;
+    spawner.before_exec(|| unsafe {
+        if libc::setpgid(0, 0) == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn own_process_group(_: &mut process::Command) {}
+
+/// How often `JsonStream::wait` checks whether cargo has exited.
+const WAIT_POLL_MS: u64 = 20;
+
 pub struct JsonStream {
-    child: Child,
+    child: Arc<Mutex<Child>>,
     stdout: BufReader<ChildStdout>,
     len_guess: usize,
 }
 
 impl JsonStream {
     pub fn kill(&mut self) {
-        let _ = self.child.kill();
+        self.killer().kill()
+    }
+
+    /// Waits for cargo to exit, e.g. after reading all its output.
+    /// Polls, rather than holding the child locked, so a `Killer` can still get at it.
+    pub fn wait(&mut self) -> Result<ExitStatus> {
+        loop {
+            let exited = self.child.lock().expect("lock cargo").try_wait();
+            match exited.chain_err(|| "couldn't wait for cargo")? {
+                Some(status) => return Ok(status),
+                None => thread::sleep(Duration::from_millis(WAIT_POLL_MS)),
+            }
+        }
+    }
+
+    /// A handle that can kill this cargo from another thread,
+    /// ending the stream.
+    pub fn killer(&self) -> Killer {
+        Killer(self.child.clone())
     }
 }
 
 impl Drop for JsonStream {
     fn drop(&mut self) {
-        self.kill()
+        self.kill();
+        // reap it
+        let _ = self.wait();
+    }
+}
+
+/// Kills a cargo process (and its rustcs) spawned by `Command::spawn`.
+#[derive(Clone)]
+pub struct Killer(Arc<Mutex<Child>>);
+
+impl Killer {
+    pub fn kill(&self) {
+        let mut child = self.0.lock().expect("lock cargo");
+        if let Ok(Some(_)) = child.try_wait() {
+            // already reaped; its pid may belong to someone else now
+            return;
+        }
+        kill_process_group(&child);
+        let _ = child.kill();
+    }
+}
+
+#[cfg(unix)]
+fn kill_process_group(child: &Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
     }
 }
 
+#[cfg(not(unix))]
+fn kill_process_group(_: &Child) {}
+
 pub struct JsonLine(pub String);
 
 impl JsonLine {
+    /// Decodes the line, first peeking at its `reason` to pick a shape.
     pub fn decode<'a>(&'a self) -> serde_json::Result<Output<'a>> {
-        serde_json::from_str(&self.0)
+        #[derive(Deserialize)]
+        struct Reason<'a> {
+            #[serde(borrow)]
+            reason: Option<Cow<'a, str>>,
+            #[serde(rename = "type")]
+            test: Option<IgnoredAny>,
+        }
+
+        let json = &self.0;
+        let reason = match serde_json::from_str(json)? {
+            Reason { reason: Some(reason), .. } => reason,
+            Reason { reason: None, test: Some(_) } => {
+                return serde_json::from_str(json).map(Output::Test)
+            }
+            Reason { reason: None, test: None } => {
+                return Err(de::Error::missing_field("reason"))
+            }
+        };
+        let output = match &*reason {
+            "compiler-artifact" => Output::Artifact(serde_json::from_str(json)?),
+            "compiler-message" => Output::Message(serde_json::from_str(json)?),
+            "build-script-executed" => Output::BuildStep(serde_json::from_str(json)?),
+            "build-finished" => Output::Finished(serde_json::from_str(json)?),
+            _ => Output::Unknown(reason.clone()),
+        };
+        Ok(output)
     }
 }
 
@@ -458,3 +736,186 @@ pub mod util {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{Command, JsonLine, Kind, LibKind, Output};
+    use super::diagnostic::Level;
+
+    macro_rules! fixture {
+        ($name:expr) => {
+            JsonLine(include_str!(concat!("../fixtures/cargo/", $name)).trim_right().to_owned())
+        }
+    }
+
+    #[test]
+    fn dylib_artifact() {
+        let line = fixture!("artifact-dylib.json");
+        match line.decode().unwrap() {
+            Output::Artifact(a) => {
+                assert_eq!(a.target.name, "g");
+                assert_eq!(a.target.kind, Kind::Lib(vec![LibKind::Dylib]));
+                assert!(a.target.kind.is_dynamic());
+                assert!(a.fresh);
+                assert_eq!(a.executable, None);
+                assert_eq!(a.manifest_path, None);
+                assert_eq!(a.features.len(), 4);
+                assert_eq!(a.profile.opt_level, "1");
+                assert_eq!(a.filenames, vec![Path::new("/Users/paul/exude/g/target/debug/deps/libg.dylib")]);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn bin_artifact_with_newer_fields() {
+        let line = fixture!("artifact-bin.json");
+        match line.decode().unwrap() {
+            Output::Artifact(a) => {
+                assert!(a.target.kind.is_bin());
+                assert!(!a.fresh);
+                assert_eq!(a.executable, Some(Path::new("/Users/paul/exude/client/target/release/client")));
+                assert_eq!(a.manifest_path, Some(Path::new("/Users/paul/exude/client/Cargo.toml")));
+                assert!(!a.profile.debug_assertions);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn proc_macro_artifact() {
+        let line = fixture!("artifact-proc-macro.json");
+        match line.decode().unwrap() {
+            Output::Artifact(a) => {
+                assert_eq!(a.target.kind, Kind::ProcMacro);
+                assert_eq!(a.target.name, "serde_derive");
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn cdylib_staticlib_artifact() {
+        let line = fixture!("artifact-cdylib-staticlib.json");
+        match line.decode().unwrap() {
+            Output::Artifact(a) => {
+                let libs = vec![LibKind::Cdylib, LibKind::Staticlib, LibKind::Rlib];
+                assert_eq!(a.target.kind, Kind::Lib(libs));
+                assert!(a.target.kind.is_dynamic());
+                assert_eq!(a.filenames.len(), 3);
+                assert_eq!(a.executable, None);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn custom_build_artifact() {
+        let line = fixture!("artifact-custom-build.json");
+        match line.decode().unwrap() {
+            Output::Artifact(a) => {
+                assert_eq!(a.target.kind, Kind::CustomBuild);
+                assert!(!a.target.kind.is_bin());
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn build_script_executed() {
+        let line = fixture!("build-script-executed.json");
+        match line.decode().unwrap() {
+            Output::BuildStep(b) => {
+                assert_eq!(b.linked_libs, vec!["g"]);
+                assert_eq!(b.env.len(), 1);
+                assert_eq!(b.env[0].0, "EXUDE_G_DIGEST");
+                assert!(b.out_dir.is_some());
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn compiler_message() {
+        let line = fixture!("message-warning.json");
+        match line.decode().unwrap() {
+            Output::Message(m) => {
+                let diag = m.message;
+                assert!(!diag.level.is_show_stopper());
+                match diag.level {
+                    Level::Warning => (),
+                    other => panic!("{:?}", other),
+                }
+                assert_eq!(diag.code.unwrap().code, "unused_variables");
+                assert_eq!(diag.spans[0].line_start, 71);
+                assert_eq!(diag.children.len(), 1);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn build_finished() {
+        let line = fixture!("build-finished.json");
+        match line.decode().unwrap() {
+            Output::Finished(f) => assert!(!f.success),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_reason() {
+        let line = fixture!("unknown-reason.json");
+        match line.decode().unwrap() {
+            Output::Unknown(reason) => assert_eq!(reason, "timing-info"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn failed_test() {
+        let line = fixture!("test-failed.json");
+        match line.decode().unwrap() {
+            Output::Test(t) => {
+                assert!(t.is_test());
+                assert_eq!(t.event, "failed");
+                assert_eq!(t.name.unwrap(), "tests::round_trip");
+                assert!(t.stdout.unwrap().contains("left: `3`"));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn finished_suite() {
+        let line = fixture!("suite-finished.json");
+        match line.decode().unwrap() {
+            Output::Test(t) => {
+                assert!(t.is_suite());
+                assert_eq!(t.event, "failed");
+                assert_eq!((t.passed, t.failed, t.ignored), (Some(11), Some(1), Some(2)));
+                assert!(t.name.is_none());
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn not_a_message() {
+        assert!(JsonLine(format!("{{\"type\":\"suite\"}}")).decode().is_err());
+        assert!(JsonLine(format!("Compiling g v0.1.0")).decode().is_err());
+    }
+
+    #[test]
+    fn explicit_target_dir() {
+        let mut cmd = Command::new();
+        cmd.manifest_path(Path::new("/src/g/Cargo.toml"))
+            .target_dir(Path::new("/tmp/shared"))
+            .no_default_features()
+            .target("x86_64-apple-darwin")
+            .release();
+        assert_eq!(cmd.artifact_dir(), Path::new("/tmp/shared/x86_64-apple-darwin/release"));
+    }
+}
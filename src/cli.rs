@@ -0,0 +1,182 @@
+//! Command-line parsing for the builder.
+
+use std::path::PathBuf;
+use std::process;
+
+use issuer::config;
+
+#[derive(Debug)]
+pub enum Command {
+    /// The interactive `> ` prompt.
+    Prompt,
+    Watch,
+    /// Build and sign, without announcing.
+    Build,
+    Announce { force: bool },
+    Status,
+    /// Re-announce the archived driver with this digest, or a prefix of it.
+    Rollback(String),
+    History(History),
+}
+
+#[derive(Debug)]
+pub enum History {
+    /// Show this many of the latest builds.
+    List(usize),
+    /// Compare two builds by their journal numbers.
+    Diff(usize, usize),
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub command: Command,
+    /// Print a JSON report as the final line of stdout.
+    pub json: bool,
+    /// Build with the release profile.
+    pub release: bool,
+    /// Cross-compile for this target triple.
+    pub target: Option<String>,
+    /// Build into this directory rather than each crate's own `target`.
+    pub target_dir: Option<PathBuf>,
+    /// Leave out the crates' default features.
+    pub no_default_features: bool,
+    /// Sign and announce drivers whose tests fail.
+    pub allow_failing_tests: bool,
+    /// Endpoint flags like `--announce HOST:PORT`, for `Endpoints::apply_args`.
+    pub endpoints: Vec<String>,
+}
+
+/// Exit code for compile errors, as opposed to other failures (1).
+pub const EXIT_BUILD_FAILED: i32 = 2;
+/// Exit code for a build that was interrupted.
+pub const EXIT_CANCELLED: i32 = 3;
+/// Exit code for a driver that built but failed its tests.
+pub const EXIT_TESTS_FAILED: i32 = 4;
+/// Exit code for unparseable arguments.
+pub const EXIT_USAGE: i32 = 64;
+
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut json = false;
+    let mut force = false;
+    let mut release = false;
+    let mut target = None;
+    let mut target_dir = None;
+    let mut no_default_features = false;
+    let mut allow_failing_tests = false;
+    let mut endpoints = Vec::new();
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            positional.push(arg);
+            continue;
+        }
+        if config::flag_key(&arg).is_some() {
+            // checked once the config file is loaded
+            if !arg.contains('=') {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                endpoints.push(arg);
+                endpoints.push(value);
+            } else {
+                endpoints.push(arg);
+            }
+            continue;
+        }
+        match &*arg {
+            "--json" => json = true,
+            "--force" | "-f" => force = true,
+            "--release" => release = true,
+            "--allow-failing-tests" => allow_failing_tests = true,
+            "--no-default-features" => no_default_features = true,
+            "--target" => {
+                let triple = args.next().ok_or_else(|| format!("--target needs a triple"))?;
+                target = Some(triple);
+            }
+            "--target-dir" => {
+                let dir = args.next().ok_or_else(|| format!("--target-dir needs a directory"))?;
+                target_dir = Some(PathBuf::from(dir));
+            }
+            "--help" | "-h" => usage(0),
+            flag => return Err(format!("unknown flag {}", flag)),
+        }
+    }
+
+    let command = match (positional.get(0).map(|s| &**s), positional.len()) {
+        (None, _) => Command::Prompt,
+        (Some("watch"), 1) => Command::Watch,
+        (Some("build"), 1) => Command::Build,
+        (Some("announce"), 1) => Command::Announce { force },
+        (Some("status"), 1) => Command::Status,
+        (Some("rollback"), 2) => Command::Rollback(positional[1].clone()),
+        (Some("rollback"), 1) => return Err(format!("rollback needs a digest")),
+        (Some("history"), 1) => Command::History(History::List(20)),
+        (Some("history"), 2) => Command::History(History::List(number(&positional[1])?)),
+        (Some("history"), 4) if positional[1] == "diff" => {
+            let a = number(&positional[2])?;
+            let b = number(&positional[3])?;
+            Command::History(History::Diff(a, b))
+        }
+        _ => return Err(format!("unknown command {:?}", positional.join(" "))),
+    };
+
+    match command {
+        Command::Announce { .. } => (),
+        _ if force => return Err(format!("--force only applies to announce")),
+        _ => (),
+    }
+
+    Ok(
+        Args {
+            command,
+            json,
+            release,
+            target,
+            target_dir,
+            no_default_features,
+            allow_failing_tests,
+            endpoints,
+        }
+    )
+}
+
+fn number(arg: &str) -> Result<usize, String> {
+    arg.parse().map_err(|_| format!("{:?} is not a number", arg))
+}
+
+pub fn usage(code: i32) -> ! {
+    println!(
+        "Usage: exude [--json] [--release] [--target TRIPLE] [--target-dir DIR] [--no-default-features] [--allow-failing-tests] [--announce HOST:PORT] [COMMAND]
+
+Commands:
+    (none)              interactive prompt; rebuilds on enter
+    watch               rebuild and announce whenever sources change
+    build               build, test and sign the driver
+    announce [--force]  build, test, sign, and announce if changed (or always, if forced)
+    status              show the currently signed driver
+    rollback <digest>   re-announce a previously signed driver; a unique prefix will do
+    history [N]         list the last N (default 20) builds from the journal
+    history diff A B    compare the timings and results of builds #A and #B
+
+Options:
+    --json              print a JSON report as the last line of stdout
+    --release           build optimized drivers with the release profile
+    --target TRIPLE     cross-compile for the given target
+    --target-dir DIR    build every crate into DIR instead of its own target directory
+    --no-default-features
+                        build without the crates' default features
+    --allow-failing-tests
+                        sign and announce the driver even if its tests fail
+
+Endpoints:
+{}
+
+Exit codes: 0 ok, 1 error, {} compile errors, {} cancelled, {} tests failed, {} bad usage.
+Set EXUDE_PASSPHRASE to sign without a terminal.",
+        config::FLAGS_HELP,
+        EXIT_BUILD_FAILED,
+        EXIT_CANCELLED,
+        EXIT_TESTS_FAILED,
+        EXIT_USAGE
+    );
+    process::exit(code)
+}
@@ -0,0 +1,319 @@
+//! An append-only log of every build, for spotting compile-time regressions.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use issuer::Digest;
+use serde_json;
+
+use errors::*;
+
+/// One line per build, oldest first.
+pub fn path(root: &Path) -> PathBuf {
+    root.join("builds.jsonl")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// Seconds since the epoch when the build started.
+    pub time: u64,
+    /// Wall-clock length of the whole build.
+    pub millis: u64,
+    pub profile: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub targets: Vec<TargetStats>,
+    pub outcome: Outcome,
+    /// The driver's digest, as in `Digest::file_name`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    pub announced: bool,
+    /// Why the driver wasn't announced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TargetStats {
+    pub name: String,
+    pub millis: u64,
+    pub warnings: usize,
+    pub errors: usize,
+    /// Only for the test run.
+    #[serde(default)]
+    pub failed_tests: usize,
+    pub fresh: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Ok,
+    /// Compile errors.
+    Failed,
+    Cancelled,
+    /// The driver built, but its tests didn't pass.
+    TestsFailed,
+    /// Anything else, like a signing failure.
+    Error,
+}
+
+impl Entry {
+    pub fn new(time: SystemTime, profile: &str, target: Option<&str>) -> Self {
+        let time = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Entry {
+            time,
+            millis: 0,
+            profile: profile.to_owned(),
+            target: target.map(str::to_owned),
+            targets: Vec::new(),
+            outcome: Outcome::Ok,
+            digest: None,
+            announced: false,
+            skipped: None,
+        }
+    }
+
+    /// The driver built, if any. Older entries hold tagged hex, which parses too.
+    pub fn driver(&self) -> Option<Digest> {
+        self.digest.as_ref().and_then(|hex| hex.parse().ok())
+    }
+
+    pub fn skip<S: Into<String>>(&mut self, reason: S) {
+        self.announced = false;
+        self.skipped = Some(reason.into());
+    }
+
+    pub fn announce_result<T>(&mut self, result: &Result<T>) {
+        match *result {
+            Ok(_) => {
+                self.announced = true;
+                self.skipped = None;
+            }
+            Err(ref e) => self.skip(format!("announce failed: {}", e)),
+        }
+    }
+
+    fn stats(&self, name: &str) -> Option<&TargetStats> {
+        self.targets.iter().find(|t| t.name == name)
+    }
+
+    fn warnings(&self) -> usize {
+        self.targets.iter().map(|t| t.warnings).sum()
+    }
+
+    fn errors(&self) -> usize {
+        self.targets.iter().map(|t| t.errors).sum()
+    }
+}
+
+impl TargetStats {
+    pub fn new(name: &str) -> Self {
+        TargetStats {
+            name: name.to_owned(),
+            millis: 0,
+            warnings: 0,
+            errors: 0,
+            failed_tests: 0,
+            fresh: false,
+        }
+    }
+}
+
+pub fn millis(elapsed: Duration) -> u64 {
+    elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64
+}
+
+pub fn append(root: &Path, entry: &Entry) -> Result<()> {
+    let path = path(root);
+    let mut line = serde_json::to_string(entry).chain_err(|| "couldn't serialize build entry")?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .chain_err(|| format!("couldn't append to {}", path.display()))
+}
+
+/// Every entry, oldest first. Unreadable lines (say, from a crash
+/// mid-write) are reported and skipped, not fatal.
+pub fn read(root: &Path) -> Result<Vec<Entry>> {
+    let path = path(root);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).chain_err(|| format!("couldn't open {}", path.display())),
+    };
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.chain_err(|| format!("couldn't read {}", path.display()))?;
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                writeln!(io::stderr(), "{}:{}: skipping entry: {}", path.display(), i + 1, e)
+                    .expect("stderr")
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Columns shown for each build.
+static TARGETS: &[&str] = &["g", "driver", "tests", "client"];
+
+/// Lists the last `count` builds, numbered for `diff`.
+pub fn print_list(entries: &[Entry], count: usize) {
+    print!("{:>5}  {:19} {:>8}", "#", "started (UTC)", "total");
+    for name in TARGETS {
+        print!(" {:>8}", name);
+    }
+    println!(" {:>5} {:>4}  {:12}  {}", "warn", "err", "driver", "result");
+
+    let skip = entries.len().saturating_sub(count);
+    for (i, entry) in entries.iter().enumerate().skip(skip) {
+        print!("{:>5}  {:19} {:>8}", i + 1, format_time(entry.time), seconds(entry.millis));
+        for name in TARGETS {
+            print!(" {:>8}", entry.stats(name).map(format_stats).unwrap_or_default());
+        }
+        let digest = entry.driver().map(|digest| digest.short_hex()).unwrap_or_else(|| "-".to_owned());
+        println!(
+            " {:>5} {:>4}  {:12}  {}",
+            entry.warnings(),
+            entry.errors(),
+            digest,
+            describe(entry)
+        );
+    }
+}
+
+/// Compares build `a` with build `b`, target by target.
+pub fn print_diff(a: (usize, &Entry), b: (usize, &Entry)) {
+    let (a_num, a) = a;
+    let (b_num, b) = b;
+    println!("build {} ({}) -> build {} ({})", a_num, format_time(a.time), b_num, format_time(b.time));
+    if a.profile != b.profile || a.target != b.target {
+        println!(
+            "  note: profiles differ ({}{} vs {}{})",
+            a.profile,
+            a.target.as_ref().map(|t| format!(" {}", t)).unwrap_or_default(),
+            b.profile,
+            b.target.as_ref().map(|t| format!(" {}", t)).unwrap_or_default()
+        );
+    }
+
+    for name in TARGETS {
+        let (before, after) = match (a.stats(name), b.stats(name)) {
+            (None, None) => continue,
+            (before, after) => (before, after),
+        };
+        let time = |s: Option<&TargetStats>| s.map(format_stats).unwrap_or_else(|| "-".into());
+        let mut line = format!("  {:>8}: {:>8} -> {:>8}", name, time(before), time(after));
+        if let (Some(before), Some(after)) = (before, after) {
+            line.push_str(&format!(" ({})", delta(before.millis, after.millis)));
+            if before.warnings != after.warnings {
+                line.push_str(&format!(", warnings {} -> {}", before.warnings, after.warnings));
+            }
+            if before.errors != after.errors {
+                line.push_str(&format!(", errors {} -> {}", before.errors, after.errors));
+            }
+            if before.failed_tests != after.failed_tests {
+                line.push_str(&format!(", failed tests {} -> {}", before.failed_tests, after.failed_tests));
+            }
+        }
+        println!("{}", line);
+    }
+
+    println!(
+        "  {:>8}: {:>8} -> {:>8} ({})",
+        "total",
+        seconds(a.millis),
+        seconds(b.millis),
+        delta(a.millis, b.millis)
+    );
+    if a.driver() != b.driver() {
+        let hex = |entry: &Entry| {
+            entry.driver().map(|digest| digest.file_name()).unwrap_or_else(|| "-".to_owned())
+        };
+        println!("  driver: {} -> {}", hex(a), hex(b));
+    } else {
+        println!("  driver unchanged");
+    }
+    println!("  result: {} -> {}", describe(a), describe(b));
+}
+
+fn describe(entry: &Entry) -> String {
+    let outcome = match entry.outcome {
+        Outcome::Ok => "ok",
+        Outcome::Failed => "failed",
+        Outcome::Cancelled => "cancelled",
+        Outcome::TestsFailed => "tests failed",
+        Outcome::Error => "error",
+    };
+    if entry.announced {
+        format!("{}, announced", outcome)
+    } else {
+        match entry.skipped {
+            Some(ref why) if entry.outcome == Outcome::Ok => format!("{}, not announced: {}", outcome, why),
+            Some(ref why) => format!("{}: {}", outcome, why),
+            None => outcome.to_owned(),
+        }
+    }
+}
+
+fn format_stats(stats: &TargetStats) -> String {
+    if stats.fresh {
+        "fresh".into()
+    } else {
+        seconds(stats.millis)
+    }
+}
+
+fn seconds(millis: u64) -> String {
+    format!("{:.1}s", millis as f64 / 1000.0)
+}
+
+fn delta(before: u64, after: u64) -> String {
+    let delta = after as i64 - before as i64;
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{}{:.1}s", sign, delta.abs() as f64 / 1000.0)
+}
+
+/// `YYYY-MM-DD hh:mm:ss` in UTC, without pulling in a date crate.
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_time;
+
+    #[test]
+    fn utc_dates() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00");
+        assert_eq!(format_time(1497960191), "2017-06-20 12:03:11");
+    }
+}
@@ -1,6 +1,9 @@
 #[macro_use]
 extern crate error_chain;
+extern crate ctrlc;
 extern crate issuer;
+extern crate libc;
+extern crate notify;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -8,60 +11,257 @@ extern crate serde_json;
 extern crate terminal_size;
 
 pub mod cargo;
+mod cli;
+mod journal;
+mod pipeline;
+mod render;
+mod watch;
 
+use std::env;
 use std::fs;
 use std::io::{self, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::net::TcpStream;
 use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::{Instant, SystemTime};
 
-use issuer::{Bincoded, DriverInfo};
+use issuer::{Digest, DriverInfo, Endpoints, Envelope, frame};
 
 use cargo::Output;
+use cargo::diagnostic::Level;
+use cli::Command;
 use errors::*;
+use journal::TargetStats;
+use pipeline::{Canceller, CurrentBuild, Progress, Status};
 
 pub mod errors {
     error_chain! {
         links {
             Issuer(::issuer::Error, ::issuer::ErrorKind);
         }
-        errors { BuildError Cancelled }
+        errors {
+            BuildError
+            Cancelled
+            TestsFailed(count: usize) {
+                description("driver tests failed")
+                display("{} driver test(s) failed", count)
+            }
+        }
     }
 }
 
 fn main() {
     let oops = "couldn't write to stderr";
     let mut stderr = io::stderr();
-    match run() {
-        Ok(()) => (),
-        Err(Error(ErrorKind::Issuer(issuer::ErrorKind::InvalidPassword), _)) => {
-            writeln!(stderr, "Invalid encryption password.").expect(oops);
-            process::exit(1);
+
+    let args = match cli::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => {
+            writeln!(stderr, "{}\n(see --help)", msg).expect(oops);
+            process::exit(cli::EXIT_USAGE);
+        }
+    };
+
+    match run(&args) {
+        Ok(report) => {
+            if args.json {
+                println!("{}", serde_json::to_string(&report).expect("json report"));
+            }
         }
         Err(e) => {
-            let mut log = stderr.lock();
-            if let Some(backtrace) = e.backtrace() {
-                writeln!(log, "\n{:?}\n", backtrace).expect(oops);
+            let code = match *e.kind() {
+                ErrorKind::BuildError => cli::EXIT_BUILD_FAILED,
+                ErrorKind::Cancelled => cli::EXIT_CANCELLED,
+                ErrorKind::TestsFailed(_) => cli::EXIT_TESTS_FAILED,
+                _ => 1,
+            };
+            match e {
+                Error(ErrorKind::BuildError, _) => writeln!(stderr, "Build failed.").expect(oops),
+                Error(ErrorKind::Cancelled, _) => writeln!(stderr, "Build cancelled.").expect(oops),
+                Error(ErrorKind::TestsFailed(n), _) => {
+                    writeln!(
+                        stderr,
+                        "{} driver test(s) failed; not signing. (--allow-failing-tests to override)",
+                        n
+                    ).expect(oops)
+                }
+                Error(ErrorKind::Issuer(issuer::ErrorKind::InvalidPassword), _) => {
+                    writeln!(stderr, "Invalid encryption password.").expect(oops)
+                }
+                ref e => {
+                    let mut log = stderr.lock();
+                    if let Some(backtrace) = e.backtrace() {
+                        writeln!(log, "\n{:?}\n", backtrace).expect(oops);
+                    }
+                    writeln!(log, "error: {}", e).expect(oops);
+                    for e in e.iter().skip(1) {
+                        writeln!(log, "caused by: {}", e).expect(oops);
+                    }
+                }
             }
-            writeln!(log, "error: {}", e).expect(oops);
-            for e in e.iter().skip(1) {
-                writeln!(log, "caused by: {}", e).expect(oops);
+            if args.json {
+                println!("{}", serde_json::to_string(&Report::failure(&e)).expect("json report"));
             }
-            drop(log);
-            process::exit(1);
+            process::exit(code);
         }
     }
 }
 
-fn run() -> Result<()> {
+fn run(args: &cli::Args) -> Result<Report> {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let mut endpoints = Endpoints::load(&root).chain_err(|| "couldn't load endpoints")?;
+    endpoints.apply_args(args.endpoints.clone()).chain_err(|| "bad endpoint flag")?;
     let config = Config {
-        root: PathBuf::from(env!("CARGO_MANIFEST_DIR")),
+        root,
+        endpoints,
+        release: args.release,
+        target: args.target.clone(),
+        target_dir: args.target_dir.clone(),
+        no_default_features: args.no_default_features,
+        allow_failing_tests: args.allow_failing_tests,
     };
-    let keys = issuer::load_keys()?;
+    let current = CurrentBuild::new();
+
+    match args.command {
+        Command::Prompt => {
+            let keys = issuer::load_keys()?;
+            cancel_on_ctrl_c(&current)?;
+            prompt(&config, &keys, &current)?;
+            Ok(Report::success())
+        }
+        Command::Watch => {
+            let keys = issuer::load_keys()?;
+            cancel_on_ctrl_c(&current)?;
+            watch::run(&config, &keys, &current)?;
+            Ok(Report::success())
+        }
+        Command::Build => {
+            let keys = issuer::load_keys()?;
+            cancel_on_ctrl_c(&current)?;
+            let (result, mut entry) = build_current(&config, &keys, &current);
+            if result.is_ok() {
+                entry.skip("built without announcing");
+            }
+            log_build(&config, &entry);
+            let (info, novelty) = result?;
+            Ok(Report::driver(&info).fresh(&novelty))
+        }
+        Command::Announce { force } => {
+            let keys = issuer::load_keys()?;
+            cancel_on_ctrl_c(&current)?;
+            let (result, mut entry) = build_current(&config, &keys, &current);
+            let (info, novelty) = match result {
+                Ok(built) => built,
+                Err(e) => {
+                    log_build(&config, &entry);
+                    return Err(e);
+                }
+            };
+            let report = Report::driver(&info).fresh(&novelty);
+            if force || !novelty.is_still_fresh() {
+                let announced = announce_build(&config, info);
+                entry.announce_result(&announced);
+                log_build(&config, &entry);
+                announced?;
+                println!("   Announced driver {}", report.short_hex());
+                Ok(report.announced(true))
+            } else {
+                entry.skip("driver unchanged");
+                log_build(&config, &entry);
+                println!("   Driver unchanged; not announcing");
+                Ok(report.announced(false))
+            }
+        }
+        Command::Status => {
+            let pk = issuer::load_public_key()?;
+            let info = issuer::verify(&pk, &config.root).chain_err(|| "no valid signed driver")?;
+            println!("Signed driver {} ({} bytes)", info.digest, info.len);
+            Ok(Report::driver(&info))
+        }
+        Command::Rollback(ref prefix) => {
+            let digest = issuer::find_archived(&config.root, prefix)?;
+            let pk = issuer::load_public_key()?;
+            let info = issuer::restore(&pk, &digest, &config.root)?;
+            let report = Report::driver(&info);
+            announce_build(&config, info)?;
+            println!("   Announced driver {}", report.short_hex());
+            Ok(report.announced(true))
+        }
+        Command::History(cli::History::List(count)) => {
+            let entries = journal::read(&config.root)?;
+            journal::print_list(&entries, count);
+            Ok(Report::success())
+        }
+        Command::History(cli::History::Diff(a, b)) => {
+            let entries = journal::read(&config.root)?;
+            let nth = |n: usize| {
+                n.checked_sub(1)
+                    .and_then(|i| entries.get(i))
+                    .map(|entry| (n, entry))
+                    .ok_or_else(|| format!("no build #{} in the journal", n))
+            };
+            journal::print_diff(nth(a)?, nth(b)?);
+            Ok(Report::success())
+        }
+    }
+}
+
+/// Ctrl-C cancels the build in progress; otherwise, it quits as usual.
+fn cancel_on_ctrl_c(current: &CurrentBuild) -> Result<()> {
+    let current = current.clone();
+    ctrlc::set_handler(
+        move || if !current.cancel() {
+            process::exit(130);
+        }
+    ).chain_err(|| "couldn't install Ctrl-C handler")
+}
+
+/// Builds as the `current` build, which a newer one (or Ctrl-C) may cancel.
+/// The journal entry has yet to learn whether the driver got announced.
+fn build_current(
+    config: &Config,
+    keys: &issuer::InsecureKeys,
+    current: &CurrentBuild,
+) -> (Result<(DriverInfo, Novelty)>, journal::Entry) {
+    let triple = config.target.as_ref().map(|t| &**t);
+    let mut entry = journal::Entry::new(SystemTime::now(), config.profile(), triple);
+    let started = Instant::now();
+    let progress = Arc::new(Progress::new(&["g", "driver", "tests", "client"]));
+
+    let cancel = current.start();
+    let result = build(config, keys, &cancel, &progress);
+    current.finish();
+    progress.finish();
+
+    entry.millis = journal::millis(started.elapsed());
+    entry.targets = progress.take_stats();
+    match result {
+        Ok((ref info, _)) => entry.digest = Some(info.digest.file_name()),
+        Err(ref e) => {
+            let (outcome, why) = match *e.kind() {
+                ErrorKind::BuildError => (journal::Outcome::Failed, "compile errors".to_owned()),
+                ErrorKind::Cancelled => (journal::Outcome::Cancelled, "cancelled".to_owned()),
+                ErrorKind::TestsFailed(_) => (journal::Outcome::TestsFailed, e.to_string()),
+                _ => (journal::Outcome::Error, e.to_string()),
+            };
+            entry.outcome = outcome;
+            entry.skip(why);
+        }
+    }
+    (result, entry)
+}
 
+/// Failing to keep the journal shouldn't fail the build.
+fn log_build(config: &Config, entry: &journal::Entry) {
+    if let Err(e) = journal::append(&config.root, entry) {
+        writeln!(io::stderr(), "journal: {}", e).expect("stderr");
+    }
+}
+
+/// The interactive loop: rebuild whenever enter is pressed.
+fn prompt(config: &Config, keys: &issuer::InsecureKeys, current: &CurrentBuild) -> Result<()> {
     let mut input = format!("\n");
     while input != "" && input != "q\n" {
         if input == "?\n" {
@@ -69,19 +269,29 @@ fn run() -> Result<()> {
             continue
         }
 
-        match build(&config, &keys) {
+        let (result, mut entry) = build_current(config, keys, current);
+        match result {
             Ok((info, novelty)) => {
                 if input == "f\n" || !novelty.is_still_fresh() {
                     let hex = info.digest.short_hex();
-                    match announce_build(info) {
+                    let announced = announce_build(config, info);
+                    entry.announce_result(&announced);
+                    match announced {
                         Ok(()) => println!("   Announced driver {}", hex),
                         Err(e) => writeln!(io::stderr(), "announce: {}", e).expect("stderr"),
                     }
+                } else {
+                    entry.skip("driver unchanged");
                 }
+                log_build(config, &entry);
             }
             Err(Error(ErrorKind::BuildError, _)) |
-            Err(Error(ErrorKind::Cancelled, _)) => (),
-            Err(e) => return Err(e)
+            Err(Error(ErrorKind::TestsFailed(_), _)) |
+            Err(Error(ErrorKind::Cancelled, _)) => log_build(config, &entry),
+            Err(e) => {
+                log_build(config, &entry);
+                return Err(e);
+            }
         }
 
         print!("> ");
@@ -102,12 +312,90 @@ anything else: rebuild and announce if changed
     Ok(())
 }
 
+/// Machine-readable summary of a command, printed with `--json`.
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    len: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fresh: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    announced: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Report {
+    fn success() -> Self {
+        Report { ok: true, ..Default::default() }
+    }
+
+    fn failure(e: &Error) -> Self {
+        Report { ok: false, error: Some(e.to_string()), ..Default::default() }
+    }
+
+    fn driver(info: &DriverInfo) -> Self {
+        Report {
+            ok: true,
+            digest: Some(info.digest.to_string()),
+            len: Some(info.len),
+            ..Default::default()
+        }
+    }
+
+    fn fresh(self, novelty: &Novelty) -> Self {
+        Report { fresh: Some(novelty.is_still_fresh()), ..self }
+    }
+
+    fn announced(self, announced: bool) -> Self {
+        Report { announced: Some(announced), ..self }
+    }
+
+    fn short_hex(&self) -> String {
+        let digest = self.digest.as_ref().and_then(|tagged| tagged.parse::<Digest>().ok());
+        digest.map(|digest| digest.short_hex()).unwrap_or_else(|| "?".to_owned())
+    }
+}
+
 #[derive(Clone)]
 struct Config {
     root: PathBuf,
+    endpoints: Endpoints,
+    release: bool,
+    target: Option<String>,
+    target_dir: Option<PathBuf>,
+    no_default_features: bool,
+    /// Sign drivers even if their tests fail.
+    allow_failing_tests: bool,
 }
 
 impl Config {
+    fn profile(&self) -> &'static str {
+        if self.release { "release" } else { "debug" }
+    }
+
+    /// Starts a cargo command with the profile, target and features shared by every crate.
+    fn cargo<'a>(&'a self, manifest: &'a Path) -> cargo::Command<'a> {
+        let mut cmd = cargo::Command::new();
+        cmd.manifest_path(manifest);
+        if self.release {
+            cmd.release();
+        }
+        if let Some(ref triple) = self.target {
+            cmd.target(triple);
+        }
+        if let Some(ref dir) = self.target_dir {
+            cmd.target_dir(dir);
+        }
+        if self.no_default_features {
+            cmd.no_default_features();
+        }
+        cmd
+    }
+
     fn client_manifest(&self) -> PathBuf {
         self.root.join("client").join("Cargo.toml")
     }
@@ -120,82 +408,230 @@ impl Config {
 }
 
 
-fn build(config: &Config, keys: &issuer::InsecureKeys) -> Result<(DriverInfo, Novelty)> {
+/// Builds g, then the client and driver side by side, signing the driver.
+/// Cancelling `cancel` kills every cargo and fails with `ErrorKind::Cancelled`.
+fn build(
+    config: &Config,
+    keys: &issuer::InsecureKeys,
+    cancel: &Canceller,
+    progress: &Arc<Progress>,
+) -> Result<(DriverInfo, Novelty)> {
     // G
+    let g_deps;
+    let g_digest;
     {
         let manifest = config.vendor_manifest();
-        let stream = cargo::Command::new()
-            .manifest_path(&manifest)
-            .features(&["gl"])
-            .spawn("build")?;
-        let artifact = process_build(stream, "g", false, None)?;
+        let mut cmd = config.cargo(&manifest);
+        cmd.features(&["gl"]);
+        let stream = cmd.spawn("build")?;
+        let artifact = finish_target(stream, &cmd.artifact_dir(), "g", false, cancel, progress)?;
+        g_deps = artifact.path.parent().expect("g dylib dir").to_owned();
+        g_digest = hash_file(&artifact.path).chain_err(|| "couldn't hash g")?;
         if artifact.novelty.is_still_fresh() {
-            println!("       Fresh G");
+            progress.println("       Fresh G");
         } else {
-            println!("     Rebuilt G");
-            // make sure driver and client get rebuilt
-            touch(&config.root.join("client/src/main.rs"))
-                .chain_err(|| "touch client/src/main.rs")?;
-            touch(&config.root.join("driver/src/lib.rs"))
-                .chain_err(|| "touch driver/src/lib.rs")?;
-            // we should write some metadata about g...?
+            progress.println(&format!("     Rebuilt G {}", g_digest.short_hex()));
         }
     }
+    if cancel.is_cancelled() {
+        bail!(ErrorKind::Cancelled);
+    }
+    // handing this to the build scripts makes cargo relink whenever g changes
+    let g_hex = g_digest.to_string();
 
-    let client_switch = DeadMansSwitch::new();
+    // the client and driver only share g, so they can build concurrently
+    let client_cancel = cancel.child();
     let client_thread = {
-        let cancel_flag = client_switch.0.clone();
-        let stream = cargo::Command::new()
-            .manifest_path(&config.client_manifest())
-            .bin_only("client")
-            .spawn("build")?;
+        let manifest = config.client_manifest();
+        let mut cmd = config.cargo(&manifest);
+        cmd.bin_only("client")
+            .env("EXUDE_G_DEPS", &g_deps)
+            .env("EXUDE_G_DIGEST", &g_hex);
+        let out_dir = cmd.artifact_dir();
+        let stream = cmd.spawn("build")?;
+        let cancel = client_cancel.clone();
+        let progress = progress.clone();
 
         thread::spawn(
             move || -> Result<()> {
-                let artifact = process_build(stream, "client", true, Some(&cancel_flag))?;
+                let artifact = finish_target(stream, &out_dir, "client", true, &cancel, &progress)?;
                 if artifact.novelty.is_still_fresh() {
-                    println!("       Fresh client");
+                    progress.println("       Fresh client");
                 } else {
-                    println!("     Rebuilt client");
+                    progress.println("     Rebuilt client");
                 }
                 Ok(())
             }
         )
     };
 
-    // Driver
-    let descriptor;
-    let novelty;
-    {
-        let stream = cargo::Command::new()
-            .manifest_path(&config.driver_manifest())
-            .spawn("build")?;
-        let artifact = process_build(stream, "driver", false, None)?;
-        novelty = artifact.novelty;
-        match novelty {
-            Novelty::StillFresh => {
-                match issuer::verify(&keys.0, &config.root) {
-                    Ok(desc) => {
-                        descriptor = desc;
-                        println!("       Fresh driver");
-                    }
-                    Err(_) => {
-                        descriptor = issuer::sign(&artifact.path, keys, &config.root)?;
-                        println!("  Signed new driver");
-                    }
-                }
-            }
-            Novelty::BrandNew => {
-                descriptor = issuer::sign(&artifact.path, keys, &config.root)?;
-                println!("  Signed new driver");
+    let driver = build_driver(config, keys, &g_deps, &g_digest, &g_hex, cancel, progress);
+    if driver.is_err() {
+        // no use for a client without its driver
+        client_cancel.cancel();
+    }
+    let client = client_thread.join().expect("client thread");
+    let driver = driver?;
+    client?;
+    Ok(driver)
+}
+
+fn build_driver(
+    config: &Config,
+    keys: &issuer::InsecureKeys,
+    g_deps: &Path,
+    g_digest: &Digest,
+    g_hex: &str,
+    cancel: &Canceller,
+    progress: &Progress,
+) -> Result<(DriverInfo, Novelty)> {
+    let manifest = config.driver_manifest();
+    let mut cmd = config.cargo(&manifest);
+    cmd.env("EXUDE_G_DEPS", g_deps).env("EXUDE_G_DIGEST", g_hex);
+    let stream = cmd.spawn("build")?;
+    let artifact = finish_target(stream, &cmd.artifact_dir(), "driver", false, cancel, progress)?;
+
+    if artifact.novelty.is_still_fresh() {
+        // the signed copy may be of another profile's build, or another g's
+        let digest = hash_file(&artifact.path).chain_err(|| "couldn't hash driver")?;
+        let signed = issuer::verify(&keys.0, &config.root).ok().and_then(|desc| {
+            if desc.digest == digest && &desc.g_digest == g_digest {
+                Some(desc)
+            } else {
+                None
             }
+        });
+        if let Some(desc) = signed {
+            // it passed its tests (or was let off) when it was signed
+            progress.set("tests", Status::Skipped);
+            progress.println("       Fresh driver");
+            return Ok((desc, artifact.novelty));
         }
     }
 
-    client_thread.join().expect("client thread")?;
-    drop(client_switch);
+    test_driver(config, g_deps, g_hex, cancel, progress)?;
 
-    Ok((descriptor, novelty))
+    // don't sign something a newer build is about to replace
+    if cancel.is_cancelled() {
+        bail!(ErrorKind::Cancelled);
+    }
+    let descriptor = issuer::sign(&artifact.path, g_digest, keys, &config.root)?;
+    progress.println("  Signed new driver");
+    Ok((descriptor, artifact.novelty))
+}
+
+/// Runs the driver's tests, which must pass for it to be signed
+/// unless the config allows failures.
+fn test_driver(
+    config: &Config,
+    g_deps: &Path,
+    g_hex: &str,
+    cancel: &Canceller,
+    progress: &Progress,
+) -> Result<()> {
+    let manifest = config.driver_manifest();
+    let mut cmd = config.cargo(&manifest);
+    cmd.env("EXUDE_G_DEPS", g_deps)
+        .env("EXUDE_G_DIGEST", g_hex)
+        .arg("--no-fail-fast")
+        .test_json();
+    let mut stream = cmd.spawn("test")?;
+    cancel.register(stream.killer());
+    progress.set("tests", Status::Building(0));
+
+    let started = Instant::now();
+    let mut stats = TargetStats::new("tests");
+    let result = process_tests(&mut stream, cancel, progress, &mut stats);
+    stats.millis = journal::millis(started.elapsed());
+    if let Ok(ref results) = result {
+        stats.failed_tests = results.failed.len();
+    }
+    progress.record(stats);
+    let results = match result {
+        Ok(results) => results,
+        Err(e) => {
+            let status = match *e.kind() {
+                ErrorKind::Cancelled => Status::Cancelled,
+                _ => Status::Failed,
+            };
+            progress.set("tests", status);
+            return Err(e);
+        }
+    };
+
+    let summary = format!("{} passed, {} ignored", results.passed, results.ignored);
+    if results.failed.is_empty() {
+        progress.set("tests", Status::Done);
+        progress.println(&format!("      Tested driver: {}", summary));
+        return Ok(());
+    }
+
+    progress.set("tests", Status::Failed);
+    let failed = results.failed.len();
+    progress.println(&format!("      Tested driver: {}, {} failed", summary, failed));
+    if config.allow_failing_tests {
+        progress.println("  Signing it anyway (--allow-failing-tests)");
+        Ok(())
+    } else {
+        bail!(ErrorKind::TestsFailed(failed))
+    }
+}
+
+/// Follows a spawned cargo to the end, showing its progress.
+/// If it gets cancelled, deletes whatever it may have left half-linked in `out_dir`.
+fn finish_target(
+    mut stream: cargo::JsonStream,
+    out_dir: &Path,
+    name: &'static str,
+    bin: bool,
+    cancel: &Canceller,
+    progress: &Progress,
+) -> Result<Artifact> {
+    cancel.register(stream.killer());
+    progress.set(name, Status::Building(0));
+    let started = Instant::now();
+    let mut stats = TargetStats::new(name);
+    let result = process_build(&mut stream, name, bin, cancel, progress, &mut stats);
+    // make sure it's dead before cleaning up after it
+    drop(stream);
+    stats.millis = journal::millis(started.elapsed());
+    if let Ok(ref artifact) = result {
+        stats.fresh = artifact.novelty.is_still_fresh();
+    }
+    progress.record(stats);
+    let status = match result {
+        Ok(_) => Status::Done,
+        Err(ref e) => {
+            match *e.kind() {
+                ErrorKind::Cancelled => {
+                    remove_partial_outputs(out_dir, name, bin);
+                    Status::Cancelled
+                }
+                _ => Status::Failed,
+            }
+        }
+    };
+    progress.set(name, status);
+    result
+}
+
+/// Removes `name`'s final outputs, so that the next build can't take
+/// a truncated file for a fresh one.
+fn remove_partial_outputs(out_dir: &Path, name: &str, bin: bool) {
+    let file = if bin {
+        format!("{}{}", name, env::consts::EXE_SUFFIX)
+    } else {
+        format!("{}{}{}", env::consts::DLL_PREFIX, name, env::consts::DLL_SUFFIX)
+    };
+    for path in &[out_dir.join(&file), out_dir.join("deps").join(&file)] {
+        match fs::remove_file(path) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                writeln!(io::stderr(), "couldn't remove {}: {}", path.display(), e).expect("stderr")
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -220,149 +656,193 @@ impl Novelty {
 }
 
 fn process_build(
-    stream: cargo::JsonStream,
+    stream: &mut cargo::JsonStream,
     name: &str,
     bin: bool,
-    kill_switch: Option<&AtomicBool>,
+    cancel: &Canceller,
+    progress: &Progress,
+    stats: &mut TargetStats,
 ) -> Result<Artifact> {
+    let mut output = None;
+    let errored = follow_cargo(
+        stream,
+        name,
+        progress,
+        stats,
+        |out| if let Output::Artifact(artifact) = out {
+            if artifact.target.name == name && artifact.target.kind.is_bin() == bin {
+                assert!(output.is_none(), "target {} seen twice", name);
+                assert!(
+                    artifact.filenames.len() == 1,
+                    "many: {:?}",
+                    artifact.filenames
+                );
+                output = Some(
+                    Artifact {
+                        path: artifact.filenames[0].to_path_buf(),
+                        novelty: if artifact.fresh {
+                            Novelty::StillFresh
+                        } else {
+                            Novelty::BrandNew
+                        },
+                    }
+                );
+            }
+        },
+    )?;
+
+    // a target that made it out whole is still good, even if cancelled
+    if cancel.is_cancelled() && (errored || output.is_none()) {
+        Err(ErrorKind::Cancelled.into())
+    } else if errored {
+        Err(ErrorKind::BuildError.into())
+    } else {
+        Ok(output.unwrap_or_else(|| panic!("target {} not seen in build output", name)))
+    }
+}
+
+#[derive(Debug, Default)]
+struct TestResults {
+    passed: usize,
+    ignored: usize,
+    /// Names of the failed tests.
+    failed: Vec<String>,
+}
 
+fn process_tests(
+    stream: &mut cargo::JsonStream,
+    cancel: &Canceller,
+    progress: &Progress,
+    stats: &mut TargetStats,
+) -> Result<TestResults> {
+    let mut results = TestResults::default();
+    let errored = follow_cargo(
+        stream,
+        "tests",
+        progress,
+        stats,
+        |out| {
+            let test = match out {
+                Output::Test(ref test) if test.is_test() => test,
+                _ => return,
+            };
+            let name = test.name.as_ref().map(|n| &**n).unwrap_or("?");
+            match &*test.event {
+                "ok" => {
+                    results.passed += 1;
+                    progress.set("tests", Status::Testing(results.passed));
+                }
+                "ignored" => results.ignored += 1,
+                "failed" => {
+                    progress.suspend(
+                        || {
+                            println!("  Test failed: {}", name);
+                            if let Some(ref stdout) = test.stdout {
+                                for line in stdout.lines() {
+                                    println!("    {}", line);
+                                }
+                            }
+                        }
+                    );
+                    results.failed.push(name.to_owned());
+                }
+                _ => (),
+            }
+        },
+    )?;
+    let status = stream.wait()?;
+
+    if cancel.is_cancelled() {
+        Err(ErrorKind::Cancelled.into())
+    } else if errored {
+        Err(ErrorKind::BuildError.into())
+    } else {
+        if !status.success() && results.failed.is_empty() {
+            // e.g. a test binary that crashed without reporting anything
+            results.failed.push(format!("(cargo test: {})", status));
+        }
+        Ok(results)
+    }
+}
+
+/// Shows cargo's diagnostics and build steps as they come,
+/// handing everything else to `each`. Returns whether the build errored.
+fn follow_cargo<F>(
+    stream: &mut cargo::JsonStream,
+    name: &str,
+    progress: &Progress,
+    stats: &mut TargetStats,
+    mut each: F,
+) -> Result<bool>
+where
+    F: FnMut(Output),
+{
     let mut stderr = io::stderr();
     let oops = "couldn't write to stderr";
 
-    let mut output = None;
     let mut errored = false;
     let mut logged_json = false;
 
+    // once cancelled, cargo gets killed and the stream ends early
     for line in stream {
-        if kill_switch.map(|b| b.load(Ordering::Relaxed)).unwrap_or(false) {
-            return Err(ErrorKind::Cancelled.into())
-        }
         let line = line?;
         let e = match line.decode() {
-            Ok(Output::Artifact(artifact)) => {
-                if artifact.target.name == name && artifact.target.kind.is_bin() == bin {
-                    assert!(output.is_none(), "target {} seen twice", name);
-                    assert!(
-                        artifact.filenames.len() == 1,
-                        "many: {:?}",
-                        artifact.filenames
-                    );
-                    output = Some(
-                        Artifact {
-                            path: artifact.filenames[0].to_path_buf(),
-                            novelty: if artifact.fresh {
-                                Novelty::StillFresh
-                            } else {
-                                Novelty::BrandNew
-                            },
-                        }
-                    );
-                }
-                continue;
-            }
             Ok(Output::Message(msg)) => {
                 let diag = msg.message;
                 if diag.level.is_show_stopper() {
                     errored = true;
+                    // rustc's closing "aborting due to N previous errors" isn't another one
+                    if !diag.message.starts_with("aborting due to") {
+                        stats.errors += 1;
+                    }
+                } else if let Level::Warning = diag.level {
+                    stats.warnings += 1;
                 }
-                let mut out = stderr.lock();
-                write!(out, "{}", diag.level).expect(oops);
-                if let Some(code) = diag.code {
-                    write!(out, "[{}]", code.code).expect(oops);
-                }
-                writeln!(out, ": {}", diag.message).expect(oops);
-                if !diag.spans.is_empty() {
-                    // probably want to search for the span where .is_primary
-                    let ref span = diag.spans[0];
-                    writeln!(
-                        out,
-                        "   --> {}:{}:{}",
-                        span.file_name.display(),
-                        span.line_start,
-                        span.column_start
-                    ).expect(oops);
-                }
-                writeln!(out, "").expect(oops);
+                progress.suspend(|| render::render(&mut stderr.lock(), &diag)).expect(oops);
                 continue;
             }
             Ok(Output::BuildStep(b)) => {
-                println!("  Build step {}", b.package_id);
+                progress.println(&format!("  Build step {}", b.package_id));
+                continue;
+            }
+            Ok(Output::Finished(finished)) => {
+                if !finished.success {
+                    errored = true;
+                }
+                continue;
+            }
+            Ok(Output::Unknown(_)) => continue,
+            Ok(out) => {
+                if let Output::Artifact(_) = out {
+                    progress.unit_built(name);
+                }
+                each(out);
                 continue;
             }
             Err(e) => e,
         };
 
-        if logged_json {
-            writeln!(stderr, "While parsing JSON:\n    {}\n", e).expect(oops);
-        } else {
-            cargo::log_json_error(&e, line);
-            logged_json = true;
-        }
-    }
-
-    if errored {
-        Err(ErrorKind::BuildError.into())
-    } else {
-        Ok(output.unwrap_or_else(|| panic!("target {} not seen in build output", name)))
+        progress.suspend(
+            || if logged_json {
+                writeln!(stderr, "While parsing JSON:\n    {}\n", e).expect(oops);
+            } else {
+                cargo::log_json_error(&e, line);
+                logged_json = true;
+            }
+        );
     }
+    Ok(errored)
 }
 
-fn announce_build(info: DriverInfo) -> Result<()> {
-    let addr: SocketAddr = ([127, 0, 0, 1], 2002).into();
-    let mut sock = TcpStream::connect(addr).chain_err(|| "couldn't connect to server")?;
-    let buf = Bincoded::new(&info).chain_err(|| "couldn't serialize driver descriptor")?;
-    write_with_length_sync(&mut sock, buf.as_ref())
+fn announce_build(config: &Config, info: DriverInfo) -> Result<()> {
+    let addr = &config.endpoints.announce;
+    let mut sock = TcpStream::connect(&**addr)
+        .chain_err(|| format!("couldn't connect to server at {}", addr))?;
+    let buf = Envelope::new(&info).chain_err(|| "couldn't serialize driver descriptor")?;
+    frame::write_frame(&mut sock, buf.as_ref())
         .chain_err(|| "couldn't write driver descriptor")
 }
 
-fn write_with_length_sync<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
-    let len = bytes.len();
-    if len > 0xffff {
-        let msg = format!("written message too long: {}", len);
-        return Err(io::Error::new(io::ErrorKind::Other, msg));
-    }
-    let len_buf = [(len >> 8) as u8, len as u8];
-    writer.write_all(&len_buf)?;
-    writer.write_all(bytes)
-}
-
-/// Bumps an existing file's mtime.
-fn touch(path: &Path) -> io::Result<()> {
-    let stat = path.metadata()?;
-    let len = stat.len();
-    let before = stat.modified()?;
-    {
-        // oddly, merely opening the file for appending doesn't bump the mtime
-        // this impl is portable, but has a race
-        let mut f = fs::OpenOptions::new().append(true).open(path)?;
-        f.write_all(b" ")?;
-        f.set_len(len)?;
-        f.sync_all()?;
-    }
-    let after = path.metadata()?.modified()?;
-    if before < after {
-        Ok(())
-    } else {
-        let msg = if before == after {
-            "modification time unchanged"
-        } else {
-            "time travel"
-        };
-        Err(io::Error::new(io::ErrorKind::Other, msg))
-    }
-}
-
-#[derive(Debug, Default)]
-pub struct DeadMansSwitch(pub Arc<AtomicBool>);
-
-impl DeadMansSwitch {
-    pub fn new() -> Self {
-        Default::default()
-    }
-}
-
-impl Drop for DeadMansSwitch {
-    fn drop(&mut self) {
-        self.0.store(true, Ordering::Relaxed)
-    }
+fn hash_file(path: &Path) -> io::Result<Digest> {
+    fs::File::open(path).and_then(Digest::from_read).map(|(digest, _)| digest)
 }
@@ -0,0 +1,210 @@
+//! Cancellation and progress reporting shared by the concurrent builds.
+
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use terminal_size::terminal_size;
+
+use cargo::Killer;
+use journal::TargetStats;
+
+/// Cancels a build from any thread: raises its flag and kills its cargos.
+///
+/// Cancelling also cancels every `child()`, but not the other way around.
+#[derive(Clone, Default)]
+pub struct Canceller(Arc<Shared>);
+
+#[derive(Default)]
+struct Shared {
+    cancelled: AtomicBool,
+    killers: Mutex<Vec<Killer>>,
+    children: Mutex<Vec<Canceller>>,
+}
+
+impl Canceller {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns false if this was already cancelled.
+    pub fn cancel(&self) -> bool {
+        if self.0.cancelled.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        for killer in self.0.killers.lock().expect("lock killers").drain(..) {
+            killer.kill();
+        }
+        for child in self.0.children.lock().expect("lock children").drain(..) {
+            child.cancel();
+        }
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Kills this cargo upon cancellation (or right now, if that already happened).
+    pub fn register(&self, killer: Killer) {
+        let mut killers = self.0.killers.lock().expect("lock killers");
+        // checked under the lock so that `cancel` can't miss it
+        if self.is_cancelled() {
+            killer.kill();
+        } else {
+            killers.push(killer);
+        }
+    }
+
+    /// A canceller for one part of this build.
+    pub fn child(&self) -> Canceller {
+        let child = Canceller::new();
+        let mut children = self.0.children.lock().expect("lock children");
+        if self.is_cancelled() {
+            child.cancel();
+        } else {
+            children.push(child.clone());
+        }
+        child
+    }
+}
+
+/// Holds whichever build is in flight, so Ctrl-C or a newer build can cancel it.
+#[derive(Clone, Default)]
+pub struct CurrentBuild(Arc<Mutex<Option<Canceller>>>);
+
+impl CurrentBuild {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Cancels the previous build, if it's still going, and tracks a fresh one.
+    pub fn start(&self) -> Canceller {
+        let cancel = Canceller::new();
+        let mut current = self.0.lock().expect("lock current build");
+        if let Some(old) = mem::replace(&mut *current, Some(cancel.clone())) {
+            old.cancel();
+        }
+        cancel
+    }
+
+    pub fn finish(&self) {
+        self.0.lock().expect("lock current build").take();
+    }
+
+    /// Returns false if there was no build to cancel.
+    pub fn cancel(&self) -> bool {
+        match *self.0.lock().expect("lock current build") {
+            Some(ref cancel) => cancel.cancel(),
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Status {
+    Waiting,
+    /// Counts the units compiled so far.
+    Building(usize),
+    /// Counts the tests passed so far.
+    Testing(usize),
+    Skipped,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+/// A one-line summary of every target, redrawn in place on a terminal.
+/// Also gathers each target's stats for the journal.
+pub struct Progress {
+    targets: Mutex<Vec<(&'static str, Status)>>,
+    stats: Mutex<Vec<TargetStats>>,
+    live: bool,
+}
+
+impl Progress {
+    pub fn new(names: &[&'static str]) -> Self {
+        Progress {
+            targets: Mutex::new(names.iter().map(|&name| (name, Status::Waiting)).collect()),
+            stats: Mutex::new(Vec::new()),
+            live: terminal_size().is_some(),
+        }
+    }
+
+    pub fn set(&self, name: &str, status: Status) {
+        let mut targets = self.targets.lock().expect("lock progress");
+        for target in targets.iter_mut().filter(|t| t.0 == name) {
+            target.1 = status;
+        }
+        self.draw(&targets);
+    }
+
+    /// Notes that another crate of `name`'s build has compiled.
+    pub fn unit_built(&self, name: &str) {
+        let mut targets = self.targets.lock().expect("lock progress");
+        for target in targets.iter_mut().filter(|t| t.0 == name) {
+            target.1 = match target.1 {
+                Status::Waiting => Status::Building(1),
+                Status::Building(n) => Status::Building(n + 1),
+                done => done,
+            };
+        }
+        self.draw(&targets);
+    }
+
+    pub fn record(&self, stats: TargetStats) {
+        self.stats.lock().expect("lock stats").push(stats);
+    }
+
+    /// Stats of the targets that have finished (or failed) so far.
+    pub fn take_stats(&self) -> Vec<TargetStats> {
+        mem::replace(&mut *self.stats.lock().expect("lock stats"), Vec::new())
+    }
+
+    /// Prints something without mangling the progress line.
+    pub fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R {
+        let targets = self.targets.lock().expect("lock progress");
+        self.clear();
+        let r = f();
+        self.draw(&targets);
+        r
+    }
+
+    pub fn println(&self, msg: &str) {
+        self.suspend(|| println!("{}", msg))
+    }
+
+    /// Erases the progress line for good.
+    pub fn finish(&self) {
+        let _targets = self.targets.lock().expect("lock progress");
+        self.clear();
+    }
+
+    fn clear(&self) {
+        if self.live {
+            io::stdout().flush().expect("flush");
+            write!(io::stderr(), "\r\x1b[K").expect("stderr");
+        }
+    }
+
+    fn draw(&self, targets: &[(&'static str, Status)]) {
+        if !self.live {
+            return;
+        }
+        let mut line = String::from("\r\x1b[K");
+        for &(name, status) in targets {
+            let status = match status {
+                Status::Waiting => format!("waiting"),
+                Status::Building(n) => format!("{} crates", n),
+                Status::Testing(n) => format!("{} passed", n),
+                Status::Skipped => format!("skipped"),
+                Status::Done => format!("done"),
+                Status::Failed => format!("failed"),
+                Status::Cancelled => format!("cancelled"),
+            };
+            line.push_str(&format!("{:>12}: {}", name, status));
+        }
+        write!(io::stderr(), "{}", line).expect("stderr");
+    }
+}
@@ -0,0 +1,344 @@
+//! Renders compiler diagnostics much like rustc does on a terminal.
+
+use std::cmp;
+use std::io::{self, Write};
+use std::path::Path;
+
+use cargo::diagnostic::{Diagnostic, Level, Span};
+
+/// Writes `diag` (and its children) followed by a blank line.
+pub fn render<W: Write>(out: &mut W, diag: &Diagnostic) -> io::Result<()> {
+    if diag.spans.is_empty() && diag.children.is_empty() {
+        if let Some(ref rendered) = diag.rendered {
+            // nothing for us to add; rustc's version may carry extra context
+            return writeln!(out, "{}\n", rendered.trim_right());
+        }
+    }
+
+    let width = digits(max_line(diag));
+    write_header(out, &diag.level, diag, "")?;
+    if let Some(primary) = primary_span(&diag.spans) {
+        write_location(out, width, "-->", primary)?;
+        writeln!(out, "{} |", pad(width))?;
+        write_snippet(out, width, &diag.spans, primary.file_name)?;
+        write_expansion(out, width, primary)?;
+    }
+
+    // whether the last thing printed was source code, which wants a spacer
+    let mut after_snippet = primary_span(&diag.spans).map_or(false, |s| s.expansion.is_none());
+    for child in &diag.children {
+        if child.spans.is_empty() {
+            if after_snippet {
+                writeln!(out, "{} |", pad(width))?;
+                after_snippet = false;
+            }
+            write_header(out, &child.level, child, &format!("{} = ", pad(width)))?;
+        } else if child.spans.iter().any(|s| s.suggested_replacement.is_some()) {
+            write_header(out, &child.level, child, "")?;
+            write_suggestion(out, width, &child.spans)?;
+            after_snippet = false;
+        } else {
+            write_header(out, &child.level, child, "")?;
+            let primary = primary_span(&child.spans).expect("child span");
+            write_location(out, width, "-->", primary)?;
+            writeln!(out, "{} |", pad(width))?;
+            write_snippet(out, width, &child.spans, primary.file_name)?;
+            after_snippet = true;
+        }
+    }
+    writeln!(out, "")
+}
+
+fn write_header<W: Write>(out: &mut W, level: &Level, diag: &Diagnostic, prefix: &str) -> io::Result<()> {
+    write!(out, "{}{}", prefix, level)?;
+    if let Some(ref code) = diag.code {
+        write!(out, "[{}]", code.code)?;
+    }
+    // indent continuation lines under the message
+    let indent = pad(prefix.len() + level.to_string().len() + 2);
+    let mut lines = diag.message.lines();
+    writeln!(out, ": {}", lines.next().unwrap_or(""))?;
+    for line in lines {
+        writeln!(out, "{}{}", indent, line)?;
+    }
+    Ok(())
+}
+
+fn write_location<W: Write>(out: &mut W, width: usize, arrow: &str, span: &Span) -> io::Result<()> {
+    writeln!(
+        out,
+        "{}{} {}:{}:{}",
+        pad(width),
+        arrow,
+        span.file_name.display(),
+        span.line_start,
+        span.column_start
+    )
+}
+
+/// Prints the source lines touched by `spans`, underlined, starting with `file`.
+/// Spans in other files get their own `:::` section afterward.
+fn write_snippet<W: Write>(out: &mut W, width: usize, spans: &[Span], file: &Path) -> io::Result<()> {
+    write_file_lines(out, width, spans, file)?;
+
+    let mut others: Vec<&Span> = spans.iter().filter(|s| s.file_name != file).collect();
+    others.sort_by(|a, b| a.file_name.cmp(b.file_name));
+    others.dedup_by(|a, b| a.file_name == b.file_name);
+    for other in others {
+        write_location(out, width, ":::", other)?;
+        writeln!(out, "{} |", pad(width))?;
+        write_file_lines(out, width, spans, other.file_name)?;
+    }
+    Ok(())
+}
+
+fn write_file_lines<W: Write>(out: &mut W, width: usize, spans: &[Span], file: &Path) -> io::Result<()> {
+    struct Mark<'s> {
+        line: usize,
+        text: &'s str,
+        start: usize,
+        end: usize,
+        primary: bool,
+        label: Option<&'s str>,
+    }
+
+    let mut marks = Vec::new();
+    for span in spans.iter().filter(|s| s.file_name == file) {
+        let last = span.text.len().saturating_sub(1);
+        for (i, line) in span.text.iter().enumerate() {
+            marks.push(
+                Mark {
+                    line: span.line_start as usize + i,
+                    text: &line.text,
+                    start: line.highlight_start,
+                    end: line.highlight_end,
+                    primary: span.is_primary,
+                    label: if i == last { span.label.as_ref().map(|l| &**l) } else { None },
+                }
+            );
+        }
+    }
+    marks.sort_by(|a, b| (a.line, !a.primary, a.start).cmp(&(b.line, !b.primary, b.start)));
+
+    let mut prev_line = None;
+    for mark in &marks {
+        if prev_line != Some(mark.line) {
+            if prev_line.map(|p| mark.line > p + 1).unwrap_or(false) {
+                writeln!(out, "...")?;
+            }
+            writeln!(out, "{:>w$} | {}", mark.line, mark.text, w = width)?;
+            prev_line = Some(mark.line);
+        }
+        let underline = if mark.primary { '^' } else { '-' };
+        let carets: String = (0..cmp::max(1, mark.end.saturating_sub(mark.start)))
+            .map(|_| underline)
+            .collect();
+        write!(out, "{} | {}{}", pad(width), leading_space(mark.text, mark.start), carets)?;
+        match mark.label {
+            Some(label) => writeln!(out, " {}", label)?,
+            None => writeln!(out, "")?,
+        }
+    }
+    Ok(())
+}
+
+/// Like rustc, points out where a macro was expanded from.
+fn write_expansion<W: Write>(out: &mut W, width: usize, span: &Span) -> io::Result<()> {
+    if let Some(ref expansion) = span.expansion {
+        writeln!(out, "{} |", pad(width))?;
+        writeln!(
+            out,
+            "{} = note: this error originates in the macro `{}` ({}:{})",
+            pad(width),
+            expansion.macro_decl_name,
+            expansion.span.file_name.display(),
+            expansion.span.line_start
+        )?;
+    }
+    Ok(())
+}
+
+/// Shows each suggested replacement spliced into its source line(s).
+fn write_suggestion<W: Write>(out: &mut W, width: usize, spans: &[Span]) -> io::Result<()> {
+    writeln!(out, "{} |", pad(width))?;
+    for span in spans {
+        let replacement = match span.suggested_replacement {
+            Some(ref r) => r,
+            None => continue,
+        };
+        let (first, last) = match (span.text.first(), span.text.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => {
+                writeln!(out, "{} | {}", pad(width), replacement)?;
+                continue;
+            }
+        };
+        let before = &first.text[..char_offset(&first.text, first.highlight_start)];
+        let after = &last.text[char_offset(&last.text, last.highlight_end)..];
+        let spliced = format!("{}{}{}", before, replacement, after);
+        for (i, line) in spliced.lines().enumerate() {
+            writeln!(out, "{:>w$} | {}", span.line_start as usize + i, line, w = width)?;
+        }
+    }
+    writeln!(out, "{} |", pad(width))
+}
+
+fn primary_span<'s, 'a>(spans: &'s [Span<'a>]) -> Option<&'s Span<'a>> {
+    spans.iter().find(|s| s.is_primary).or_else(|| spans.first())
+}
+
+/// The largest line number we might print, for sizing the gutter.
+fn max_line(diag: &Diagnostic) -> usize {
+    let own = diag.spans
+        .iter()
+        .map(|s| {
+            let suggested = s.suggested_replacement.as_ref().map_or(0, |r| r.lines().count());
+            cmp::max(s.line_end, s.line_start + suggested.saturating_sub(1) as u32) as usize
+        })
+        .max()
+        .unwrap_or(0);
+    diag.children.iter().map(max_line).fold(own, cmp::max)
+}
+
+fn digits(mut n: usize) -> usize {
+    let mut count = 1;
+    while n >= 10 {
+        n /= 10;
+        count += 1;
+    }
+    count
+}
+
+fn pad(width: usize) -> String {
+    (0..width).map(|_| ' ').collect()
+}
+
+/// Whitespace to line up with the 1-based `column`, preserving tabs from `text`.
+fn leading_space(text: &str, column: usize) -> String {
+    text.chars()
+        .take(column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect()
+}
+
+/// Byte offset of the 1-based char `column`, clamped to the end of `text`.
+fn char_offset(text: &str, column: usize) -> usize {
+    text.char_indices()
+        .nth(column.saturating_sub(1))
+        .map(|(i, _)| i)
+        .unwrap_or(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use cargo::diagnostic::Diagnostic;
+    use super::render;
+
+    fn rendered(json: &str) -> String {
+        let diag: Diagnostic = serde_json::from_str(json).expect("diagnostic json");
+        let mut out = Vec::new();
+        render(&mut out, &diag).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn prerendered() {
+        let out = rendered(
+            r#"{
+            "message": "aborting due to previous error", "code": null, "level": "error",
+            "spans": [], "children": [], "rendered": "error: aborting due to previous error\n"
+        }"#,
+        );
+        assert_eq!(out, "error: aborting due to previous error\n\n");
+    }
+
+    #[test]
+    fn mismatched_types() {
+        let out = rendered(
+            r#"{
+            "message": "mismatched types",
+            "code": {"code": "E0308", "explanation": null},
+            "level": "error",
+            "spans": [{
+                "file_name": "src/lib.rs", "byte_start": 50, "byte_end": 53,
+                "line_start": 9, "line_end": 9, "column_start": 17, "column_end": 20,
+                "is_primary": true,
+                "text": [{"text": "    let x: u8 = 300;", "highlight_start": 17, "highlight_end": 20}],
+                "label": "expected u8", "suggested_replacement": null, "expansion": null
+            }],
+            "children": [
+                {"message": "expected type `u8`", "code": null, "level": "note",
+                 "spans": [], "children": [], "rendered": null},
+                {"message": "try a wider type", "code": null, "level": "help",
+                 "spans": [{
+                    "file_name": "src/lib.rs", "byte_start": 43, "byte_end": 45,
+                    "line_start": 9, "line_end": 9, "column_start": 12, "column_end": 14,
+                    "is_primary": true,
+                    "text": [{"text": "    let x: u8 = 300;", "highlight_start": 12, "highlight_end": 14}],
+                    "label": null, "suggested_replacement": "u16", "expansion": null
+                 }],
+                 "children": [], "rendered": null}
+            ],
+            "rendered": null
+        }"#,
+        );
+        assert_eq!(
+            out,
+            "error[E0308]: mismatched types
+ --> src/lib.rs:9:17
+  |
+9 |     let x: u8 = 300;
+  |                 ^^^ expected u8
+  |
+  = note: expected type `u8`
+help: try a wider type
+  |
+9 |     let x: u16 = 300;
+  |
+
+"
+        );
+    }
+
+    #[test]
+    fn secondary_span_and_gap() {
+        let out = rendered(
+            r#"{
+            "message": "use of moved value: `v`",
+            "code": null,
+            "level": "error",
+            "spans": [
+                {"file_name": "src/main.rs", "byte_start": 0, "byte_end": 1,
+                 "line_start": 12, "line_end": 12, "column_start": 10, "column_end": 11,
+                 "is_primary": true,
+                 "text": [{"text": "    drop(v);", "highlight_start": 10, "highlight_end": 11}],
+                 "label": "value used here after move", "suggested_replacement": null,
+                 "expansion": null},
+                {"file_name": "src/main.rs", "byte_start": 0, "byte_end": 1,
+                 "line_start": 8, "line_end": 8, "column_start": 10, "column_end": 11,
+                 "is_primary": false,
+                 "text": [{"text": "    take(v);", "highlight_start": 10, "highlight_end": 11}],
+                 "label": "value moved here", "suggested_replacement": null,
+                 "expansion": null}
+            ],
+            "children": [],
+            "rendered": null
+        }"#,
+        );
+        assert_eq!(
+            out,
+            "error: use of moved value: `v`
+  --> src/main.rs:12:10
+   |
+ 8 |     take(v);
+   |          - value moved here
+...
+12 |     drop(v);
+   |          ^ value used here after move
+
+"
+        );
+    }
+}
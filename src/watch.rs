@@ -0,0 +1,131 @@
+//! Rebuilds and announces whenever the driver's sources change.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use notify::{self, DebouncedEvent, RecursiveMode, Watcher};
+
+use errors::*;
+use pipeline::CurrentBuild;
+use super::{Config, announce_build, build_current, log_build};
+
+/// How long a burst of saves must settle before we rebuild.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Crates whose sources trigger a rebuild.
+static WATCHED_CRATES: &[&str] = &["g", "driver", "client"];
+
+/// Loops forever, rebuilding on each (debounced) change.
+/// A change arriving mid-build cancels the stale build and starts over.
+pub fn run(config: &Config, keys: &::issuer::InsecureKeys, current: &CurrentBuild) -> Result<()> {
+    let (event_tx, event_rx) = mpsc::channel();
+    let mut watcher = notify::watcher(event_tx, DEBOUNCE).chain_err(|| "couldn't start watcher")?;
+    for name in WATCHED_CRATES {
+        let dir = config.root.join(name);
+        watcher
+            .watch(dir.join("src"), RecursiveMode::Recursive)
+            .chain_err(|| format!("couldn't watch {}/src", name))?;
+        for file in &["Cargo.toml", "build.rs"] {
+            let path = dir.join(file);
+            if path.exists() {
+                watcher
+                    .watch(&path, RecursiveMode::NonRecursive)
+                    .chain_err(|| format!("couldn't watch {}/{}", name, file))?;
+            }
+        }
+    }
+
+    let (dirty_tx, dirty_rx) = mpsc::channel();
+    {
+        let current = current.clone();
+        thread::Builder::new()
+            .name("watch".into())
+            .spawn(move || relay_changes(event_rx, dirty_tx, current))
+            .chain_err(|| "couldn't spawn watch thread")?;
+    }
+
+    let mut last_announced = ::issuer::verify(&keys.0, &config.root).ok().map(|info| info.digest);
+
+    println!("Watching for changes. (Ctrl-C to quit)");
+    loop {
+        let (result, mut entry) = build_current(config, keys, current);
+        match result {
+            Ok((info, _)) => {
+                // compare digests rather than novelty, in case a cancelled build
+                // managed to sign a driver before noticing it was stale
+                if last_announced.as_ref() != Some(&info.digest) {
+                    let digest = info.digest.clone();
+                    let announced = announce_build(config, info);
+                    entry.announce_result(&announced);
+                    match announced {
+                        Ok(()) => {
+                            println!("   Announced driver {}", digest.short_hex());
+                            last_announced = Some(digest);
+                        }
+                        Err(e) => writeln!(io::stderr(), "announce: {}", e).expect("stderr"),
+                    }
+                } else {
+                    entry.skip("already announced");
+                }
+                log_build(config, &entry);
+            }
+            Err(Error(ErrorKind::BuildError, _)) |
+            Err(Error(ErrorKind::TestsFailed(_), _)) => log_build(config, &entry),
+            Err(Error(ErrorKind::Cancelled, _)) => {
+                log_build(config, &entry);
+                println!("   Cancelled stale build");
+            }
+            Err(e) => {
+                log_build(config, &entry);
+                return Err(e);
+            }
+        }
+
+        dirty_rx.recv().chain_err(|| "watcher went away")?;
+        // coalesce any changes that piled up during the build
+        while dirty_rx.try_recv().is_ok() {}
+    }
+}
+
+/// Cancels the current build and pings the main loop for every relevant change.
+fn relay_changes(
+    events: Receiver<DebouncedEvent>,
+    dirty: Sender<()>,
+    current: CurrentBuild,
+) {
+    for event in events {
+        let relevant = match event {
+            DebouncedEvent::NoticeWrite(_) |
+            DebouncedEvent::NoticeRemove(_) |
+            DebouncedEvent::Chmod(_) => false,
+            DebouncedEvent::Create(ref path) |
+            DebouncedEvent::Write(ref path) |
+            DebouncedEvent::Remove(ref path) => is_source(path),
+            DebouncedEvent::Rename(ref from, ref to) => is_source(from) || is_source(to),
+            DebouncedEvent::Rescan => true,
+            DebouncedEvent::Error(e, path) => {
+                writeln!(io::stderr(), "watch: {} ({:?})", e, path).expect("stderr");
+                false
+            }
+        };
+        if !relevant {
+            continue;
+        }
+
+        current.cancel();
+        if dirty.send(()).is_err() {
+            break;
+        }
+    }
+}
+
+/// Filters out editor droppings like `.lib.rs.swp` and `lib.rs~`.
+fn is_source(path: &Path) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => !name.starts_with('.') && !name.ends_with('~'),
+        None => false,
+    }
+}